@@ -0,0 +1,204 @@
+use crate::config::Config;
+use crate::database::SQLite;
+use crate::decode::PcmReader;
+use crate::error::{FileError, IndexError, Stage};
+use crate::loudness::{Loudness, LoudnessMeter};
+use crate::music::File;
+use crate::waveform::{self, PeakBuilder};
+use rusqlite::params;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Decoding is far slower than reading tags, so it runs on its own after
+// warming and only ever picks up files that are ready to be played
+pub fn analyse(config: &Config, files_mutex: Arc<Mutex<HashMap<u32, File>>>) {
+    let mut queue: Vec<u32> = Vec::new();
+
+    loop {
+        match analyse_next(config, files_mutex.clone(), &mut queue) {
+            Some(errors) => {
+                for error in errors {
+                    println!("Analyse error: {}", error);
+                }
+            }
+            None => {
+                println!("Sleeping for 60 seconds, nothing to analyse...");
+                thread::sleep(Duration::from_secs(60));
+            }
+        }
+    }
+}
+
+// Analyse everything that hasn't been yet, then return
+pub fn analyse_until_empty(config: &Config, files_mutex: Arc<Mutex<HashMap<u32, File>>>) {
+    let mut queue: Vec<u32> = Vec::new();
+    let mut analysed = 0;
+    let mut errors: Vec<FileError> = Vec::new();
+
+    while let Some(file_errors) = analyse_next(config, files_mutex.clone(), &mut queue) {
+        analysed += 1;
+        errors.extend(file_errors);
+    }
+
+    println!("Nothing left to analyse.");
+    println!(
+        "Analyse report: {} analysed, {} errors",
+        analysed,
+        errors.len()
+    );
+    for error in errors {
+        println!("  {}", error);
+    }
+}
+
+// Analyses one file, returns None when there was nothing to analyse. A file
+// that can't be decoded is still marked as analysed so it isn't retried until
+// it changes.
+fn analyse_next(
+    config: &Config,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    queue: &mut Vec<u32>,
+) -> Option<Vec<FileError>> {
+    let mut file = next_unanalysed(config, &files_mutex, queue)?;
+    let mut errors: Vec<FileError> = Vec::new();
+
+    println!("Analysing `{}`...", file.path);
+
+    match analyse_file(config, &file) {
+        Ok(analysis) => {
+            if let Err(err) = waveform::save(file.id, &analysis.waveforms) {
+                println!("Update failed (waveforms): {}", err);
+            }
+
+            if let Some(loudness) = analysis.loudness {
+                println!(
+                    "Loudness: {:?} LUFS, true peak: {:?} dBTP, range: {:?} LU",
+                    loudness.integrated, loudness.true_peak, loudness.range
+                );
+                file.loudness = loudness.integrated;
+                file.true_peak = loudness.true_peak;
+                file.loudness_range = loudness.range;
+            }
+        }
+        Err(err) => {
+            println!("Could not analyse `{}`: {}", file.path, err);
+            errors.push(FileError::new(file.path.clone(), Stage::Analyse, err));
+        }
+    }
+
+    file.analysed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    save_analysis(&file, files_mutex);
+
+    Some(errors)
+}
+
+// Pops the next file that still needs analysing. The files are only scanned
+// when the queue runs dry, anything queued is checked again as it may have
+// changed or gone since.
+fn next_unanalysed(
+    config: &Config,
+    files_mutex: &Arc<Mutex<HashMap<u32, File>>>,
+    queue: &mut Vec<u32>,
+) -> Option<File> {
+    let needs_analysing = |file: &File| {
+        file.indexed_at > 0
+            && file.analysed_at == 0
+            && !file.parse_fail
+            && file.duration <= config.max_duration
+    };
+
+    println!("Locking files (analyse)...");
+    let files = files_mutex.lock().unwrap();
+
+    if queue.is_empty() {
+        *queue = files
+            .values()
+            .filter(|file| needs_analysing(file))
+            .map(|file| file.id)
+            .collect();
+    }
+
+    let mut file = None;
+    while let Some(id) = queue.pop() {
+        if let Some(queued) = files.get(&id).filter(|queued| needs_analysing(queued)) {
+            file = Some(queued.clone());
+            break;
+        }
+    }
+
+    println!("Unlocking files (analyse)...");
+    drop(files);
+
+    file
+}
+
+struct Analysis {
+    // (buckets, peaks) per resolution
+    waveforms: Vec<(u32, Vec<u8>)>,
+    // None when nothing could be decoded
+    loudness: Option<Loudness>,
+}
+
+// Decodes the file once, feeding the waveform and the loudness meter together
+fn analyse_file(config: &Config, file: &File) -> Result<Analysis, IndexError> {
+    let mut reader = PcmReader::open(&file.path, &file.file_ext)?;
+    let mut peaks = PeakBuilder::new();
+    let mut meter: Option<LoudnessMeter> = None;
+
+    while let Some(pcm) = reader.next_samples()? {
+        peaks.push(pcm.samples, pcm.channels);
+
+        let meter = meter.get_or_insert_with(|| LoudnessMeter::new(pcm.channels, pcm.sample_rate));
+
+        if meter.accepts(pcm.channels, pcm.sample_rate) {
+            meter.push(pcm.samples);
+        }
+    }
+
+    Ok(Analysis {
+        waveforms: peaks.finish(&config.waveform_resolutions),
+        loudness: meter.map(LoudnessMeter::finish),
+    })
+}
+
+// The file may have been changed and re-queued for warming while it was being
+// decoded, in which case the results are stale and thrown away
+fn save_analysis(file: &File, files_mutex: Arc<Mutex<HashMap<u32, File>>>) {
+    println!("Locking files (save_analysis)...");
+    let mut files = files_mutex.lock().unwrap();
+    if let Some(current) = files.get_mut(&file.id) {
+        if current.file_size == file.file_size && current.file_modified == file.file_modified {
+            current.analysed_at = file.analysed_at;
+            current.loudness = file.loudness;
+            current.true_peak = file.true_peak;
+            current.loudness_range = file.loudness_range;
+        }
+    }
+    println!("Unlocking files (save_analysis)...");
+    drop(files);
+
+    let conn = SQLite::connect();
+
+    match conn.execute(
+        "UPDATE files SET analysed_at = ?1, loudness = ?2, true_peak = ?3, loudness_range = ?4
+        WHERE id = ?5 AND file_size = ?6 AND file_modified = ?7",
+        params![
+            file.analysed_at,
+            file.loudness,
+            file.true_peak,
+            file.loudness_range,
+            file.id,
+            file.file_size,
+            file.file_modified.to_string()
+        ],
+    ) {
+        Ok(_) => println!("Updating analysis (files)..."),
+        Err(err) => println!("Update failed (files): {}", err),
+    }
+}
@@ -0,0 +1,211 @@
+use crate::config::Config;
+use crate::error::IndexError;
+use crate::music::File;
+use image::{ImageFormat, ImageReader};
+use lofty::config::ParseOptions;
+use lofty::picture::PictureType;
+use lofty::prelude::TaggedFileExt;
+use lofty::probe::Probe;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+// Images next to a file that count as its cover when nothing is embedded, the
+// earlier a name is in the list the more it's preferred
+const SIDECAR_NAMES: &[&str] = &["cover", "folder", "front", "album", "albumart"];
+const SIDECAR_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+
+// Thumbnails are always re-encoded as JPEG
+const THUMBNAIL_QUALITY: u8 = 85;
+
+// Finds the file's cover (embedded first, then a sidecar image) and stores it
+// in the art cache. Returns the cover's content hash, or an empty string when
+// the file doesn't have one. Identical covers, e.g every track of an album,
+// share one entry in the cache.
+pub fn cache_cover(config: &Config, file: &File) -> Result<String, IndexError> {
+    let image = match embedded_cover(file) {
+        Some(image) => image,
+        None => match sidecar_cover(file)? {
+            Some(image) => image,
+            None => return Ok("".to_string()),
+        },
+    };
+
+    let hash = format!("{:x}", Sha256::digest(&image));
+    let path = original_path(&config.art_directory, &hash);
+
+    if !path.exists() {
+        println!("Caching cover `{}`...", hash);
+        write_atomically(&path, &image)?;
+    }
+
+    Ok(hash)
+}
+
+// lofty has already been run on the file by now, if it can't be read again
+// that's been reported so it's treated as having no embedded cover
+fn embedded_cover(file: &File) -> Option<Vec<u8>> {
+    let tagged_file = Probe::open(&file.path)
+        .ok()?
+        .options(ParseOptions::new().read_properties(false))
+        .guess_file_type()
+        .ok()?
+        .read()
+        .ok()?;
+
+    let pictures: Vec<_> = tagged_file
+        .tags()
+        .iter()
+        .flat_map(|tag| tag.pictures())
+        .filter(|picture| is_supported(picture.data()))
+        .collect();
+
+    pictures
+        .iter()
+        .find(|picture| picture.pic_type() == PictureType::CoverFront)
+        .or_else(|| pictures.first())
+        .map(|picture| picture.data().to_vec())
+}
+
+fn sidecar_cover(file: &File) -> Result<Option<Vec<u8>>, IndexError> {
+    let directory = match Path::new(&file.path).parent() {
+        Some(directory) => directory,
+        None => return Ok(None),
+    };
+
+    // e.g (0, "./files/album/Cover.JPG"), names are compared case insensitively
+    let mut candidates: Vec<(usize, PathBuf)> = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_lowercase());
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+
+        if let (Some(stem), Some(extension)) = (stem, extension) {
+            if !SIDECAR_EXTENSIONS.contains(&extension.as_str()) {
+                continue;
+            }
+
+            if let Some(rank) = SIDECAR_NAMES.iter().position(|name| *name == stem) {
+                candidates.push((rank, path));
+            }
+        }
+    }
+
+    candidates.sort();
+
+    for (_, path) in candidates {
+        let image = fs::read(&path)?;
+
+        if is_supported(&image) {
+            return Ok(Some(image));
+        }
+    }
+
+    Ok(None)
+}
+
+fn is_supported(image: &[u8]) -> bool {
+    matches!(
+        image::guess_format(image),
+        Ok(ImageFormat::Jpeg) | Ok(ImageFormat::Png)
+    )
+}
+
+// Covers are spread over 256 directories so none of them gets huge,
+// e.g ./art/3f/3fa9...
+fn original_path(art_directory: &str, hash: &str) -> PathBuf {
+    Path::new(art_directory).join(&hash[0..2]).join(hash)
+}
+
+fn thumbnail_path(art_directory: &str, hash: &str, size: u32) -> PathBuf {
+    Path::new(art_directory)
+        .join(&hash[0..2])
+        .join(format!("{}-{}.jpg", hash, size))
+}
+
+// The configured thumbnail size closest to what was asked for, rounding up so
+// the image is never scaled up by the browser. Anything bigger than the
+// largest size gets the largest size.
+pub fn snap_size(sizes: &[u32], requested: u32) -> Option<u32> {
+    sizes
+        .iter()
+        .filter(|size| **size >= requested)
+        .min()
+        .or_else(|| sizes.iter().max())
+        .copied()
+}
+
+// Reads a cover from the cache, as stored when `size` is None or as a JPEG
+// that fits in a `size` square. Thumbnails are made the first time they're
+// asked for and kept. Returns the image and its mime type.
+pub fn read_cover(
+    art_directory: &str,
+    hash: &str,
+    size: Option<u32>,
+) -> Result<(Vec<u8>, &'static str), IndexError> {
+    // the hash comes from the database but it's used in a path, so be strict
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(IndexError::InvalidPath(PathBuf::from(hash)));
+    }
+
+    let original = original_path(art_directory, hash);
+
+    let size = match size {
+        Some(size) => size,
+        None => {
+            let image = fs::read(&original)?;
+            let mime = match image::guess_format(&image) {
+                Ok(ImageFormat::Png) => "image/png",
+                _ => "image/jpeg",
+            };
+            return Ok((image, mime));
+        }
+    };
+
+    let thumbnail = thumbnail_path(art_directory, hash, size);
+
+    if let Ok(image) = fs::read(&thumbnail) {
+        return Ok((image, "image/jpeg"));
+    }
+
+    println!("Resizing cover `{}` to {}px...", hash, size);
+    let decoded = ImageReader::open(&original)?
+        .with_guessed_format()?
+        .decode()
+        .map_err(|err| IndexError::Io(std::io::Error::other(err)))?;
+
+    let mut encoded = Vec::new();
+    decoded
+        .thumbnail(size, size)
+        .into_rgb8()
+        .write_with_encoder(image::codecs::jpeg::JpegEncoder::new_with_quality(
+            &mut Cursor::new(&mut encoded),
+            THUMBNAIL_QUALITY,
+        ))
+        .map_err(|err| IndexError::Io(std::io::Error::other(err)))?;
+
+    write_atomically(&thumbnail, &encoded)?;
+
+    Ok((encoded, "image/jpeg"))
+}
+
+// Written to a temporary file and renamed, so a half written image is never served
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // unique, two requests for the same thumbnail mustn't share one
+    let temporary = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+    let written = fs::write(&temporary, contents).and_then(|_| fs::rename(&temporary, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written
+}
@@ -0,0 +1,357 @@
+// Conditional requests as RFC 9110 describes them, so players can check a
+// file they already have instead of downloading it again
+// https://www.rfc-editor.org/rfc/rfc9110#name-conditional-requests
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::{HeaderMap, Method};
+
+// What a response is checked against
+pub struct Validators {
+    // strong, e.g "0fdd6cca-24dc-65f2b1c0"
+    pub etag: String,
+    // whole seconds, like HTTP dates
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    // `tag` tells apart different things served from one file, e.g a file id.
+    // The size and modified time mean an edited file never keeps its ETag.
+    pub fn new(tag: &str, metadata: &Metadata) -> Validators {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .unwrap_or(0);
+
+        Validators {
+            etag: format!("\"{}-{:x}-{:x}\"", tag, metadata.len(), modified),
+            last_modified: UNIX_EPOCH + Duration::from_secs(modified),
+        }
+    }
+
+    pub fn last_modified_header(&self) -> String {
+        httpdate::fmt_http_date(self.last_modified)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    // send the file, honouring the Range header when `range` is true
+    Proceed { range: bool },
+    // 304, the client's copy is current
+    NotModified,
+    // 412
+    PreconditionFailed,
+}
+
+// The preconditions in the order RFC 9110 section 13.2.2 evaluates them
+pub fn evaluate(method: &Method, headers: &HeaderMap, validators: &Validators) -> Outcome {
+    let get_or_head = method == Method::GET || method == Method::HEAD;
+
+    if let Some(if_match) = header(headers, "if-match") {
+        if !etag_matches(if_match, &validators.etag, false) {
+            return Outcome::PreconditionFailed;
+        }
+    } else if let Some(since) = header(headers, "if-unmodified-since").and_then(parse_date) {
+        if validators.last_modified > since {
+            return Outcome::PreconditionFailed;
+        }
+    }
+
+    if let Some(if_none_match) = header(headers, "if-none-match") {
+        if etag_matches(if_none_match, &validators.etag, true) {
+            return if get_or_head {
+                Outcome::NotModified
+            } else {
+                Outcome::PreconditionFailed
+            };
+        }
+    } else if let Some(since) = header(headers, "if-modified-since").and_then(parse_date) {
+        if get_or_head && validators.last_modified <= since {
+            return Outcome::NotModified;
+        }
+    }
+
+    // Range only means something for GET
+    if method != Method::GET || header(headers, "range").is_none() {
+        return Outcome::Proceed { range: false };
+    }
+
+    let range = match header(headers, "if-range") {
+        // a changed file gets all of it rather than a piece of the new one
+        Some(if_range) if if_range.starts_with('"') || if_range.starts_with("W/") => {
+            etag_matches(if_range, &validators.etag, false)
+        }
+        Some(if_range) => parse_date(if_range) == Some(validators.last_modified),
+        None => true,
+    };
+
+    Outcome::Proceed { range }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+}
+
+fn parse_date(date: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(date).ok()
+}
+
+// `*` or a list like `"a", W/"b"`. Weak tags only count when `weak` is true
+// (If-None-Match), our own tags are always strong.
+fn etag_matches(header: &str, etag: &str, weak: bool) -> bool {
+    if header == "*" {
+        return true;
+    }
+
+    let mut rest = header;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        if rest.is_empty() {
+            return false;
+        }
+
+        let is_weak = rest.starts_with("W/");
+        if is_weak {
+            rest = &rest[2..];
+        }
+
+        // everything up to and including the closing quote
+        let end = match rest.strip_prefix('"').and_then(|tag| tag.find('"')) {
+            Some(end) => end + 2,
+            None => return false,
+        };
+
+        if &rest[..end] == etag && (weak || !is_weak) {
+            return true;
+        }
+
+        rest = &rest[end..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::HeaderValue;
+
+    const ETAG: &str = "\"1-2-3\"";
+
+    fn validators() -> Validators {
+        Validators {
+            etag: ETAG.to_string(),
+            last_modified: UNIX_EPOCH + Duration::from_secs(1_000_000_000),
+        }
+    }
+
+    fn date(seconds: u64) -> String {
+        httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    fn evaluate_with(method: Method, headers: &[(&'static str, &str)]) -> Outcome {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        evaluate(&method, &map, &validators())
+    }
+
+    const PROCEED: Outcome = Outcome::Proceed { range: false };
+
+    #[test]
+    fn no_preconditions() {
+        assert_eq!(evaluate_with(Method::GET, &[]), PROCEED);
+    }
+
+    #[test]
+    fn if_match() {
+        assert_eq!(evaluate_with(Method::GET, &[("if-match", ETAG)]), PROCEED);
+        assert_eq!(evaluate_with(Method::GET, &[("if-match", "*")]), PROCEED);
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-match", "\"other\", \"1-2-3\"")]),
+            PROCEED
+        );
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-match", "\"other\"")]),
+            Outcome::PreconditionFailed
+        );
+        // If-Match only takes strong comparisons
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-match", "W/\"1-2-3\"")]),
+            Outcome::PreconditionFailed
+        );
+    }
+
+    #[test]
+    fn if_unmodified_since() {
+        assert_eq!(
+            evaluate_with(
+                Method::GET,
+                &[("if-unmodified-since", &date(1_000_000_000))]
+            ),
+            PROCEED
+        );
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-unmodified-since", &date(999_999_999))]),
+            Outcome::PreconditionFailed
+        );
+        // dates that can't be read are ignored
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-unmodified-since", "yesterday")]),
+            PROCEED
+        );
+    }
+
+    #[test]
+    fn if_match_is_used_over_if_unmodified_since() {
+        assert_eq!(
+            evaluate_with(
+                Method::GET,
+                &[
+                    ("if-match", ETAG),
+                    ("if-unmodified-since", &date(999_999_999))
+                ]
+            ),
+            PROCEED
+        );
+        assert_eq!(
+            evaluate_with(
+                Method::GET,
+                &[
+                    ("if-match", "\"other\""),
+                    ("if-unmodified-since", &date(1_000_000_000))
+                ]
+            ),
+            Outcome::PreconditionFailed
+        );
+    }
+
+    #[test]
+    fn if_none_match() {
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-none-match", ETAG)]),
+            Outcome::NotModified
+        );
+        assert_eq!(
+            evaluate_with(Method::HEAD, &[("if-none-match", ETAG)]),
+            Outcome::NotModified
+        );
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-none-match", "*")]),
+            Outcome::NotModified
+        );
+        // If-None-Match takes weak comparisons
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-none-match", "\"a\", W/\"1-2-3\"")]),
+            Outcome::NotModified
+        );
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-none-match", "\"other\"")]),
+            PROCEED
+        );
+        // anything but GET and HEAD fails instead
+        assert_eq!(
+            evaluate_with(Method::POST, &[("if-none-match", ETAG)]),
+            Outcome::PreconditionFailed
+        );
+    }
+
+    #[test]
+    fn if_modified_since() {
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-modified-since", &date(1_000_000_000))]),
+            Outcome::NotModified
+        );
+        assert_eq!(
+            evaluate_with(Method::HEAD, &[("if-modified-since", &date(1_000_000_001))]),
+            Outcome::NotModified
+        );
+        assert_eq!(
+            evaluate_with(Method::GET, &[("if-modified-since", &date(999_999_999))]),
+            PROCEED
+        );
+        // only GET and HEAD look at it
+        assert_eq!(
+            evaluate_with(Method::POST, &[("if-modified-since", &date(1_000_000_000))]),
+            PROCEED
+        );
+    }
+
+    #[test]
+    fn if_none_match_is_used_over_if_modified_since() {
+        assert_eq!(
+            evaluate_with(
+                Method::GET,
+                &[
+                    ("if-none-match", "\"other\""),
+                    ("if-modified-since", &date(1_000_000_000))
+                ]
+            ),
+            PROCEED
+        );
+        assert_eq!(
+            evaluate_with(
+                Method::GET,
+                &[
+                    ("if-none-match", ETAG),
+                    ("if-modified-since", &date(999_999_999))
+                ]
+            ),
+            Outcome::NotModified
+        );
+    }
+
+    #[test]
+    fn if_range() {
+        let ranged = |headers: &[(&'static str, &str)]| {
+            let mut headers = headers.to_vec();
+            headers.push(("range", "bytes=0-1"));
+            evaluate_with(Method::GET, &headers)
+        };
+
+        assert_eq!(ranged(&[]), Outcome::Proceed { range: true });
+        assert_eq!(
+            ranged(&[("if-range", ETAG)]),
+            Outcome::Proceed { range: true }
+        );
+        assert_eq!(
+            ranged(&[("if-range", "\"other\"")]),
+            Outcome::Proceed { range: false }
+        );
+        // If-Range only takes strong comparisons
+        assert_eq!(
+            ranged(&[("if-range", "W/\"1-2-3\"")]),
+            Outcome::Proceed { range: false }
+        );
+        assert_eq!(
+            ranged(&[("if-range", &date(1_000_000_000))]),
+            Outcome::Proceed { range: true }
+        );
+        assert_eq!(
+            ranged(&[("if-range", &date(1_000_000_001))]),
+            Outcome::Proceed { range: false }
+        );
+        // HEAD never gets a range
+        assert_eq!(
+            evaluate_with(Method::HEAD, &[("range", "bytes=0-1")]),
+            PROCEED
+        );
+    }
+
+    #[test]
+    fn etag_lists() {
+        assert!(etag_matches("*", ETAG, false));
+        assert!(etag_matches(ETAG, ETAG, false));
+        assert!(etag_matches("\"a\",\"1-2-3\"", ETAG, false));
+        assert!(etag_matches(" \"a\" , W/\"1-2-3\" ", ETAG, true));
+        assert!(!etag_matches("W/\"1-2-3\"", ETAG, false));
+        assert!(!etag_matches("1-2-3", ETAG, true));
+        assert!(!etag_matches("\"1-2-3", ETAG, true));
+        assert!(!etag_matches("\"1-2-3-4\"", ETAG, true));
+        assert!(!etag_matches("", ETAG, true));
+    }
+}
@@ -0,0 +1,326 @@
+use ini::Ini;
+use std::env;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const DEFAULT_CONFIG_FILE_PATH: &str = "./conf.ini";
+
+// Every setting can be overridden with an environment variable named
+// AURALIST_<SECTION>_<KEY>, e.g AURALIST_SERVE_PORT=8080
+const ENV_PREFIX: &str = "AURALIST";
+
+// Every extension lofty can probe, the format itself is detected from the
+// file's content when it's warmed so a mislabelled file is still read
+// https://docs.rs/lofty/latest/lofty/#supported-formats
+const DEFAULT_EXTENSIONS: &[&str] = &[
+    "3gp", "aac", "afc", "aif", "aifc", "aiff", "ape", "flac", "m4a", "m4b", "m4p", "m4r", "m4v",
+    "mp+", "mp1", "mp2", "mp3", "mp4", "mpc", "mpp", "oga", "ogg", "opus", "spx", "wav", "wave",
+    "wv",
+];
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // [index]
+    pub directory: String,
+    pub exclusions: String,
+    pub extensions: Vec<String>,
+    pub watch: bool,
+    pub rescan_interval: u64,
+
+    // [warm]
+    pub mix_threshold: u64,
+    pub max_duration: u64,
+
+    // [art]
+    pub art_directory: String,
+    pub art_sizes: Vec<u32>,
+
+    // [analyse]
+    pub analyse: bool,
+    pub waveform_resolutions: Vec<u32>,
+
+    // [transcode]
+    pub transcode_cache: String,
+    pub transcode_opus: String,
+    pub transcode_mp3: String,
+    pub transcode_max_jobs: usize,
+
+    // [radio]
+    pub radio_bitrate: u32,
+    pub radio_sample_rate: u32,
+    pub radio_channels: u8,
+
+    // [subsonic]
+    pub subsonic_username: String,
+    pub subsonic_password: String,
+
+    // [serve]
+    pub address: IpAddr,
+    pub port: u16,
+    pub public_url: String,
+    // signs play tokens, empty means one generated and kept in the database
+    pub token_secret: String,
+    // seconds a play token works for at least
+    pub token_lifetime: u64,
+    // seconds an unused shuffle session is kept for
+    pub session_lifetime: u64,
+    pub cors_origins: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            directory: "./files".to_string(),
+            exclusions: "./exclusions.txt".to_string(),
+            extensions: DEFAULT_EXTENSIONS
+                .iter()
+                .map(|extension| extension.to_string())
+                .collect(),
+            watch: false,
+            rescan_interval: 3600,
+            mix_threshold: 23 * 60,
+            max_duration: 12000,
+            art_directory: "./art".to_string(),
+            art_sizes: vec![64, 128, 256, 512],
+            analyse: true,
+            waveform_resolutions: vec![256, 1024, 4096],
+            transcode_cache: "./transcodes".to_string(),
+            // opt in, see the README for ffmpeg commands
+            transcode_opus: "".to_string(),
+            transcode_mp3: "".to_string(),
+            transcode_max_jobs: 4,
+            radio_bitrate: 128,
+            radio_sample_rate: 44100,
+            radio_channels: 2,
+            subsonic_username: "auralist".to_string(),
+            subsonic_password: "".to_string(),
+            address: IpAddr::from([0, 0, 0, 0]),
+            port: 1337,
+            public_url: "".to_string(),
+            token_secret: "".to_string(),
+            token_lifetime: 86400,
+            session_lifetime: 86400,
+            cors_origins: vec![
+                "https://randomsound.uk".to_string(),
+                "http://localhost:1338".to_string(),
+                "http://localhost:1337".to_string(),
+                "http://192.168.2.41:1337".to_string(),
+            ],
+        }
+    }
+}
+
+impl Config {
+    // The path of the config file, AURALIST_CONFIG or ./conf.ini
+    pub fn path() -> String {
+        match env::var(format!("{}_CONFIG", ENV_PREFIX)) {
+            Ok(path) => path,
+            Err(_) => DEFAULT_CONFIG_FILE_PATH.to_string(),
+        }
+    }
+
+    // Loads conf.ini if it exists, falls back to the defaults for anything missing
+    pub fn load(path: &str) -> Result<Config, String> {
+        let conf = if Path::new(path).exists() {
+            println!("Loading config from `{}`...", path);
+            match Ini::load_from_file(path) {
+                Ok(conf) => Some(conf),
+                Err(err) => return Err(format!("Cannot read `{}`: {}", path, err)),
+            }
+        } else {
+            println!("Config file `{}` is missing, using defaults...", path);
+            None
+        };
+
+        let default = Config::default();
+
+        Ok(Config {
+            directory: string_value(&conf, "index", "directory", default.directory),
+            exclusions: string_value(&conf, "index", "exclusions", default.exclusions),
+            extensions: list_value(&conf, "index", "extensions", default.extensions),
+            watch: parsed_value(&conf, "index", "watch", default.watch)?,
+            rescan_interval: parsed_value(
+                &conf,
+                "index",
+                "rescan_interval",
+                default.rescan_interval,
+            )?,
+            mix_threshold: parsed_value(&conf, "warm", "mix_threshold", default.mix_threshold)?,
+            max_duration: parsed_value(&conf, "warm", "max_duration", default.max_duration)?,
+            art_directory: string_value(&conf, "art", "directory", default.art_directory),
+            art_sizes: parsed_list_value(&conf, "art", "sizes", default.art_sizes)?,
+            analyse: parsed_value(&conf, "analyse", "enabled", default.analyse)?,
+            waveform_resolutions: parsed_list_value(
+                &conf,
+                "analyse",
+                "waveform_resolutions",
+                default.waveform_resolutions,
+            )?,
+            transcode_cache: string_value(&conf, "transcode", "cache", default.transcode_cache),
+            transcode_opus: string_value(&conf, "transcode", "opus", default.transcode_opus),
+            transcode_mp3: string_value(&conf, "transcode", "mp3", default.transcode_mp3),
+            transcode_max_jobs: parsed_value(
+                &conf,
+                "transcode",
+                "max_jobs",
+                default.transcode_max_jobs,
+            )?,
+            radio_bitrate: parsed_value(&conf, "radio", "bitrate", default.radio_bitrate)?,
+            radio_sample_rate: parsed_value(
+                &conf,
+                "radio",
+                "sample_rate",
+                default.radio_sample_rate,
+            )?,
+            radio_channels: parsed_value(&conf, "radio", "channels", default.radio_channels)?,
+            subsonic_username: string_value(
+                &conf,
+                "subsonic",
+                "username",
+                default.subsonic_username,
+            ),
+            subsonic_password: string_value(
+                &conf,
+                "subsonic",
+                "password",
+                default.subsonic_password,
+            ),
+            address: parsed_value(&conf, "serve", "address", default.address)?,
+            port: parsed_value(&conf, "serve", "port", default.port)?,
+            public_url: string_value(&conf, "serve", "public_url", default.public_url),
+            token_secret: string_value(&conf, "serve", "token_secret", default.token_secret),
+            token_lifetime: parsed_value(&conf, "serve", "token_lifetime", default.token_lifetime)?,
+            session_lifetime: parsed_value(
+                &conf,
+                "serve",
+                "session_lifetime",
+                default.session_lifetime,
+            )?,
+            cors_origins: list_value(&conf, "serve", "cors_origins", default.cors_origins),
+        })
+    }
+
+    pub fn to_ini(&self) -> Ini {
+        let mut conf = Ini::new();
+        conf.with_section(Some("index"))
+            .set("directory", &self.directory)
+            .set("exclusions", &self.exclusions)
+            .set("extensions", self.extensions.join(","))
+            .set("watch", self.watch.to_string())
+            .set("rescan_interval", self.rescan_interval.to_string());
+        conf.with_section(Some("warm"))
+            .set("mix_threshold", self.mix_threshold.to_string())
+            .set("max_duration", self.max_duration.to_string());
+        conf.with_section(Some("art"))
+            .set("directory", &self.art_directory)
+            .set(
+                "sizes",
+                self.art_sizes
+                    .iter()
+                    .map(|size| size.to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            );
+        conf.with_section(Some("analyse"))
+            .set("enabled", self.analyse.to_string())
+            .set(
+                "waveform_resolutions",
+                self.waveform_resolutions
+                    .iter()
+                    .map(|buckets| buckets.to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            );
+        conf.with_section(Some("transcode"))
+            .set("cache", &self.transcode_cache)
+            .set("opus", &self.transcode_opus)
+            .set("mp3", &self.transcode_mp3)
+            .set("max_jobs", self.transcode_max_jobs.to_string());
+        conf.with_section(Some("radio"))
+            .set("bitrate", self.radio_bitrate.to_string())
+            .set("sample_rate", self.radio_sample_rate.to_string())
+            .set("channels", self.radio_channels.to_string());
+        conf.with_section(Some("subsonic"))
+            .set("username", &self.subsonic_username)
+            .set("password", &self.subsonic_password);
+        conf.with_section(Some("serve"))
+            .set("address", self.address.to_string())
+            .set("port", self.port.to_string())
+            .set("public_url", &self.public_url)
+            .set("token_secret", &self.token_secret)
+            .set("token_lifetime", self.token_lifetime.to_string())
+            .set("session_lifetime", self.session_lifetime.to_string())
+            .set("cors_origins", self.cors_origins.join(","));
+        conf
+    }
+}
+
+// Extensions are matched case insensitively, `*` indexes every file and leaves
+// it to lofty to work out what it is
+pub fn matches_extension(extensions: &[String], extension: &str) -> bool {
+    extensions
+        .iter()
+        .any(|wanted| wanted == "*" || wanted.eq_ignore_ascii_case(extension))
+}
+
+fn raw_value(conf: &Option<Ini>, section: &str, key: &str) -> Option<String> {
+    let env_name = format!("{}_{}_{}", ENV_PREFIX, section, key).to_uppercase();
+
+    if let Ok(value) = env::var(env_name) {
+        return Some(value);
+    }
+
+    conf.as_ref()?
+        .get_from(Some(section), key)
+        .map(|value| value.to_string())
+}
+
+fn string_value(conf: &Option<Ini>, section: &str, key: &str, default: String) -> String {
+    raw_value(conf, section, key).unwrap_or(default)
+}
+
+// Comma separated e.g "flac,mp3", an empty value means an empty list
+fn list_value(conf: &Option<Ini>, section: &str, key: &str, default: Vec<String>) -> Vec<String> {
+    match raw_value(conf, section, key) {
+        Some(value) => value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        None => default,
+    }
+}
+
+fn parsed_list_value<T: FromStr>(
+    conf: &Option<Ini>,
+    section: &str,
+    key: &str,
+    default: Vec<T>,
+) -> Result<Vec<T>, String> {
+    match raw_value(conf, section, key) {
+        Some(_) => list_value(conf, section, key, Vec::new())
+            .iter()
+            .map(|item| {
+                item.parse::<T>()
+                    .map_err(|_| format!("Invalid value for `{}.{}`: `{}`", section, key, item))
+            })
+            .collect(),
+        None => Ok(default),
+    }
+}
+
+fn parsed_value<T: FromStr>(
+    conf: &Option<Ini>,
+    section: &str,
+    key: &str,
+    default: T,
+) -> Result<T, String> {
+    match raw_value(conf, section, key) {
+        Some(value) => value
+            .trim()
+            .parse::<T>()
+            .map_err(|_| format!("Invalid value for `{}.{}`: `{}`", section, key, value)),
+        None => Ok(default),
+    }
+}
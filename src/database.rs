@@ -2,10 +2,141 @@ use rusqlite::Connection as RuConnection;
 
 pub struct SQLite;
 
+// Schema migrations, applied once each and in order. `PRAGMA user_version`
+// holds how many have been applied, so only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: files table, IF NOT EXISTS so databases from before versioning still upgrade
+    "
+    CREATE TABLE IF NOT EXISTS files (
+        id            INTEGER PRIMARY KEY,
+        path          TEXT NOT NULL,
+        file_name     TEXT NOT NULL,
+        file_ext      TEXT NOT NULL,
+        file_size     INTEGER,
+        file_modified INTEGER,
+        title         TEXT NOT NULL,
+        artist        TEXT NOT NULL,
+        album         TEXT NOT NULL,
+        duration      INTEGER,
+        indexed_at    INTEGER,
+        accessed_at   INTEGER,
+        parse_fail    INTEGER
+    );
+
+    CREATE INDEX IF NOT EXISTS duration ON files (duration);
+    ",
+    // 2: full text search
+    "
+    CREATE VIRTUAL TABLE IF NOT EXISTS search
+    USING FTS5(path, file_name, file_ext, title, artist, album);
+    ",
+    // 3: make search an external content index over files, keyed on the murmur id,
+    // so it can't hold duplicate or stale rows, and let triggers keep it in sync
+    "
+    DROP TABLE IF EXISTS search;
+
+    CREATE VIRTUAL TABLE search
+    USING FTS5(path, file_name, file_ext, title, artist, album, content='files', content_rowid='id');
+
+    CREATE TRIGGER files_after_insert AFTER INSERT ON files BEGIN
+        INSERT INTO search (rowid, path, file_name, file_ext, title, artist, album)
+        VALUES (new.id, new.path, new.file_name, new.file_ext, new.title, new.artist, new.album);
+    END;
+
+    CREATE TRIGGER files_after_delete AFTER DELETE ON files BEGIN
+        INSERT INTO search (search, rowid, path, file_name, file_ext, title, artist, album)
+        VALUES ('delete', old.id, old.path, old.file_name, old.file_ext, old.title, old.artist, old.album);
+    END;
+
+    CREATE TRIGGER files_after_update AFTER UPDATE ON files BEGIN
+        INSERT INTO search (search, rowid, path, file_name, file_ext, title, artist, album)
+        VALUES ('delete', old.id, old.path, old.file_name, old.file_ext, old.title, old.artist, old.album);
+        INSERT INTO search (rowid, path, file_name, file_ext, title, artist, album)
+        VALUES (new.id, new.path, new.file_name, new.file_ext, new.title, new.artist, new.album);
+    END;
+
+    INSERT INTO search (search) VALUES ('rebuild');
+    ",
+    // 4: sampled content hash, used to recognise a file that moved to a new path
+    "
+    ALTER TABLE files ADD COLUMN content_hash INTEGER NOT NULL DEFAULT 0;
+
+    CREATE INDEX IF NOT EXISTS file_size ON files (file_size);
+    ",
+    // 5: the format lofty detected from the content, the extension can lie
+    "
+    ALTER TABLE files ADD COLUMN format TEXT NOT NULL DEFAULT '';
+    ",
+    // 6: richer tags and audio properties, 0 or empty when unknown
+    "
+    ALTER TABLE files ADD COLUMN album_artist TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN track_number INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN track_total INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN disc_number INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN disc_total INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN year INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN date TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN genre TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN composer TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN comment TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN label TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN bpm INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN isrc TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN musicbrainz_recording_id TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN musicbrainz_track_id TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN musicbrainz_release_id TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN musicbrainz_release_group_id TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN musicbrainz_artist_id TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN musicbrainz_album_artist_id TEXT NOT NULL DEFAULT '';
+    ALTER TABLE files ADD COLUMN bitrate INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN sample_rate INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN bit_depth INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE files ADD COLUMN channels INTEGER NOT NULL DEFAULT 0;
+    ",
+    // 7: content hash of the cover in the art cache, empty when there isn't one
+    "
+    ALTER TABLE files ADD COLUMN art TEXT NOT NULL DEFAULT '';
+    ",
+    // 8: decode based analysis, waveform peaks are interleaved signed min/max bytes
+    "
+    ALTER TABLE files ADD COLUMN analysed_at INTEGER NOT NULL DEFAULT 0;
+
+    CREATE TABLE waveforms (
+        file_id INTEGER NOT NULL,
+        buckets INTEGER NOT NULL,
+        peaks   BLOB NOT NULL,
+        PRIMARY KEY (file_id, buckets)
+    );
+
+    CREATE TRIGGER files_after_delete_waveforms AFTER DELETE ON files BEGIN
+        DELETE FROM waveforms WHERE file_id = old.id;
+    END;
+    ",
+    // 9: EBU R128 loudness from the analysis and ReplayGain from tags, NULL when unknown
+    "
+    ALTER TABLE files ADD COLUMN loudness REAL;
+    ALTER TABLE files ADD COLUMN true_peak REAL;
+    ALTER TABLE files ADD COLUMN loudness_range REAL;
+    ALTER TABLE files ADD COLUMN replaygain_track_gain REAL;
+    ALTER TABLE files ADD COLUMN replaygain_track_peak REAL;
+    ALTER TABLE files ADD COLUMN replaygain_album_gain REAL;
+    ALTER TABLE files ADD COLUMN replaygain_album_peak REAL;
+    ",
+    // 10: server wide values that have to outlive a restart, e.g the token secret
+    "
+    CREATE TABLE settings (
+        name  TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    ",
+];
+
 impl SQLite {
     pub fn initialize() -> RuConnection {
         let persist = SQLite::connect();
-        SQLite::migrate();
+        if let Err(error) = SQLite::migrate() {
+            panic!("Cannot migrate SQLite: {}", error);
+        }
         persist
     }
 
@@ -16,46 +147,46 @@ impl SQLite {
         }
     }
 
-    pub fn migrate() {
+    pub fn schema_version(conn: &RuConnection) -> rusqlite::Result<usize> {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+    }
+
+    pub fn migrate() -> rusqlite::Result<()> {
         println!("Initializing DB...");
 
-        let conn = SQLite::connect();
+        let mut conn = SQLite::connect();
+        let version = SQLite::schema_version(&conn)?;
 
-        let sql = "
-        CREATE TABLE IF NOT EXISTS files (
-            id            INTEGER PRIMARY KEY,
-            path          TEXT NOT NULL,
-            file_name     TEXT NOT NULL,
-            file_ext      TEXT NOT NULL,
-            file_size     INTEGER,
-            file_modified INTEGER,
-            title         TEXT NOT NULL,
-            artist        TEXT NOT NULL,
-            album         TEXT NOT NULL,
-            duration      INTEGER,
-            indexed_at    INTEGER,
-            accessed_at   INTEGER,
-            parse_fail    INTEGER
-        );
-
-        CREATE INDEX IF NOT EXISTS duration ON files (duration);
-        ";
-
-        match conn.execute_batch(sql) {
-            Ok(_) => println!("Successfully created files table."),
-            Err(err) => println!("update failed: migration 1: {}", err),
+        if version > MIGRATIONS.len() {
+            panic!(
+                "Database schema version {} is newer than this build supports ({})",
+                version,
+                MIGRATIONS.len()
+            );
         }
 
-        let conn = SQLite::connect();
+        for (index, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+            let next_version = index + 1;
+            println!("Applying migration {}...", next_version);
 
-        let sql = "
-        CREATE VIRTUAL TABLE IF NOT EXISTS search
-        USING FTS5(path, file_name, file_ext, title, artist, album);
-        ";
+            // Each migration and its version bump either both happen or neither does
+            let tx = conn.transaction()?;
+            tx.execute_batch(sql)?;
+            tx.pragma_update(None, "user_version", next_version)?;
+            tx.commit()?;
 
-        match conn.execute_batch(sql) {
-            Ok(_) => println!("Successfully created search table."),
-            Err(err) => println!("update failed: migration 2: {}", err),
+            println!("Successfully applied migration {}.", next_version);
         }
+
+        println!("Database schema is at version {}.", MIGRATIONS.len());
+
+        Ok(())
+    }
+
+    // Throws away the search index and rebuilds it from the files table
+    pub fn rebuild_search() -> rusqlite::Result<()> {
+        let conn = SQLite::connect();
+        conn.execute("INSERT INTO search (search) VALUES ('rebuild')", [])?;
+        Ok(())
     }
 }
@@ -0,0 +1,69 @@
+// Calendar dates from unix seconds, for the few places that need to write
+// one themselves (Subsonic timestamps and zip entries)
+
+pub struct Civil {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+}
+
+// UTC, proleptic Gregorian
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn from_unix(seconds: u64) -> Civil {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    Civil {
+        year,
+        month,
+        day,
+        hour: time / 3600,
+        minute: time % 3600 / 60,
+        second: time % 60,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ymd_hms(seconds: u64) -> (i64, i64, i64, u64, u64, u64) {
+        let civil = from_unix(seconds);
+        (
+            civil.year,
+            civil.month,
+            civil.day,
+            civil.hour,
+            civil.minute,
+            civil.second,
+        )
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(ymd_hms(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(ymd_hms(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(ymd_hms(951_868_799), (2000, 2, 29, 23, 59, 59));
+        assert_eq!(ymd_hms(1_709_294_400), (2024, 3, 1, 12, 0, 0));
+        assert_eq!(ymd_hms(1_735_689_599), (2024, 12, 31, 23, 59, 59));
+        assert_eq!(ymd_hms(4_102_444_800), (2100, 1, 1, 0, 0, 0));
+    }
+}
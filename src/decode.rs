@@ -0,0 +1,133 @@
+use crate::error::IndexError;
+use std::fs::File as StdFsFile;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+// One decoded packet
+pub struct Pcm<'a> {
+    // interleaved, e.g left, right, left, right...
+    pub samples: &'a [f32],
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+// Decodes a file into interleaved f32 samples, one packet at a time so even
+// a three hour mix never has to be held in memory
+pub struct PcmReader {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    buffer: Option<SampleBuffer<f32>>,
+    channels: usize,
+    sample_rate: u32,
+}
+
+impl PcmReader {
+    pub fn open(path: &str, extension: &str) -> Result<PcmReader, IndexError> {
+        let source = StdFsFile::open(path)?;
+        let stream = MediaSourceStream::new(Box::new(source), Default::default());
+
+        // the extension is only a hint, symphonia still sniffs the content
+        let mut hint = Hint::new();
+        hint.with_extension(extension);
+
+        let probed = symphonia::default::get_probe().format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?;
+
+        let format = probed.format;
+
+        let track = match format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        {
+            Some(track) => track,
+            None => return Err(SymphoniaError::Unsupported("no audio track").into()),
+        };
+
+        let track_id = track.id;
+        let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+        let channels = track
+            .codec_params
+            .channels
+            .map_or(0, |channels| channels.count());
+
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())?;
+
+        Ok(PcmReader {
+            format,
+            decoder,
+            track_id,
+            buffer: None,
+            channels,
+            sample_rate,
+        })
+    }
+
+    // The next run of interleaved samples, None at the end of the file.
+    // Corrupt packets are skipped rather than failing the whole file.
+    pub fn next_samples(&mut self) -> Result<Option<Pcm<'_>>, IndexError> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(None)
+                }
+                Err(SymphoniaError::ResetRequired) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(SymphoniaError::DecodeError(err)) => {
+                    println!("Skipping a corrupt packet: {}", err);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            if decoded.frames() == 0 {
+                continue;
+            }
+
+            let spec = *decoded.spec();
+            self.channels = spec.channels.count();
+            self.sample_rate = spec.rate;
+
+            let needed = decoded.capacity() * self.channels;
+            if self
+                .buffer
+                .as_ref()
+                .is_none_or(|buffer| buffer.capacity() < needed)
+            {
+                self.buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+            }
+
+            // just checked it's there
+            let buffer = self.buffer.as_mut().unwrap();
+            buffer.copy_interleaved_ref(decoded);
+
+            return Ok(Some(Pcm {
+                samples: buffer.samples(),
+                channels: self.channels,
+                sample_rate: self.sample_rate,
+            }));
+        }
+    }
+}
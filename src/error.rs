@@ -0,0 +1,104 @@
+use std::fmt;
+use std::path::PathBuf;
+
+// Everything that can go wrong with a single file while indexing or warming it
+#[derive(Debug)]
+pub enum IndexError {
+    // walkdir couldn't read a directory entry, e.g permission denied
+    Walk(walkdir::Error),
+    // the path isn't valid UTF-8 or has no file name
+    InvalidPath(PathBuf),
+    // the file couldn't be opened or read, e.g it was deleted mid-scan
+    Io(std::io::Error),
+    // lofty couldn't make sense of the file
+    Tags(lofty::error::LoftyError),
+    // symphonia couldn't decode the audio
+    Decode(symphonia::core::errors::Error),
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::Walk(err) => write!(f, "{}", err),
+            IndexError::InvalidPath(path) => write!(f, "invalid path {:?}", path),
+            IndexError::Io(err) => write!(f, "{}", err),
+            IndexError::Tags(err) => write!(f, "{}", err),
+            IndexError::Decode(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl From<walkdir::Error> for IndexError {
+    fn from(err: walkdir::Error) -> Self {
+        IndexError::Walk(err)
+    }
+}
+
+impl From<std::io::Error> for IndexError {
+    fn from(err: std::io::Error) -> Self {
+        IndexError::Io(err)
+    }
+}
+
+impl From<lofty::error::LoftyError> for IndexError {
+    fn from(err: lofty::error::LoftyError) -> Self {
+        IndexError::Tags(err)
+    }
+}
+
+impl From<symphonia::core::errors::Error> for IndexError {
+    fn from(err: symphonia::core::errors::Error) -> Self {
+        IndexError::Decode(err)
+    }
+}
+
+// Which part of the pipeline a file failed in
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    Walk,
+    Index,
+    Warm,
+    Hash,
+    Art,
+    Analyse,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Stage::Walk => "walk",
+            Stage::Index => "index",
+            Stage::Warm => "warm",
+            Stage::Hash => "hash",
+            Stage::Art => "art",
+            Stage::Analyse => "analyse",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// A failure that was recorded so the walk or warm could carry on
+#[derive(Debug)]
+pub struct FileError {
+    pub path: String,
+    pub stage: Stage,
+    pub message: String,
+}
+
+impl FileError {
+    pub fn new(path: impl Into<String>, stage: Stage, error: IndexError) -> FileError {
+        FileError {
+            path: path.into(),
+            stage,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] `{}`: {}", self.stage, self.path, self.message)
+    }
+}
@@ -0,0 +1,455 @@
+// EBU R128 loudness (ITU-R BS.1770-4 and EBU Tech 3342), measured while a file
+// is decoded so nothing has to be kept but one energy value per 100ms
+
+// Blocks quieter than this are never counted, in LUFS
+const ABSOLUTE_GATE: f64 = -70.0;
+
+// How far below the ungated level a block can be before it's ignored, in LU
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+
+// Gating blocks are 400ms with 75% overlap, short term windows 3s with a 1s
+// hop, both are built from 100ms sub blocks
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+const SUB_BLOCKS_PER_SHORT_TERM: usize = 30;
+const SUB_BLOCKS_PER_SHORT_TERM_HOP: usize = 10;
+
+// Taps per phase of the true peak interpolation filter
+const TRUE_PEAK_TAPS: usize = 12;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Loudness {
+    // LUFS, None for silence
+    pub integrated: Option<f64>,
+    // dBTP, None for silence
+    pub true_peak: Option<f64>,
+    // LU, None when the file is shorter than one short term window
+    pub range: Option<f64>,
+}
+
+pub struct LoudnessMeter {
+    channels: usize,
+    sample_rate: u32,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    peaks: Vec<TruePeak>,
+    frames_per_sub_block: usize,
+    frames_in_sub_block: usize,
+    energy: f64,
+    sub_blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, sample_rate: u32) -> LoudnessMeter {
+        LoudnessMeter {
+            channels,
+            sample_rate,
+            weights: channel_weights(channels),
+            filters: (0..channels)
+                .map(|_| k_weighting(f64::from(sample_rate)))
+                .collect(),
+            peaks: (0..channels).map(|_| TruePeak::new(sample_rate)).collect(),
+            frames_per_sub_block: (sample_rate as usize / 10).max(1),
+            frames_in_sub_block: 0,
+            energy: 0.0,
+            sub_blocks: Vec::new(),
+        }
+    }
+
+    // Whether samples in this layout can be fed to the meter, a file that
+    // changes layout halfway through only has its first layout measured
+    pub fn accepts(&self, channels: usize, sample_rate: u32) -> bool {
+        self.channels == channels && self.sample_rate == sample_rate
+    }
+
+    // Interleaved samples
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = f64::from(*sample);
+
+                self.peaks[channel].push(sample);
+
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let filtered = high_pass.process(shelf.process(sample));
+                self.energy += self.weights[channel] * filtered * filtered;
+            }
+
+            self.frames_in_sub_block += 1;
+
+            if self.frames_in_sub_block == self.frames_per_sub_block {
+                self.sub_blocks.push(self.energy);
+                self.energy = 0.0;
+                self.frames_in_sub_block = 0;
+            }
+        }
+    }
+
+    pub fn finish(self) -> Loudness {
+        let frames = self.frames_per_sub_block as f64;
+
+        let blocks = windows(&self.sub_blocks, SUB_BLOCKS_PER_BLOCK, 1, frames);
+        let short_terms = windows(
+            &self.sub_blocks,
+            SUB_BLOCKS_PER_SHORT_TERM,
+            SUB_BLOCKS_PER_SHORT_TERM_HOP,
+            frames,
+        );
+
+        let peak = self
+            .peaks
+            .iter()
+            .map(|peak| peak.max)
+            .fold(0.0_f64, f64::max);
+
+        Loudness {
+            integrated: integrated(&blocks),
+            true_peak: if peak > 0.0 {
+                Some(20.0 * peak.log10())
+            } else {
+                None
+            },
+            range: range(&short_terms),
+        }
+    }
+}
+
+// Mean weighted energy of every window of `size` sub blocks, `hop` apart
+fn windows(sub_blocks: &[f64], size: usize, hop: usize, frames: f64) -> Vec<f64> {
+    if sub_blocks.len() < size {
+        return Vec::new();
+    }
+
+    (0..=sub_blocks.len() - size)
+        .step_by(hop)
+        .map(|start| sub_blocks[start..start + size].iter().sum::<f64>() / (size as f64 * frames))
+        .collect()
+}
+
+fn to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn integrated(blocks: &[f64]) -> Option<f64> {
+    let loud: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|energy| to_lufs(*energy) > ABSOLUTE_GATE)
+        .collect();
+
+    if loud.is_empty() {
+        return None;
+    }
+
+    let relative_gate = to_lufs(mean(&loud)) + INTEGRATED_RELATIVE_GATE;
+
+    let gated: Vec<f64> = loud
+        .into_iter()
+        .filter(|energy| to_lufs(*energy) > relative_gate)
+        .collect();
+
+    if gated.is_empty() {
+        return None;
+    }
+
+    Some(to_lufs(mean(&gated)))
+}
+
+// The spread between the 10th and 95th percentile of short term loudness
+fn range(short_terms: &[f64]) -> Option<f64> {
+    let loud: Vec<f64> = short_terms
+        .iter()
+        .copied()
+        .filter(|energy| to_lufs(*energy) > ABSOLUTE_GATE)
+        .collect();
+
+    if loud.is_empty() {
+        return None;
+    }
+
+    let relative_gate = to_lufs(mean(&loud)) + RANGE_RELATIVE_GATE;
+
+    let mut gated: Vec<f64> = loud
+        .into_iter()
+        .map(to_lufs)
+        .filter(|loudness| *loudness > relative_gate)
+        .collect();
+
+    if gated.is_empty() {
+        return None;
+    }
+
+    gated.sort_by(|a, b| a.total_cmp(b));
+
+    let last = (gated.len() - 1) as f64;
+    let low = gated[(last * 0.10).round() as usize];
+    let high = gated[(last * 0.95).round() as usize];
+
+    Some(high - low)
+}
+
+// Surround channels count for more, the LFE isn't counted at all. Anything
+// that isn't 5.1 is treated as front channels.
+fn channel_weights(channels: usize) -> Vec<f64> {
+    match channels {
+        // L, R, C, LFE, Ls, Rs
+        6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+        _ => vec![1.0; channels],
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    // transposed direct form II
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// The K-weighting pre-filter (a high shelf modelling the head) followed by the
+// RLB high pass, with the coefficients worked out for any sample rate
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+
+    let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+    let vh = 10.0_f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+
+    let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+// Peaks between samples are found by upsampling (4x below 96kHz, 2x below
+// 192kHz) with a windowed sinc filter
+struct TruePeak {
+    factor: usize,
+    coefficients: Vec<f64>,
+    history: Vec<f64>,
+    position: usize,
+    max: f64,
+}
+
+impl TruePeak {
+    fn new(sample_rate: u32) -> TruePeak {
+        let factor = match sample_rate {
+            0..=95999 => 4,
+            96000..=191999 => 2,
+            _ => 1,
+        };
+
+        let taps = TRUE_PEAK_TAPS * factor;
+        let middle = (taps - 1) as f64 / 2.0;
+
+        let mut coefficients: Vec<f64> = (0..taps)
+            .map(|n| {
+                let x = (n as f64 - middle) / factor as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+                };
+                let window =
+                    0.5 - 0.5 * (2.0 * std::f64::consts::PI * (n as f64 + 0.5) / taps as f64).cos();
+                sinc * window
+            })
+            .collect();
+
+        // every phase passes a constant signal through unchanged
+        for phase in 0..factor {
+            let sum: f64 = (0..TRUE_PEAK_TAPS)
+                .map(|tap| coefficients[phase + tap * factor])
+                .sum();
+
+            for tap in 0..TRUE_PEAK_TAPS {
+                coefficients[phase + tap * factor] /= sum;
+            }
+        }
+
+        TruePeak {
+            factor,
+            coefficients,
+            history: vec![0.0; TRUE_PEAK_TAPS],
+            position: 0,
+            max: 0.0,
+        }
+    }
+
+    fn push(&mut self, sample: f64) {
+        self.max = self.max.max(sample.abs());
+
+        if self.factor == 1 {
+            return;
+        }
+
+        self.history[self.position] = sample;
+
+        for phase in 0..self.factor {
+            let mut value = 0.0;
+
+            for tap in 0..TRUE_PEAK_TAPS {
+                let index = (self.position + TRUE_PEAK_TAPS - tap) % TRUE_PEAK_TAPS;
+                value += self.coefficients[phase + tap * self.factor] * self.history[index];
+            }
+
+            self.max = self.max.max(value.abs());
+        }
+
+        self.position = (self.position + 1) % TRUE_PEAK_TAPS;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    // Interleaved, the same on every channel
+    fn sine(channels: usize, frequency: f64, dbfs: f64, seconds: f64, phase: f64) -> Vec<f32> {
+        let amplitude = 10.0_f64.powf(dbfs / 20.0);
+        let frames = (seconds * f64::from(RATE)) as usize;
+
+        (0..frames)
+            .flat_map(|frame| {
+                let t = frame as f64 / f64::from(RATE);
+                let sample = amplitude * (2.0 * std::f64::consts::PI * frequency * t + phase).sin();
+                std::iter::repeat_n(sample as f32, channels)
+            })
+            .collect()
+    }
+
+    fn measure(channels: usize, samples: &[f32]) -> Loudness {
+        let mut meter = LoudnessMeter::new(channels, RATE);
+        meter.push(samples);
+        meter.finish()
+    }
+
+    fn assert_near(actual: Option<f64>, expected: f64, tolerance: f64) {
+        let actual = actual.expect("a measurement");
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    // EBU Tech 3341 case 1
+    #[test]
+    fn stereo_sine_at_minus_23() {
+        let loudness = measure(2, &sine(2, 1000.0, -23.0, 5.0, 0.0));
+
+        assert_near(loudness.integrated, -23.0, 0.1);
+    }
+
+    // A -20 dBFS sine is -23 LUFS on its own, both stereo channels count so
+    // the same sine in each is 3 LU louder
+    #[test]
+    fn sine_at_minus_20() {
+        let mono = measure(1, &sine(1, 1000.0, -20.0, 5.0, 0.0));
+        let stereo = measure(2, &sine(2, 1000.0, -20.0, 5.0, 0.0));
+
+        assert_near(mono.integrated, -23.0, 0.1);
+        assert_near(stereo.integrated, -20.0, 0.1);
+        assert_near(stereo.true_peak, -20.0, 0.1);
+    }
+
+    #[test]
+    fn silence() {
+        let loudness = measure(2, &vec![0.0; 2 * RATE as usize * 5]);
+
+        assert_eq!(loudness.integrated, None);
+        assert_eq!(loudness.true_peak, None);
+        assert_eq!(loudness.range, None);
+    }
+
+    #[test]
+    fn quiet_blocks_are_gated() {
+        let mut samples = sine(2, 1000.0, -23.0, 10.0, 0.0);
+        samples.extend(vec![0.0; 2 * RATE as usize * 10]);
+
+        assert_near(measure(2, &samples).integrated, -23.0, 0.1);
+    }
+
+    #[test]
+    fn full_scale_true_peak() {
+        let loudness = measure(2, &sine(2, 1000.0, 0.0, 5.0, 0.0));
+
+        assert_near(loudness.true_peak, 0.0, 0.5);
+    }
+
+    // A quarter of the sample rate 45 degrees out never has a sample above
+    // -3 dBFS, the peaks are all between samples
+    #[test]
+    fn inter_sample_true_peak() {
+        let samples = sine(1, 12000.0, 0.0, 5.0, std::f64::consts::FRAC_PI_4);
+        let sample_peak = samples.iter().fold(0.0_f32, |max, s| max.max(s.abs()));
+
+        assert!(20.0 * f64::from(sample_peak).log10() < -2.9);
+        assert_near(measure(1, &samples).true_peak, 0.0, 0.5);
+    }
+
+    // EBU Tech 3342 case 1
+    #[test]
+    fn loudness_range() {
+        let mut samples = sine(2, 1000.0, -20.0, 20.0, 0.0);
+        samples.extend(sine(2, 1000.0, -30.0, 20.0, 0.0));
+
+        assert_near(measure(2, &samples).range, 10.0, 1.0);
+    }
+
+    #[test]
+    fn no_range_under_one_short_term_window() {
+        let loudness = measure(2, &sine(2, 1000.0, -23.0, 2.0, 0.0));
+
+        assert!(loudness.integrated.is_some());
+        assert_eq!(loudness.range, None);
+    }
+
+    #[test]
+    fn accepts_only_the_first_layout() {
+        let meter = LoudnessMeter::new(2, RATE);
+
+        assert!(meter.accepts(2, RATE));
+        assert!(!meter.accepts(1, RATE));
+        assert!(!meter.accepts(2, 44100));
+    }
+}
@@ -3,9 +3,12 @@ use rand::seq::SliceRandom;
 use murmurhash32::murmurhash3;
 use rusqlite::params;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
-use std::path::Path;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{thread, time};
 use walkdir::WalkDir;
@@ -14,9 +17,30 @@ use warp::{http::Method, http::StatusCode, Filter, Rejection, Reply};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+mod analyse;
+mod art;
+mod conditional;
+mod config;
+use crate::config::Config;
 mod database;
+mod date;
+mod decode;
+mod error;
+mod loudness;
 use crate::database::SQLite;
+use crate::error::{FileError, IndexError, Stage};
 mod music;
+mod playlist;
+mod radio;
+mod range;
+mod search;
+mod shuffle;
+mod subsonic;
+mod token;
+mod transcode;
+mod watch;
+mod waveform;
+mod zip;
 use crate::music::File;
 use crate::music::FileHashed;
 use std::sync::{Arc, Mutex};
@@ -32,13 +56,43 @@ struct InvalidParameter;
 impl warp::reject::Reject for InvalidParameter {}
 
 fn main() {
+    let command = env::args().nth(1).unwrap_or_default();
+
+    match command.as_str() {
+        "" | "run" | "index" | "analyse" | "serve" => (),
+        "init" => {
+            init();
+            return;
+        }
+        "reindex-search" => {
+            reindex_search();
+            return;
+        }
+        "help" | "--help" | "-h" => {
+            print_usage();
+            return;
+        }
+        _ => {
+            eprintln!("Unknown command `{}`", command);
+            print_usage();
+            process::exit(1);
+        }
+    }
+
+    let mut config = match Config::load(&Config::path()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
     // murmurs with their file counterparts
     let files: HashMap<u32, File> = HashMap::new();
     let files_mutex = Arc::new(Mutex::new(files));
 
-    // random ids that need to be sought
-    let plays: HashMap<String, File> = HashMap::new();
-    let plays_mutex = Arc::new(Mutex::new(plays));
+    // listener sessions with their shuffle bags
+    let sessions_mutex = Arc::new(Mutex::new(shuffle::Sessions::default()));
 
     // murmurs of mixes
     let mixes: Vec<u32> = Vec::new();
@@ -56,51 +110,118 @@ fn main() {
     let have_been_warmed: Vec<u32> = Vec::new();
     let have_been_warmed_mutex = Arc::new(Mutex::new(have_been_warmed));
 
-    /*
+    // Files that were warmed by a previous run only need warming again if they changed
     println!("Loading old data...");
     SQLite::initialize();
     load_old_data(
+        &config,
         files_mutex.clone(),
         have_been_warmed_mutex.clone(),
         mixes_mutex.clone(),
         tunes_mutex.clone(),
     );
     println!("Finshed loading old data.");
-    */
+
+    // signs the play tokens handed out with every file
+    let signer = token::Signer::new(&config.token_secret, config.token_lifetime);
+
+    if command == "index" {
+        println!("Indexing basic file information...");
+        index(
+            &config,
+            false,
+            files_mutex.clone(),
+            to_be_warmed_mutex.clone(),
+            have_been_warmed_mutex.clone(),
+            mixes_mutex.clone(),
+            tunes_mutex.clone(),
+        );
+        println!("Warming database with more file info...");
+        warm_until_empty(
+            &config,
+            files_mutex.clone(),
+            mixes_mutex.clone(),
+            tunes_mutex.clone(),
+            to_be_warmed_mutex.clone(),
+            have_been_warmed_mutex.clone(),
+        );
+        println!("Finished indexing.");
+        return;
+    }
+
+    if command == "analyse" {
+        println!("Analysing warmed files...");
+        analyse::analyse_until_empty(&config, files_mutex.clone());
+        println!("Finished analysing.");
+        return;
+    }
+
+    // the web server needs its encoders before anyone asks for a transcode
+    transcode::check_encoders(&mut config);
 
     thread::scope(|s| {
         s.spawn(|| {
             println!("Logging queues...");
             log_queues(
                 files_mutex.clone(),
-                plays_mutex.clone(),
-                to_be_warmed_mutex.clone(),
-                have_been_warmed_mutex.clone(),
-            );
-        });
-        s.spawn(|| {
-            println!("Indexing basic file information...");
-            index(files_mutex.clone(), to_be_warmed_mutex.clone());
-        });
-        s.spawn(|| {
-            println!("Warming database with more file info...");
-            warm(
-                files_mutex.clone(),
-                mixes_mutex.clone(),
-                tunes_mutex.clone(),
                 to_be_warmed_mutex.clone(),
                 have_been_warmed_mutex.clone(),
             );
         });
+        // `serve` only answers requests, the default `run` also indexes
+        if command != "serve" {
+            s.spawn(|| {
+                println!("Indexing basic file information...");
+                index(
+                    &config,
+                    true,
+                    files_mutex.clone(),
+                    to_be_warmed_mutex.clone(),
+                    have_been_warmed_mutex.clone(),
+                    mixes_mutex.clone(),
+                    tunes_mutex.clone(),
+                );
+            });
+            s.spawn(|| {
+                println!("Warming database with more file info...");
+                warm(
+                    &config,
+                    files_mutex.clone(),
+                    mixes_mutex.clone(),
+                    tunes_mutex.clone(),
+                    to_be_warmed_mutex.clone(),
+                    have_been_warmed_mutex.clone(),
+                );
+            });
+            if config.analyse {
+                s.spawn(|| {
+                    println!("Analysing warmed files...");
+                    analyse::analyse(&config, files_mutex.clone());
+                });
+            }
+        } else {
+            s.spawn(|| {
+                println!("Reloading files from the database periodically...");
+                reload(
+                    &config,
+                    files_mutex.clone(),
+                    have_been_warmed_mutex.clone(),
+                    mixes_mutex.clone(),
+                    tunes_mutex.clone(),
+                );
+            });
+        }
         s.spawn(|| {
             println!("Starting periodic cleanup tasks...");
-            cleanup(plays_mutex.clone());
+            cleanup(&config, sessions_mutex.clone());
         });
         s.spawn(|| {
             println!("Starting web server...");
             serve(
+                &config,
                 files_mutex.clone(),
-                plays_mutex.clone(),
+                signer.clone(),
+                sessions_mutex.clone(),
                 have_been_warmed_mutex.clone(),
                 mixes_mutex.clone(),
                 tunes_mutex.clone(),
@@ -110,26 +231,65 @@ fn main() {
     });
 }
 
+fn print_usage() {
+    println!("Usage: auralist-rs [COMMAND]");
+    println!();
+    println!("Commands:");
+    println!("  run             Index, warm and serve files (default)");
+    println!("  init            Write a starter conf.ini");
+    println!("  index           Index and warm all files once, then exit");
+    println!("  analyse         Decode warmed files for waveforms, then exit");
+    println!("  serve           Serve previously indexed files without indexing");
+    println!("  reindex-search  Rebuild the search index from the files table");
+    println!("  help            Print this message");
+}
+
+fn init() {
+    let path = Config::path();
+
+    if Path::new(&path).exists() {
+        println!("Config file `{}` already exists, not overwriting it.", path);
+        return;
+    }
+
+    match Config::default().to_ini().write_to_file(&path) {
+        Ok(_) => println!("Wrote starter config to `{}`.", path),
+        Err(err) => {
+            eprintln!("Could not write `{}`: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn reindex_search() {
+    SQLite::initialize();
+
+    println!("Rebuilding search index...");
+    match SQLite::rebuild_search() {
+        Ok(_) => println!("Finished rebuilding search index."),
+        Err(err) => {
+            eprintln!("Could not rebuild search index: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
 #[tokio::main]
 async fn log_queues(
     files_mutex: Arc<std::sync::Mutex<std::collections::HashMap<u32, music::File>>>,
-    plays_mutex: Arc<Mutex<HashMap<String, File>>>,
     to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
     have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
 ) {
     loop {
         let files_mutex = files_mutex.lock().unwrap();
-        let plays_mutex = plays_mutex.lock().unwrap();
         let to_be_warmed = to_be_warmed_mutex.lock().unwrap();
         let have_been_warmed = have_been_warmed_mutex.lock().unwrap();
 
         println!("Files: {:?}", files_mutex.len());
-        println!("Plays: {:?}", plays_mutex.len());
         println!("To be warmed: {:?}", to_be_warmed.len());
         println!("Have been warmed: {:?}", have_been_warmed.len());
 
         drop(files_mutex);
-        drop(plays_mutex);
         drop(to_be_warmed);
         drop(have_been_warmed);
 
@@ -140,6 +300,7 @@ async fn log_queues(
 
 #[tokio::main]
 async fn warm(
+    config: &Config,
     files_mutex: Arc<std::sync::Mutex<std::collections::HashMap<u32, music::File>>>,
     mixes_mutex: Arc<Mutex<Vec<u32>>>,
     tunes_mutex: Arc<Mutex<Vec<u32>>>,
@@ -150,60 +311,24 @@ async fn warm(
     let mut i23 = 0;
     let mut i2323 = 0;
     loop {
-        let mut to_be_warmed = to_be_warmed_mutex.lock().unwrap();
-        let hash_to_be_warmed = to_be_warmed.pop();
-        drop(to_be_warmed);
-
-        if !hash_to_be_warmed.is_none() {
-            println!("Attempting to warm a file...");
-            println!("Locking files (warm)...");
-            let files = files_mutex.lock().unwrap();
-
-            println!("Checking if file has been warmed already...");
-            let file = match files.get(&hash_to_be_warmed.unwrap()) {
-                Some(file) => Some(file.clone()),
-                None => None,
-            };
-
-            println!("Unlocking files (warm)...");
-            drop(files);
-
-            if !file.is_none() {
-                println!("File does not exist in memory...");
-                let mut f = file.unwrap();
-                f.indexed_at = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-
-                // get info from tags if possible
-                // https://docs.rs/lofty/latest/lofty/#supported-formats
-                if f.file_ext == "mp3" || f.file_ext == "flac" {
-                    println!("---------- TRIGGER A LOFTY POPULATE...");
-                    f.populate_lofty();
-                }
-
-                // sqlite disabled for now
-                //f.save_to_database();
-
-                load_file_info_into_memory_and_mark_as_warmed(
-                    f.clone(),
-                    files_mutex.clone(),
-                    have_been_warmed_mutex.clone(),
-                    mixes_mutex.clone(),
-                    tunes_mutex.clone(),
-                );
+        let warmed = warm_next(
+            config,
+            files_mutex.clone(),
+            mixes_mutex.clone(),
+            tunes_mutex.clone(),
+            to_be_warmed_mutex.clone(),
+            have_been_warmed_mutex.clone(),
+        );
 
-                // todo: update search
-                //search::write_index(f)
-            } else {
-                println!("This file doesn't need to be warmed, it already has been...");
+        if let Some(errors) = warmed {
+            for error in errors {
+                println!("Warm error: {}", error);
             }
         } else {
             // The below code is bad code...
-            i = i + 1;
-            i23 = i23 + 1;
-            i2323 = i2323 + 1;
+            i += 1;
+            i23 += 1;
+            i2323 += 1;
             if i > 3 {
                 println!("Sleeping for 10 seconds, nothing to warm...");
                 i = 0;
@@ -223,7 +348,113 @@ async fn warm(
     }
 }
 
+// Warm everything that is currently queued, then return
+fn warm_until_empty(
+    config: &Config,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+) {
+    let mut warmed = 0;
+    let mut errors: Vec<FileError> = Vec::new();
+
+    while let Some(file_errors) = warm_next(
+        config,
+        files_mutex.clone(),
+        mixes_mutex.clone(),
+        tunes_mutex.clone(),
+        to_be_warmed_mutex.clone(),
+        have_been_warmed_mutex.clone(),
+    ) {
+        warmed += 1;
+        errors.extend(file_errors);
+    }
+
+    println!("Nothing left to warm.");
+    println!("Warm report: {} warmed, {} errors", warmed, errors.len());
+    for error in errors {
+        println!("  {}", error);
+    }
+}
+
+// Pops one hash off the queue and warms it, returns None if the queue was
+// empty, otherwise anything that went wrong along the way
+fn warm_next(
+    config: &Config,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+) -> Option<Vec<FileError>> {
+    let mut to_be_warmed = to_be_warmed_mutex.lock().unwrap();
+    let hash_to_be_warmed = to_be_warmed.pop();
+    drop(to_be_warmed);
+
+    let hash_to_be_warmed = hash_to_be_warmed?;
+
+    let mut errors: Vec<FileError> = Vec::new();
+
+    println!("Attempting to warm a file...");
+    println!("Locking files (warm)...");
+    let files = files_mutex.lock().unwrap();
+
+    println!("Checking if file has been warmed already...");
+    let file = files.get(&hash_to_be_warmed).cloned();
+
+    println!("Unlocking files (warm)...");
+    drop(files);
+
+    if let Some(mut f) = file {
+        println!("File does not exist in memory...");
+        f.indexed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // get info from tags if possible, lofty works out the format from
+        // the content so anything that made it past the extension filter is tried
+        println!("---------- TRIGGER A LOFTY POPULATE...");
+        match f.populate_lofty() {
+            Ok(()) => match art::cache_cover(config, &f) {
+                Ok(art) => f.art = art,
+                Err(err) => {
+                    println!("Could not cache the cover of `{}`: {}", f.path, err);
+                    errors.push(FileError::new(f.path.clone(), Stage::Art, err));
+                }
+            },
+            Err(err) => errors.push(FileError::new(f.path.clone(), Stage::Warm, err)),
+        }
+
+        match f.compute_content_hash() {
+            Ok(content_hash) => f.content_hash = content_hash,
+            Err(err) => {
+                println!("Could not hash `{}`: {}", f.path, err);
+                errors.push(FileError::new(f.path.clone(), Stage::Hash, err.into()));
+            }
+        }
+
+        f.save_to_database();
+
+        load_file_info_into_memory_and_mark_as_warmed(
+            config,
+            f,
+            files_mutex,
+            have_been_warmed_mutex,
+            mixes_mutex,
+            tunes_mutex,
+        );
+    } else {
+        println!("This file doesn't need to be warmed, it already has been...");
+    }
+
+    Some(errors)
+}
+
 fn load_old_data(
+    config: &Config,
     files_mutex: Arc<Mutex<HashMap<u32, File>>>,
     have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
     mixes_mutex: Arc<Mutex<Vec<u32>>>,
@@ -235,6 +466,7 @@ fn load_old_data(
     for f in get_all_db_files() {
         println!("+ Got a file from the database...");
         load_file_info_into_memory_and_mark_as_warmed(
+            config,
             f,
             files_mutex.clone(),
             have_been_warmed_mutex.clone(),
@@ -245,36 +477,44 @@ fn load_old_data(
 }
 
 fn load_file_info_into_memory_and_mark_as_warmed(
+    config: &Config,
     mut file: File,
     files_mutex: Arc<std::sync::Mutex<HashMap<u32, File>>>,
     have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
     mixes_mutex: Arc<Mutex<Vec<u32>>>,
     tunes_mutex: Arc<Mutex<Vec<u32>>>,
 ) {
+    // Add the file info to the in memory list, even if it can't be played the
+    // indexer needs it to know the file doesn't have to be warmed again
+    file.insert_into_memory(files_mutex);
+
+    // A re-warmed file may have become unplayable or moved between mixes and tunes
+    unmark_as_warmed(
+        file.id,
+        have_been_warmed_mutex.clone(),
+        mixes_mutex.clone(),
+        tunes_mutex.clone(),
+    );
+
     // Skip files that couldn't be parsed by id3
     if file.parse_fail {
         return;
     }
 
-    // Skip files longer than 12000 seconds
-    if file.duration > 12000 {
+    // Skip files longer than the configured maximum (12000 seconds by default)
+    if file.duration > config.max_duration {
         return;
     }
 
-    // Add the file info to the in memory list
-    file.insert_into_memory(files_mutex);
-
     // Add the file info to the have been indexed list
     println!("Locking have_been_warmed (load_file_info_into_memory_and_mark_as_warmed)...");
     let mut have_been_warmed = have_been_warmed_mutex.lock().unwrap();
     have_been_warmed.push(file.id);
-    have_been_warmed.dedup();
     println!("Unlocking have_been_warmed (load_file_info_into_memory_and_mark_as_warmed)...");
     drop(have_been_warmed);
 
     let f = file.clone();
-    let mix_threshold = 23 * 60;
-    if f.duration > mix_threshold {
+    if f.duration > config.mix_threshold {
         // add to in memory list of mixes
         println!("Locking mixes (load_file_info_into_memory_and_mark_as_warmed)...");
         let mut mixes = mixes_mutex.lock().unwrap();
@@ -293,6 +533,22 @@ fn load_file_info_into_memory_and_mark_as_warmed(
     }
 }
 
+fn unmark_as_warmed(
+    id: u32,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) {
+    println!("Locking have_been_warmed, mixes and tunes (unmark_as_warmed)...");
+    have_been_warmed_mutex
+        .lock()
+        .unwrap()
+        .retain(|&warmed| warmed != id);
+    mixes_mutex.lock().unwrap().retain(|&mix| mix != id);
+    tunes_mutex.lock().unwrap().retain(|&tune| tune != id);
+    println!("Unlocking have_been_warmed, mixes and tunes (unmark_as_warmed)...");
+}
+
 // todo: where does this belong?
 fn get_all_db_files() -> Vec<File> {
     let conn = SQLite::connect();
@@ -301,23 +557,7 @@ fn get_all_db_files() -> Vec<File> {
         .expect("SQL Statement prepare fail");
 
     let file_iter = stmt
-        .query_map(params![], |row| {
-            Ok(File {
-                id: row.get(0)?,
-                path: row.get(1)?,
-                file_name: row.get(2)?,
-                file_ext: row.get(3)?,
-                file_size: row.get(4)?,
-                file_modified: row.get(5)?,
-                title: row.get(6)?,
-                artist: row.get(7)?,
-                album: row.get(8)?,
-                duration: row.get(9)?,
-                indexed_at: row.get(10)?,
-                accessed_at: row.get(11)?,
-                parse_fail: row.get(12)?,
-            })
-        })
+        .query_map(params![], File::from_row)
         .expect("Error during get_all_db_files query/iteration.");
 
     let mut files: Vec<File> = Vec::new();
@@ -332,61 +572,121 @@ fn get_all_db_files() -> Vec<File> {
     files
 }
 
-#[tokio::main]
-async fn cleanup(plays_mutex: Arc<Mutex<HashMap<String, File>>>) {
+// Forgets listener sessions that have gone quiet
+fn cleanup(config: &Config, sessions_mutex: Arc<Mutex<shuffle::Sessions>>) {
     let interval = Duration::from_secs(600);
     let mut next_time = Instant::now() + interval;
 
     loop {
-        println!("Clearing plays (cleanup)...");
-        clear_plays(plays_mutex.clone());
-        sleep(next_time - Instant::now());
+        sleep(next_time.saturating_duration_since(Instant::now()));
+        println!("Locking sessions (cleanup)...");
+        let mut sessions = sessions_mutex.lock().unwrap();
+        sessions.expire(config.session_lifetime);
+        println!("Unlocking sessions (cleanup)...");
+        drop(sessions);
         println!("Sleeping for 600 seconds (cleanup)...");
         next_time += interval;
     }
 }
 
-fn clear_plays(plays_mutex: Arc<Mutex<HashMap<String, File>>>) {
-    // Acquire and drop mutex
-    println!("Locking plays (clear_plays)...");
-    let plays = plays_mutex.lock().unwrap();
-    let iter = plays.clone().into_iter();
-    println!("Unlocking plays (clear_plays)...");
-    drop(plays);
+// `serve` never walks the directory, so it picks up what an `index` run
+// elsewhere (e.g from cron) has written to the database every
+// `rescan_interval` seconds instead
+fn reload(
+    config: &Config,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) {
+    if config.rescan_interval == 0 {
+        return;
+    }
+
+    let interval = Duration::from_secs(config.rescan_interval);
+    let mut next_time = Instant::now() + interval;
+
+    loop {
+        println!(
+            "Sleeping for {} seconds (reload)...",
+            config.rescan_interval
+        );
+        sleep(next_time.saturating_duration_since(Instant::now()));
+        next_time += interval;
 
-    for (hash, file) in iter {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let accessed_at = file.accessed_at;
-        let duration = file.duration;
+        replace_library(
+            config,
+            get_all_db_files(),
+            &files_mutex,
+            &have_been_warmed_mutex,
+            &mixes_mutex,
+            &tunes_mutex,
+        );
+    }
+}
+
+// Swaps everything in memory for `files`, sorted into the same lists
+// `load_old_data` would put them in. The lists are built before anything is
+// locked so requests are only held up for the swap.
+fn replace_library(
+    config: &Config,
+    files: Vec<File>,
+    files_mutex: &Arc<Mutex<HashMap<u32, File>>>,
+    have_been_warmed_mutex: &Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: &Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: &Arc<Mutex<Vec<u32>>>,
+) {
+    let mut have_been_warmed = Vec::new();
+    let mut mixes = Vec::new();
+    let mut tunes = Vec::new();
 
-        // if enough time has passed for the song to have played twice...
-        if now - accessed_at > duration * 2 {
-            println!("Locking plays (clear_plays)...");
-            let mut plays = plays_mutex.lock().unwrap();
-            // the url won't work anymore
-            println!("Removing a play...");
-            println!("Now: {:?}", now);
-            println!("Accesed at: {:?}", accessed_at);
-            println!("Duration: {:?}", duration);
-            println!("Duration*2: {:?}", duration * 2);
+    for file in files.iter() {
+        if file.parse_fail || file.duration > config.max_duration {
+            continue;
+        }
 
-            plays.remove(&hash);
-            println!("Unlocking plays (clear_plays)...");
-            drop(plays);
+        have_been_warmed.push(file.id);
+        if file.duration > config.mix_threshold {
+            mixes.push(file.id);
+        } else {
+            tunes.push(file.id);
         }
     }
+
+    let files: HashMap<u32, File> = files.into_iter().map(|file| (file.id, file)).collect();
+    println!(
+        "Reloaded {} files, {} can be played.",
+        files.len(),
+        have_been_warmed.len()
+    );
+
+    println!("Locking files, have_been_warmed, mixes and tunes (replace_library)...");
+    let mut files_lock = files_mutex.lock().unwrap();
+    let mut have_been_warmed_lock = have_been_warmed_mutex.lock().unwrap();
+    let mut mixes_lock = mixes_mutex.lock().unwrap();
+    let mut tunes_lock = tunes_mutex.lock().unwrap();
+    *files_lock = files;
+    *have_been_warmed_lock = have_been_warmed;
+    *mixes_lock = mixes;
+    *tunes_lock = tunes;
+    drop(tunes_lock);
+    drop(mixes_lock);
+    drop(have_been_warmed_lock);
+    println!("Unlocking files, have_been_warmed, mixes and tunes (replace_library)...");
+    drop(files_lock);
 }
 
 #[tokio::main]
 async fn index(
+    config: &Config,
+    keep_watching: bool,
     files_mutex: Arc<std::sync::Mutex<HashMap<u32, File>>>,
     to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
 ) {
-    // todo: make this a command line arg
-    let directory_to_index = "./files";
+    let directory_to_index = &config.directory;
 
     if !Path::new(&directory_to_index).exists() {
         println!(
@@ -397,29 +697,160 @@ async fn index(
         return;
     }
 
-    let directory_exclusions_file_path = "./exclusions.txt";
+    let directory_exclusions_file_path = &config.exclusions;
 
-    if directory_exclusions_file_path.len() > 0
-        && !Path::new(&directory_exclusions_file_path).exists()
-    {
+    // An empty exclusions setting means nothing is excluded
+    let directory_exclusions = if directory_exclusions_file_path.is_empty() {
+        Vec::new()
+    } else if !Path::new(&directory_exclusions_file_path).exists() {
         println!(
             "Exclusions file is missing: `{:?}`",
             &directory_exclusions_file_path
         );
 
         return;
+    } else {
+        lines_from_file(directory_exclusions_file_path)
+    };
+
+    walk_and_sweep(
+        config,
+        &directory_exclusions,
+        files_mutex.clone(),
+        to_be_warmed_mutex.clone(),
+        have_been_warmed_mutex.clone(),
+        mixes_mutex.clone(),
+        tunes_mutex.clone(),
+    );
+
+    if !keep_watching {
+        return;
+    }
+
+    // The watcher runs its own rescans
+    if config.watch {
+        watch::watch(
+            config,
+            &directory_exclusions,
+            files_mutex,
+            to_be_warmed_mutex,
+            have_been_warmed_mutex,
+            mixes_mutex,
+            tunes_mutex,
+        );
+        return;
+    }
+
+    if config.rescan_interval == 0 {
+        return;
+    }
+
+    let interval = Duration::from_secs(config.rescan_interval);
+    let mut next_time = Instant::now() + interval;
+
+    loop {
+        println!("Sleeping for {} seconds (index)...", config.rescan_interval);
+        sleep(next_time.saturating_duration_since(Instant::now()));
+        next_time += interval;
+
+        println!("Rescanning `{}`...", config.directory);
+        walk_and_sweep(
+            config,
+            &directory_exclusions,
+            files_mutex.clone(),
+            to_be_warmed_mutex.clone(),
+            have_been_warmed_mutex.clone(),
+            mixes_mutex.clone(),
+            tunes_mutex.clone(),
+        );
     }
+}
+
+// One full walk of the music directory followed by a sweep of what's gone
+fn walk_and_sweep(
+    config: &Config,
+    directory_exclusions: &[String],
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) -> IndexReport {
+    let started_at = Instant::now();
+
+    let mut walk = get_files(
+        config.directory.to_string(),
+        directory_exclusions.to_vec(),
+        &config.extensions,
+        files_mutex.clone(),
+        to_be_warmed_mutex.clone(),
+    );
+    println!("Finished getting files.");
+
+    let mut report = IndexReport {
+        new: walk.new.len(),
+        changed: walk.changed,
+        moved: 0,
+        removed: 0,
+        unchanged: walk.unchanged,
+        errors: Vec::new(),
+        elapsed: Duration::ZERO,
+    };
 
-    let directory_exclusions = lines_from_file(directory_exclusions_file_path);
+    let errors = std::mem::take(&mut walk.errors);
 
-    match get_files(
-        directory_to_index.to_string(),
-        directory_exclusions,
+    let (moved, removed) = sweep(
+        config,
+        walk,
         files_mutex,
         to_be_warmed_mutex,
-    ) {
-        Ok(_) => println!("Finished getting files."),
-        Err(err) => println!("{}", err),
+        have_been_warmed_mutex,
+        mixes_mutex,
+        tunes_mutex,
+    );
+
+    // a move shows up in the walk as a new file
+    report.new -= moved;
+    report.moved = moved;
+    report.removed = removed;
+    report.errors = errors;
+    report.elapsed = started_at.elapsed();
+
+    println!("{}", report);
+
+    report
+}
+
+// Statistics for one walk of the music directory
+struct IndexReport {
+    new: usize,
+    changed: usize,
+    moved: usize,
+    removed: usize,
+    unchanged: usize,
+    errors: Vec<FileError>,
+    elapsed: Duration,
+}
+
+impl fmt::Display for IndexReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Index report: {} new, {} changed, {} moved, {} removed, {} unchanged, {} errors in {:.1}s",
+            self.new,
+            self.changed,
+            self.moved,
+            self.removed,
+            self.unchanged,
+            self.errors.len(),
+            self.elapsed.as_secs_f64()
+        )?;
+
+        for error in &self.errors {
+            write!(f, "\n  {}", error)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -434,281 +865,1723 @@ fn lines_from_file(filename: impl AsRef<Path>) -> Vec<String> {
 fn get_files(
     directory: std::string::String,
     exclusions: Vec<std::string::String>,
+    extensions_to_index: &[String],
     files_mutex: Arc<std::sync::Mutex<HashMap<u32, File>>>,
     to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
-) -> Result<(), walkdir::Error> {
+) -> WalkResult {
     println!("Walking files...");
 
-    'entries: for entry in WalkDir::new(directory) {
+    let mut walk = WalkResult {
+        seen: HashSet::new(),
+        new: Vec::new(),
+        changed: 0,
+        unchanged: 0,
+        errors: Vec::new(),
+        failed: Vec::new(),
+    };
+
+    for entry in WalkDir::new(&directory) {
         let entry = match entry {
             Ok(file) => file,
-            Err(error) => panic!("Problem with file: {:?}", error),
+            Err(error) => {
+                println!("Problem with file: {:?}", error);
+                // without a path nothing under the directory can be trusted
+                let failed = match error.path() {
+                    Some(path) => path.to_path_buf(),
+                    None => PathBuf::from(&directory),
+                };
+                walk.errors.push(FileError::new(
+                    failed.to_string_lossy(),
+                    Stage::Walk,
+                    error.into(),
+                ));
+                walk.failed.push(failed);
+                continue;
+            }
         };
 
         let path = entry.path();
 
         println!("+ PATH: `{:?}`", &path);
 
-        for exclusion in &exclusions {
-            if path.starts_with(exclusion) {
-                println!("Excluding: `{:?}`", &path);
-                println!("Based on rule: `{:?}`", &exclusion);
-                continue 'entries;
+        if is_excluded(path, &exclusions) {
+            continue;
+        }
+
+        match index_path(
+            path,
+            extensions_to_index,
+            files_mutex.clone(),
+            to_be_warmed_mutex.clone(),
+        ) {
+            Err(error) => {
+                println!("Problem with file: {:?}", error);
+                walk.errors
+                    .push(FileError::new(path.to_string_lossy(), Stage::Index, error));
+                walk.failed.push(path.to_path_buf());
             }
+            Ok(IndexedPath::New(file_hash)) => {
+                walk.seen.insert(file_hash);
+                walk.new.push(file_hash);
+            }
+            Ok(IndexedPath::Changed(file_hash)) => {
+                walk.seen.insert(file_hash);
+                walk.changed += 1;
+            }
+            Ok(IndexedPath::Unchanged(file_hash)) => {
+                walk.seen.insert(file_hash);
+                walk.unchanged += 1;
+            }
+            Ok(IndexedPath::Ignored) => (),
         }
+        println!("END (get_files)...");
+    }
 
-        if !path.is_dir() {
-            // todo: make this a command line arg
-            let binding = "flac,mp3";
+    walk
+}
 
-            let extensions_to_index: Vec<&str> = binding.split(",").collect();
+fn is_excluded(path: &Path, exclusions: &[String]) -> bool {
+    for exclusion in exclusions {
+        if path.starts_with(exclusion) {
+            println!("Excluding: `{:?}`", &path);
+            println!("Based on rule: `{:?}`", &exclusion);
+            return true;
+        }
+    }
 
-            let f = File::new_empty_file_from_path(&path);
+    false
+}
+
+// What indexing a single path did
+enum IndexedPath {
+    // a directory or a file with an extension that isn't indexed
+    Ignored,
+    // wasn't in memory, queued to be warmed
+    New(u32),
+    // size or modified time differs from memory, queued to be warmed
+    Changed(u32),
+    Unchanged(u32),
+}
+
+// Compares a path on disk with what's in memory and queues it to be warmed if needed
+fn index_path(
+    path: &Path,
+    extensions_to_index: &[String],
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+) -> Result<IndexedPath, IndexError> {
+    if path.is_dir() {
+        return Ok(IndexedPath::Ignored);
+    }
+
+    let mut f = File::new_empty_file_from_path(path)?;
+
+    if !config::matches_extension(extensions_to_index, &f.file_ext) {
+        return Ok(IndexedPath::Ignored);
+    }
+
+    let file_hash = murmurhash3(f.path.as_bytes());
+
+    let mut warm_the_file = false;
+
+    f.populate_from_path()?;
+
+    println!("Locking files (get_files2)...");
+    let files = files_mutex.lock().unwrap();
+    println!("Trying to get file from memory...");
+    let current_file_in_memory_result = files.get(&file_hash).cloned();
+    println!("Unlocking files (get_files2)...");
+    drop(files);
+
+    let indexed;
+
+    // If the file is in the list of files in memory
+    if let Some(current_file_in_memory) = current_file_in_memory_result {
+        println!("File is in memory already...");
+
+        // if the File size has changed, update the memory record and mark it for warming
+        if f.clone().file_size != current_file_in_memory.file_size {
+            println!("File size has changed, it will be warmed...");
+            f.insert_into_memory(Arc::clone(&files_mutex));
+            warm_the_file = true;
+        }
+
+        // If the File modified has changed, index it
+        if f.clone().file_modified != current_file_in_memory.file_modified {
+            println!("File modified time has changed, it will be warmed...");
+            f.insert_into_memory(Arc::clone(&files_mutex));
+            warm_the_file = true;
+        }
+
+        // Files warmed before content hashes existed get one now, so
+        // a later move can still be recognised
+        if !warm_the_file && current_file_in_memory.content_hash == 0 {
+            backfill_content_hash(current_file_in_memory, Arc::clone(&files_mutex));
+        }
+
+        indexed = if warm_the_file {
+            IndexedPath::Changed(file_hash)
+        } else {
+            IndexedPath::Unchanged(file_hash)
+        };
+    } else {
+        println!("File is not in memory...");
+        f.insert_into_memory(Arc::clone(&files_mutex));
+        warm_the_file = true;
+        indexed = IndexedPath::New(file_hash);
+    }
+
+    if warm_the_file {
+        println!("Queueing the file to be warmed...");
+        println!("Locking to_be_warmed (get_files)...");
+        let mut to_be_warmed = to_be_warmed_mutex.lock().unwrap();
+        if !to_be_warmed.contains(&file_hash) {
+            println!("Queueing file to be indexed...");
+            to_be_warmed.push(file_hash);
+            to_be_warmed.dedup();
+        } else {
+            println!("File is already queued to be indexed...");
+        }
+        println!("Unlocking to_be_warmed (get_files)...");
+        drop(to_be_warmed);
+    } else {
+        println!("Did not queue the file to be indexed...");
+    }
+
+    Ok(indexed)
+}
+
+fn backfill_content_hash(mut file: File, files_mutex: Arc<Mutex<HashMap<u32, File>>>) {
+    match file.compute_content_hash() {
+        Ok(content_hash) => {
+            println!("Backfilling content hash...");
+            file.content_hash = content_hash;
+            file.save_to_database();
+            file.insert_into_memory(files_mutex);
+        }
+        Err(err) => println!("Could not hash `{}`: {}", file.path, err),
+    }
+}
+
+// What a walk of the music directory found
+struct WalkResult {
+    // murmurs of every indexable file that is on disk
+    seen: HashSet<u32>,
+    // murmurs of files that were not in memory before the walk
+    new: Vec<u32>,
+    changed: usize,
+    unchanged: usize,
+    errors: Vec<FileError>,
+    // entries that couldn't be read or indexed, whatever is under them may
+    // still be on disk
+    failed: Vec<PathBuf>,
+}
+
+// Removes files that are in memory but weren't seen by the walk, returns how
+// many were moved and removed
+fn sweep(
+    config: &Config,
+    walk: WalkResult,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) -> (usize, usize) {
+    println!("Locking files (sweep)...");
+    let files = files_mutex.lock().unwrap();
+    let stale = stale_files(&files, &walk);
+    println!("Unlocking files (sweep)...");
+    drop(files);
+
+    // An unmounted share looks exactly like every file being deleted
+    if walk.seen.is_empty() && !stale.is_empty() {
+        println!(
+            "Walk found no files, not removing the {} in memory.",
+            stale.len()
+        );
+        return (0, 0);
+    }
+
+    reconcile(
+        config,
+        stale,
+        walk.new,
+        files_mutex,
+        to_be_warmed_mutex,
+        have_been_warmed_mutex,
+        mixes_mutex,
+        tunes_mutex,
+    )
+}
+
+// Files in memory the walk didn't see. Anything under a path the walk failed
+// on is kept, a permission error or a file being copied isn't a deletion.
+fn stale_files(files: &HashMap<u32, File>, walk: &WalkResult) -> Vec<File> {
+    let mut kept = 0;
+
+    let stale: Vec<File> = files
+        .values()
+        .filter(|file| !walk.seen.contains(&file.id))
+        .filter(|file| {
+            let path = Path::new(&file.path);
+            let failed = walk.failed.iter().any(|failed| path.starts_with(failed));
+            if failed {
+                kept += 1;
+            }
+            !failed
+        })
+        .cloned()
+        .collect();
+
+    if kept > 0 {
+        println!("Keeping {} files under paths the walk couldn't read.", kept);
+    }
+
+    stale
+}
+
+// Forgets files that are no longer on disk. A new file with the same size,
+// modified time and content as a removed one is treated as a move, it takes
+// over the old record instead of being warmed from scratch. Returns how many
+// files were moved and removed.
+#[allow(clippy::too_many_arguments)]
+fn reconcile(
+    config: &Config,
+    mut stale: Vec<File>,
+    new: Vec<u32>,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) -> (usize, usize) {
+    println!("Locking files (reconcile)...");
+    let files = files_mutex.lock().unwrap();
+    let new: Vec<File> = new.iter().filter_map(|id| files.get(id).cloned()).collect();
+    println!("Unlocking files (reconcile)...");
+    drop(files);
+
+    let mut moved_count = 0;
+
+    for new_file in new {
+        let position = match stale.iter().position(|old| is_same_file(old, &new_file)) {
+            Some(position) => position,
+            None => continue,
+        };
+
+        let old = stale.remove(position);
+        println!("Moved: `{}` -> `{}`", old.path, new_file.path);
+        moved_count += 1;
+
+        to_be_warmed_mutex
+            .lock()
+            .unwrap()
+            .retain(|&queued| queued != new_file.id);
+
+        waveform::move_to(old.id, new_file.id);
+
+        forget_file(
+            old.id,
+            files_mutex.clone(),
+            to_be_warmed_mutex.clone(),
+            have_been_warmed_mutex.clone(),
+            mixes_mutex.clone(),
+            tunes_mutex.clone(),
+        );
+
+        let mut moved = old.clone();
+        moved.id = new_file.id;
+        moved.path = new_file.path;
+        moved.file_name = new_file.file_name;
+        moved.file_ext = new_file.file_ext;
+        moved.save_to_database();
+
+        load_file_info_into_memory_and_mark_as_warmed(
+            config,
+            moved,
+            files_mutex.clone(),
+            have_been_warmed_mutex.clone(),
+            mixes_mutex.clone(),
+            tunes_mutex.clone(),
+        );
+    }
+
+    let removed_count = stale.len();
+
+    for old in stale {
+        println!("Removed: `{}`", old.path);
+        forget_file(
+            old.id,
+            files_mutex.clone(),
+            to_be_warmed_mutex.clone(),
+            have_been_warmed_mutex.clone(),
+            mixes_mutex.clone(),
+            tunes_mutex.clone(),
+        );
+    }
+
+    (moved_count, removed_count)
+}
+
+fn is_same_file(old: &File, new: &File) -> bool {
+    if old.file_size != new.file_size || old.file_modified != new.file_modified {
+        return false;
+    }
+
+    // Rows warmed before content hashes existed can only be matched on name
+    if old.content_hash == 0 {
+        return old.file_name == new.file_name;
+    }
+
+    match new.compute_content_hash() {
+        Ok(content_hash) => content_hash == old.content_hash,
+        Err(_) => false,
+    }
+}
+
+// Removes a file from memory, every queue and the database
+fn forget_file(
+    id: u32,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) {
+    println!("Locking files and to_be_warmed (forget_file)...");
+    files_mutex.lock().unwrap().remove(&id);
+    to_be_warmed_mutex
+        .lock()
+        .unwrap()
+        .retain(|&queued| queued != id);
+    println!("Unlocking files and to_be_warmed (forget_file)...");
+
+    unmark_as_warmed(id, have_been_warmed_mutex, mixes_mutex, tunes_mutex);
+
+    File::delete_from_database(id);
+}
+
+// The file a play token was issued for, if it's genuine and still valid
+fn get_file_from_token(
+    token: &str,
+    signer: &token::Signer,
+    files_mutex: &Arc<Mutex<HashMap<u32, File>>>,
+) -> Option<music::File> {
+    let id = signer.verify(token)?;
+
+    println!("Locking files (get_file_from_token)...");
+    let files = files_mutex.lock().unwrap();
+    let result = files.get(&id).cloned();
+    println!("Unlocking files (get_file_from_token)...");
+    drop(files);
+
+    result
+}
+
+// Narrows down /random, every filter that's given has to match
+#[derive(Deserialize, Debug, Clone, Default)]
+struct RandomQuery {
+    // seconds
+    pub min_duration: Option<u64>,
+    pub max_duration: Option<u64>,
+    // comma separated, e.g mp3,flac
+    pub ext: Option<String>,
+    // these three match part of the tag, ignoring case
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub min_year: Option<u32>,
+    pub max_year: Option<u32>,
+    // relative to the indexed directory, e.g radio-shows/2019
+    pub folder: Option<String>,
+    // the same syntax as /search
+    pub q: Option<String>,
+    // whose shuffle bag to pick from, the session cookie is used without it
+    pub session: Option<String>,
+}
+
+impl RandomQuery {
+    fn is_empty(&self) -> bool {
+        self.min_duration.is_none()
+            && self.max_duration.is_none()
+            && self.ext.is_none()
+            && self.artist.is_none()
+            && self.album.is_none()
+            && self.genre.is_none()
+            && self.min_year.is_none()
+            && self.max_year.is_none()
+            && self.folder.is_none()
+            && self.q.is_none()
+    }
+
+    // Each mode and set of filters has its own bag, so narrowing things down
+    // doesn't count towards what's left of the whole mode
+    fn bag(&self, mode: &str) -> String {
+        if self.is_empty() {
+            return mode.to_string();
+        }
+
+        let filters = RandomQuery {
+            session: None,
+            ..self.clone()
+        };
+
+        format!("{}?{:?}", mode, filters)
+    }
+
+    // everything but `q`, which needs the search index
+    fn matches(&self, file: &File, directory: &str) -> bool {
+        if self.min_duration.is_some_and(|min| file.duration < min)
+            || self.max_duration.is_some_and(|max| file.duration > max)
+        {
+            return false;
+        }
+
+        // files without a year are left out when a year is asked for
+        if (self.min_year.is_some() || self.max_year.is_some()) && file.year == 0 {
+            return false;
+        }
+        if self.min_year.is_some_and(|min| file.year < min)
+            || self.max_year.is_some_and(|max| file.year > max)
+        {
+            return false;
+        }
+
+        if let Some(ext) = &self.ext {
+            let wanted = ext
+                .split(',')
+                .map(|ext| ext.trim().trim_start_matches('.'))
+                .any(|ext| ext.eq_ignore_ascii_case(&file.file_ext));
+            if !wanted {
+                return false;
+            }
+        }
+
+        let contains = |tag: &str, wanted: &Option<String>| match wanted {
+            Some(wanted) => tag.to_lowercase().contains(&wanted.trim().to_lowercase()),
+            None => true,
+        };
+        if !(contains(&file.artist, &self.artist) || contains(&file.album_artist, &self.artist))
+            || !contains(&file.album, &self.album)
+            || !contains(&file.genre, &self.genre)
+        {
+            return false;
+        }
+
+        if let Some(folder) = &self.folder {
+            let folder = folder.trim().trim_matches('/');
+            let path = Path::new(&file.path);
+            let relative = path.strip_prefix(directory).unwrap_or(path);
+            // whole folder names only, `radio` doesn't match `radio-shows`
+            if !relative.starts_with(folder) && !path.starts_with(folder) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+// A file from everything in the selection that matches the filters, which
+// the session hasn't heard since it last went through all of them
+#[allow(clippy::too_many_arguments)]
+fn generate_filtered_random_response(
+    query: RandomQuery,
+    directory: &str,
+    files_mutex: &Arc<Mutex<HashMap<u32, File>>>,
+    selection_mutex: Arc<Mutex<Vec<u32>>>,
+    sessions_mutex: &Arc<Mutex<shuffle::Sessions>>,
+    session: Option<&str>,
+    bag: &str,
+    signer: &token::Signer,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let searched = match query.q.as_deref().map(search::to_fts_query) {
+        None => None,
+        Some(Some(fts_query)) => match search::matching_ids(&fts_query) {
+            Ok(ids) => Some(ids),
+            Err(err) => {
+                println!("Search failed: {}", err);
+                let response = EmptyResponse {
+                    status: 400,
+                    message: "Search query could not be run".to_string(),
+                };
+
+                return warp::reply::with_status(
+                    warp::reply::json(&response),
+                    StatusCode::BAD_REQUEST,
+                );
+            }
+        },
+        Some(None) => {
+            let response = EmptyResponse {
+                status: 400,
+                message: "Search query is empty".to_string(),
+            };
+
+            return warp::reply::with_status(warp::reply::json(&response), StatusCode::BAD_REQUEST);
+        }
+    };
+
+    println!("Locking files (generate_filtered_random_response)...");
+    let files = files_mutex.lock().unwrap();
+    let selection = selection_mutex.lock().unwrap();
+    let matching: Vec<u32> = selection
+        .iter()
+        .filter(|id| searched.as_ref().is_none_or(|ids| ids.contains(id)))
+        .filter(|id| {
+            files
+                .get(id)
+                .is_some_and(|file| query.matches(file, directory))
+        })
+        .copied()
+        .collect();
+    drop(selection);
+
+    println!("Locking sessions (generate_filtered_random_response)...");
+    let mut sessions = sessions_mutex.lock().unwrap();
+    let picked = shuffle::pick(&mut sessions, session, bag, &matching);
+    println!("Unlocking sessions (generate_filtered_random_response)...");
+    drop(sessions);
+
+    let picked = picked.and_then(|id| files.get(&id).cloned());
+    println!("Unlocking files (generate_filtered_random_response)...");
+    drop(files);
+
+    let file = match picked {
+        Some(file) => file,
+        None => {
+            let response = EmptyResponse {
+                status: 404,
+                message: "No files match".to_string(),
+            };
+
+            return warp::reply::with_status(warp::reply::json(&response), StatusCode::NOT_FOUND);
+        }
+    };
+
+    let files_hashed = issue_tokens(vec![file], signer);
+
+    let response = FileResponse {
+        status: 200,
+        message: "OK".to_string(),
+        count: files_hashed.len(),
+        data: files_hashed,
+    };
+
+    warp::reply::with_status(warp::reply::json(&response), StatusCode::OK)
+}
+
+fn generate_random_response(
+    files_mutex: &Arc<std::sync::Mutex<std::collections::HashMap<u32, music::File>>>,
+    signer: &token::Signer,
+    random_hash: u32,
+) -> warp::reply::Json {
+    if random_hash == 0 {
+        let response = EmptyResponse {
+            status: 404,
+            message: "No files have been indexed (yet...)".to_string(),
+        };
+
+        return warp::reply::json(&response);
+    }
+
+    let files_mutex = Arc::clone(files_mutex);
+    let files = files_mutex.lock().unwrap();
+
+    let mut random_files: Vec<File> = Vec::new();
+
+    match files.get(&random_hash) {
+        Some(file) => random_files.push(file.clone()),
+        _ => println!("Hash is missing from db"),
+    };
+
+    drop(files);
+
+    let random_files_hashed = issue_tokens(random_files, signer);
+
+    let response = FileResponse {
+        status: 200,
+        message: "OK".to_string(),
+        count: random_files_hashed.len(),
+        data: random_files_hashed,
+    };
+
+    warp::reply::json(&response)
+}
+
+// Gives each file a play token that the stream route will accept, and one
+// for its album that /download/album will
+fn issue_tokens(files: Vec<File>, signer: &token::Signer) -> Vec<FileHashed> {
+    files
+        .into_iter()
+        .map(|file| file.to_response(signer.issue(&file), signer.issue_album(&file.album_id())))
+        .collect()
+}
 
-            if extensions_to_index.contains(&&f.file_ext.as_str()) {
-                let file_hash = murmurhash3(f.path.as_bytes());
+const SEARCH_DEFAULT_LIMIT: usize = 20;
+const SEARCH_MAX_LIMIT: usize = 100;
 
-                let mut warm_the_file = false;
+#[derive(Deserialize, Debug)]
+struct SearchQuery {
+    pub q: String,
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
 
-                let mut f = File::new_empty_file_from_path(path);
-                f.populate_from_path();
+fn generate_search_response(
+    query: SearchQuery,
+    max_duration: u64,
+    signer: &token::Signer,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let fts_query = match search::to_fts_query(&query.q) {
+        Some(fts_query) => fts_query,
+        None => {
+            let response = EmptyResponse {
+                status: 400,
+                message: "Search query is empty".to_string(),
+            };
 
-                println!("Locking files (get_files2)...");
-                let files_mutex = files_mutex.clone();
-                let files = files_mutex.lock().unwrap();
-                println!("Trying to get file from memory...");
-                let current_file_in_memory_result = match files.get(&file_hash) {
-                    Some(file) => Some(file.clone()),
-                    _ => None,
+            return warp::reply::with_status(warp::reply::json(&response), StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let page = query.page.unwrap_or(1).max(1);
+    let limit = query
+        .limit
+        .unwrap_or(SEARCH_DEFAULT_LIMIT)
+        .clamp(1, SEARCH_MAX_LIMIT);
+
+    println!("Searching for `{}`...", fts_query);
+    let (files, total) =
+        match search::search_files(&fts_query, max_duration, limit, (page - 1) * limit) {
+            Ok(result) => result,
+            Err(err) => {
+                println!("Search failed: {}", err);
+                let response = EmptyResponse {
+                    status: 400,
+                    message: "Search query could not be run".to_string(),
                 };
-                println!("Unlocking files (get_files2)...");
-                drop(files);
-
-                // If the file is in the list of files in memory
-                if !current_file_in_memory_result.is_none() {
-                    println!("File is in memory already...");
-                    let current_file_in_memory = current_file_in_memory_result.unwrap();
-
-                    // if the File size has changed, update the memory record and mark it for warming
-                    if f.clone().file_size != current_file_in_memory.file_size {
-                        println!("File size has changed, it will be warmed...");
-                        f.insert_into_memory(Arc::clone(&files_mutex));
-                        warm_the_file = true;
-                    }
-
-                    // If the File modified has changed, index it
-                    if f.clone().file_modified != current_file_in_memory.file_modified {
-                        println!("File modified time has changed, it will be warmed...");
-                        f.insert_into_memory(Arc::clone(&files_mutex));
-                        warm_the_file = true;
-                    }
+
+                return warp::reply::with_status(
+                    warp::reply::json(&response),
+                    StatusCode::BAD_REQUEST,
+                );
+            }
+        };
+
+    let files_hashed = issue_tokens(files, signer);
+
+    let response = SearchResponse {
+        status: 200,
+        message: "OK".to_string(),
+        count: files_hashed.len(),
+        total,
+        page,
+        limit,
+        data: files_hashed,
+    };
+
+    warp::reply::with_status(warp::reply::json(&response), StatusCode::OK)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EmptyResponse {
+    pub status: i32,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FileResponse {
+    pub status: i32,
+    pub message: String,
+    pub count: usize,
+    pub data: Vec<FileHashed>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SearchResponse {
+    pub status: i32,
+    pub message: String,
+    pub count: usize,
+    pub total: usize,
+    pub page: usize,
+    pub limit: usize,
+    pub data: Vec<FileHashed>,
+}
+
+#[tokio::main]
+async fn serve(
+    config: &Config,
+    files_mutex: Arc<std::sync::Mutex<std::collections::HashMap<u32, music::File>>>,
+    signer: token::Signer,
+    sessions_mutex: Arc<Mutex<shuffle::Sessions>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) {
+    println!("SERVING");
+    let files_mutex_1 = Arc::clone(&files_mutex);
+    let have_been_warmed_mutex = Arc::clone(&have_been_warmed_mutex);
+    let mixes_mutex_1 = Arc::clone(&mixes_mutex);
+    let tunes_mutex_1 = Arc::clone(&tunes_mutex);
+
+    let signer_1 = signer.clone();
+    let signer_2 = signer.clone();
+    let signer_3 = signer.clone();
+    let signer_4 = signer.clone();
+    let signer_5 = signer.clone();
+    let signer_6 = signer.clone();
+    let signer_7 = signer.clone();
+    let signer_8 = signer.clone();
+    let signer_9 = signer;
+
+    let files_mutex_3 = Arc::clone(&files_mutex);
+    let files_mutex_4 = Arc::clone(&files_mutex);
+    let files_mutex_8 = Arc::clone(&files_mutex);
+    let all_mutex_3 = Arc::clone(&have_been_warmed_mutex);
+    let files_mutex_5 = Arc::clone(&files_mutex);
+    let files_mutex_6 = Arc::clone(&files_mutex);
+    let files_mutex_7 = Arc::clone(&files_mutex);
+
+    let files_mutex_2 = Arc::clone(&files_mutex);
+    let all_mutex_2 = Arc::clone(&have_been_warmed_mutex);
+    let mixes_mutex_2 = Arc::clone(&mixes_mutex);
+    let tunes_mutex_2 = Arc::clone(&tunes_mutex);
+    let public_url = config.public_url.clone();
+    let random_directory = config.directory.clone();
+    let session_lifetime = config.session_lifetime;
+    let search_max_duration = config.max_duration;
+
+    let subsonic = Arc::new(subsonic::Subsonic::new(
+        config.clone(),
+        Arc::clone(&files_mutex),
+        Arc::clone(&have_been_warmed_mutex),
+    ));
+    let subsonic_config = config.clone();
+
+    let art_directory = config.art_directory.clone();
+    let art_sizes = config.art_sizes.clone();
+    let transcode_config = config.clone();
+    let jobs = Arc::new(transcode::Jobs::new(config.transcode_max_jobs));
+    let transcode_jobs = Arc::clone(&jobs);
+
+    let radio = Arc::new(radio::Radio::new(
+        config.clone(),
+        Arc::clone(&files_mutex),
+        Arc::clone(&have_been_warmed_mutex),
+        Arc::clone(&mixes_mutex),
+        Arc::clone(&tunes_mutex),
+        Arc::clone(&jobs),
+    ));
+
+    // default e.g https://domain.tld
+    let default = warp::path::end().and(warp::fs::file("static/index.html"));
+
+    // domain.tld/js/*
+    let js = warp::path("js")
+        .and(warp::fs::dir("static/js"))
+        .map(|res: warp::fs::File| {
+            // cache for 23 days
+            warp::reply::with_header(
+                res,
+                "cache-control",
+                "Cache-Control: public, max-age 1987200, s-maxage 1987200, immutable",
+            )
+        });
+
+    // domain.tld/random/[all|tunes|mixes]?genre=[genre]&min_year=[year]&session=[id]&...
+    let random = warp::path!("random" / String)
+        .and(warp::query::<RandomQuery>())
+        .and(warp::cookie::optional::<String>(shuffle::COOKIE))
+        .map(
+            move |mode: String, query: RandomQuery, cookie: Option<String>| {
+                println!("START (route:random)...");
+                // a session asked for by name wins over the cookie
+                let (session, new_session) = match query
+                    .session
+                    .clone()
+                    .filter(|id| shuffle::valid_id(id))
+                    .or_else(|| cookie.filter(|id| shuffle::valid_id(id)))
+                {
+                    Some(session) => (session, false),
+                    None => (shuffle::new_id(), true),
+                };
+                let all = Arc::clone(&have_been_warmed_mutex);
+                let mixes = Arc::clone(&mixes_mutex_1);
+                let tunes = Arc::clone(&tunes_mutex_1);
+                let selection = mode_selection(all, mixes, tunes, &mode);
+                let fm = Arc::clone(&files_mutex_1);
+                let bag = query.bag(&mode);
+                // a new session is only kept once its cookie comes back
+                let remembered = if new_session {
+                    None
+                } else {
+                    Some(session.as_str())
+                };
+                let mut response = if query.is_empty() {
+                    let random_hash = shuffled_hash(selection, &sessions_mutex, remembered, &bag);
+                    generate_random_response(&fm, &signer_1, random_hash).into_response()
                 } else {
-                    println!("File is not in memory...");
-                    f.insert_into_memory(Arc::clone(&files_mutex));
-                    warm_the_file = true;
+                    generate_filtered_random_response(
+                        query,
+                        &random_directory,
+                        &fm,
+                        selection,
+                        &sessions_mutex,
+                        remembered,
+                        &bag,
+                        &signer_1,
+                    )
+                    .into_response()
+                };
+                if new_session {
+                    let cookie = format!(
+                        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                        shuffle::COOKIE,
+                        session,
+                        session_lifetime
+                    );
+                    response
+                        .headers_mut()
+                        .insert("Set-Cookie", HeaderValue::from_str(&cookie).unwrap());
                 }
+                println!("END (route:random)...");
+                response
+            },
+        );
+
+    // domain.tld/search?q=[query]&page=[page]&limit=[limit]
+    let search =
+        warp::path!("search")
+            .and(warp::query::<SearchQuery>())
+            .map(move |query: SearchQuery| {
+                println!("START (route:search)...");
+                let response = generate_search_response(query, search_max_duration, &signer_4);
+                println!("END (route:search)...");
+                response
+            });
+
+    // domain.tld/stream/[anything]?format=[opus|mp3|wav]&bitrate=[kbps]
+    let transcode = warp::path!("stream" / String)
+        .and(warp::query::<TranscodeQuery>())
+        .and(warp::method())
+        .and(warp::header::headers_cloned())
+        .and_then(
+            move |token: String, query: TranscodeQuery, method: Method, headers: HeaderMap| {
+                get_transcode(
+                    token,
+                    query,
+                    method,
+                    headers,
+                    signer_7.clone(),
+                    Arc::clone(&files_mutex_7),
+                    transcode_config.clone(),
+                    Arc::clone(&transcode_jobs),
+                )
+            },
+        );
+
+    // domain.tld/stream/[anything] (GET or HEAD, with ranges and preconditions)
+    let stream = warp::path!("stream" / String)
+        .and(warp::method())
+        .and(warp::header::headers_cloned())
+        .and_then(move |token: String, method: Method, headers: HeaderMap| {
+            println!("START (stream/[anything])...");
+            get_range(
+                token,
+                method,
+                headers,
+                signer_2.clone(),
+                Arc::clone(&files_mutex_3),
+            )
+        });
+
+    // domain.tld/download/[token] (the stream, saved under the file's name)
+    let download = warp::path!("download" / String)
+        .and(warp::method())
+        .and(warp::header::headers_cloned())
+        .and_then(move |token: String, method: Method, headers: HeaderMap| {
+            println!("START (route:download)...");
+            get_download(
+                token,
+                method,
+                headers,
+                signer_3.clone(),
+                Arc::clone(&files_mutex_4),
+            )
+        });
+
+    // domain.tld/download/album/[album_id]
+    let album_download = warp::path!("download" / "album" / String)
+        .and(warp::method())
+        .and_then(move |token: String, method: Method| {
+            println!("START (route:download/album)...");
+            get_album_download(
+                token,
+                method,
+                signer_9.clone(),
+                Arc::clone(&files_mutex_8),
+                Arc::clone(&all_mutex_3),
+            )
+        });
+
+    // domain.tld/radio/[all|tunes|mixes].mp3
+    let radio = warp::path!("radio" / String)
+        .and(warp::header::optional::<String>("icy-metadata"))
+        .map(move |station: String, icy_metadata: Option<String>| {
+            println!("START (route:radio)...");
+            generate_radio_response(&radio, station, icy_metadata)
+        });
+
+    // domain.tld/playlist/[all|tunes|mixes].[m3u8|pls|xspf]?count=[files]
+    let playlist = warp::path!("playlist" / String)
+        .and(warp::query::<PlaylistQuery>())
+        .and(warp::header::headers_cloned())
+        .map(
+            move |name: String, query: PlaylistQuery, headers: HeaderMap| {
+                println!("START (route:playlist)...");
+                let hashes = match name.rsplit_once('.') {
+                    Some((mode, _)) if radio::MODES.contains(&mode) => random_hashes(
+                        Arc::clone(&all_mutex_2),
+                        Arc::clone(&mixes_mutex_2),
+                        Arc::clone(&tunes_mutex_2),
+                        mode,
+                        query
+                            .count
+                            .unwrap_or(PLAYLIST_DEFAULT_COUNT)
+                            .clamp(1, PLAYLIST_MAX_COUNT),
+                    ),
+                    _ => Vec::new(),
+                };
+                let base_url = base_url(&public_url, &headers);
+                let response =
+                    generate_playlist_response(&name, hashes, &base_url, &files_mutex_2, &signer_8);
+                println!("END (route:playlist)...");
+                response
+            },
+        );
+
+    // domain.tld/art/[token]?size=[pixels]
+    let art = warp::path!("art" / String)
+        .and(warp::query::<ArtQuery>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(
+            move |token: String, query: ArtQuery, if_none_match: Option<String>| {
+                println!("START (route:art)...");
+                get_art(
+                    token,
+                    query,
+                    if_none_match,
+                    signer_5.clone(),
+                    Arc::clone(&files_mutex_5),
+                    art_directory.clone(),
+                    art_sizes.clone(),
+                )
+            },
+        );
+
+    // domain.tld/waveform/[token]?buckets=[count]&format=[json|binary]
+    let waveform = warp::path!("waveform" / String)
+        .and(warp::query::<WaveformQuery>())
+        .map(move |token: String, query: WaveformQuery| {
+            println!("START (route:waveform)...");
+            let response = generate_waveform_response(token, query, &signer_6, &files_mutex_6);
+            println!("END (route:waveform)...");
+            response
+        });
+
+    // domain.tld/rest/[method](.view)?u=&t=&s=&f=... (Subsonic clients)
+    let subsonic_params = warp::get()
+        .and(warp::query::<Vec<(String, String)>>())
+        .or(warp::post()
+            .and(warp::query::<Vec<(String, String)>>())
+            .and(warp::body::content_length_limit(64 * 1024))
+            .and(warp::body::form::<Vec<(String, String)>>())
+            .map(
+                |mut query: Vec<(String, String)>, form: Vec<(String, String)>| {
+                    query.extend(form);
+                    query
+                },
+            ))
+        .unify();
+    let rest = warp::path!("rest" / String)
+        .and(subsonic_params)
+        .and(warp::header::headers_cloned())
+        .and_then(
+            move |method: String, params: Vec<(String, String)>, headers: HeaderMap| {
+                println!("START (route:rest/{})...", method);
+                generate_subsonic_response(
+                    Arc::clone(&subsonic),
+                    method,
+                    subsonic::Params(params),
+                    headers,
+                    subsonic_config.clone(),
+                    Arc::clone(&jobs),
+                )
+            },
+        );
+
+    let cors = warp::cors()
+        .allow_origins(config.cors_origins.iter().map(|origin| origin.as_str()))
+        .allow_methods(&[Method::GET, Method::HEAD, Method::POST, Method::OPTIONS])
+        .allow_headers(vec!["Authorization", "Content-Type", "User-Agent"]);
+    //.allow_headers(vec!["Sec-Fetch-Mode", "Referer", "Origin", "Access-Control-Request-Method", "Access-Control-Request-Headers"]);
+
+    let gets = warp::get()
+        .and(
+            default
+                .or(random)
+                .or(search)
+                .or(radio)
+                .or(playlist)
+                .or(art)
+                .or(waveform)
+                .or(js),
+        )
+        .or(warp::get()
+            .or(warp::head())
+            .unify()
+            .and(transcode.or(stream).or(download).or(album_download)))
+        .or(rest)
+        .with(cors)
+        .recover(handle_rejection);
+
+    warp::serve(gets).run((config.address, config.port)).await;
+}
+
+#[derive(Deserialize, Debug)]
+struct WaveformQuery {
+    pub buckets: Option<u32>,
+    pub format: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct WaveformResponse {
+    pub status: u16,
+    pub message: String,
+    pub buckets: u32,
+    pub duration: u64,
+    pub data: Vec<i8>,
+}
+
+// Peaks for the file behind a play token, as interleaved min/max pairs
+// between -127 and 127. `format=binary` sends the same pairs as raw signed
+// bytes, half the size of the JSON even after compression.
+fn generate_waveform_response(
+    token: String,
+    query: WaveformQuery,
+    signer: &token::Signer,
+    files_mutex: &Arc<Mutex<HashMap<u32, File>>>,
+) -> warp::reply::Response {
+    let binary = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("binary") => true,
+        Some(_) => {
+            let response = EmptyResponse {
+                status: 400,
+                message: "`format` must be `json` or `binary`".to_string(),
+            };
+
+            return warp::reply::with_status(warp::reply::json(&response), StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+    };
+
+    let file = match get_file_from_token(&token, signer, files_mutex) {
+        Some(file) => file,
+        None => {
+            let response = EmptyResponse {
+                status: 404,
+                message: "Unknown token".to_string(),
+            };
+
+            return warp::reply::with_status(warp::reply::json(&response), StatusCode::NOT_FOUND)
+                .into_response();
+        }
+    };
+
+    let (buckets, peaks) = match waveform::load(file.id, query.buckets) {
+        Ok(Some(waveform)) => waveform,
+        Ok(None) => {
+            let response = EmptyResponse {
+                status: 404,
+                message: "No waveform for this file (yet...)".to_string(),
+            };
+
+            return warp::reply::with_status(warp::reply::json(&response), StatusCode::NOT_FOUND)
+                .into_response();
+        }
+        Err(err) => {
+            println!("Could not load waveform: {}", err);
+            let response = EmptyResponse {
+                status: 500,
+                message: "Could not load waveform".to_string(),
+            };
+
+            return warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response();
+        }
+    };
+
+    if binary {
+        return warp::http::Response::builder()
+            .header("Content-Type", "application/octet-stream")
+            .header("X-Waveform-Buckets", buckets)
+            .header("X-Waveform-Duration", file.duration)
+            .body(Body::from(peaks))
+            .unwrap();
+    }
+
+    let response = WaveformResponse {
+        status: 200,
+        message: "OK".to_string(),
+        buckets,
+        duration: file.duration,
+        data: peaks.iter().map(|peak| *peak as i8).collect(),
+    };
+
+    warp::reply::json(&response).into_response()
+}
+
+// Subsonic requests answer in the client's format (XML unless `f=json`),
+// streams and covers go through the same code as the rest of the API
+async fn generate_subsonic_response(
+    subsonic: Arc<subsonic::Subsonic>,
+    method: String,
+    params: subsonic::Params,
+    headers: HeaderMap,
+    config: Config,
+    jobs: Arc<transcode::Jobs>,
+) -> Result<warp::reply::Response, Rejection> {
+    if !subsonic.enabled() {
+        return Err(warp::reject::not_found());
+    }
+
+    let json = params.json();
+
+    // some requests read the database, keep them off the async threads
+    let outcome = tokio::task::spawn_blocking(move || subsonic.handle(&method, &params))
+        .await
+        .map_err(|err| {
+            println!("Subsonic request failed: {}", err);
+            warp::reject()
+        })?;
+
+    let result = match outcome {
+        subsonic::Outcome::Ok(payload) => Ok(payload),
+        subsonic::Outcome::Failed(code, message) => Err((code, message)),
+        subsonic::Outcome::Stream {
+            file,
+            format,
+            bitrate,
+        } => {
+            if let Some(target) = format
+                .as_deref()
+                .and_then(|format| transcode::Target::find(&config, format))
+            {
+                return transcode_file(
+                    *file,
+                    target,
+                    bitrate,
+                    Method::GET,
+                    headers,
+                    &config,
+                    &jobs,
+                )
+                .await;
+            }
+
+            let mime = file.mime_type();
+
+            let tag = format!("{:08x}", file.id);
+
+            return match internal_get_range(file.path, mime, tag, Method::GET, headers).await {
+                Ok(response) => Ok(response),
+                Err(err) => {
+                    println!("Error in generate_subsonic_response: {}", err.message);
+                    Err(warp::reject())
+                }
+            };
+        }
+        subsonic::Outcome::Cover { hash, size } => {
+            let size = size.and_then(|size| art::snap_size(&config.art_sizes, size));
+            let art_directory = config.art_directory.clone();
+            let cover =
+                tokio::task::spawn_blocking(move || art::read_cover(&art_directory, &hash, size))
+                    .await;
+
+            match cover {
+                Ok(Ok((image, mime))) => {
+                    return Ok(warp::http::Response::builder()
+                        .header("Content-Type", mime)
+                        .header("Content-Length", image.len())
+                        .header("Cache-Control", "public, max-age=31536000, immutable")
+                        .body(Body::from(image))
+                        .unwrap());
+                }
+                _ => Err((70, "Cover art not found".to_string())),
+            }
+        }
+    };
+
+    let content_type = if json {
+        "application/json"
+    } else {
+        "text/xml; charset=utf-8"
+    };
+
+    Ok(warp::http::Response::builder()
+        .header("Content-Type", content_type)
+        .body(Body::from(subsonic::render(json, result)))
+        .unwrap())
+}
+
+const PLAYLIST_DEFAULT_COUNT: usize = 50;
+const PLAYLIST_MAX_COUNT: usize = 500;
+
+#[derive(Deserialize, Debug)]
+struct PlaylistQuery {
+    pub count: Option<usize>,
+}
+
+// Where this server can be reached from outside, for links that leave the
+// browser. [serve] public_url wins, otherwise it's worked out from the
+// request, which is only right behind a proxy that passes the Host through.
+fn base_url(public_url: &str, headers: &HeaderMap) -> String {
+    if !public_url.is_empty() {
+        return public_url.trim_end_matches('/').to_string();
+    }
+
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| {
+                value
+                    .split(',')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string()
+            })
+            .filter(|value| !value.is_empty())
+    };
+
+    let scheme = header("x-forwarded-proto").unwrap_or_else(|| "http".to_string());
+    let host = header("x-forwarded-host")
+        .or_else(|| header("host"))
+        .unwrap_or_else(|| "localhost".to_string());
+
+    format!("{}://{}", scheme, host)
+}
+
+// e.g all.m3u8, every entry gets its own play token
+fn generate_playlist_response(
+    name: &str,
+    hashes: Vec<u32>,
+    base_url: &str,
+    files_mutex: &Arc<Mutex<HashMap<u32, File>>>,
+    signer: &token::Signer,
+) -> warp::reply::Response {
+    let format = match name.rsplit_once('.') {
+        Some((mode, extension)) if radio::MODES.contains(&mode) => playlist::find_format(extension),
+        _ => None,
+    };
+
+    let format = match format {
+        Some(format) => format,
+        None => {
+            let response = EmptyResponse {
+                status: 404,
+                message: "Unknown playlist, try all.m3u8, tunes.pls or mixes.xspf".to_string(),
+            };
+
+            return warp::reply::with_status(warp::reply::json(&response), StatusCode::NOT_FOUND)
+                .into_response();
+        }
+    };
+
+    println!("Locking files (generate_playlist_response)...");
+    let files = files_mutex.lock().unwrap();
+    let selected: Vec<File> = hashes
+        .iter()
+        .filter_map(|hash| files.get(hash).cloned())
+        .collect();
+    println!("Unlocking files (generate_playlist_response)...");
+    drop(files);
+
+    let entries: Vec<playlist::Entry> = selected
+        .iter()
+        .zip(issue_tokens(selected.clone(), signer))
+        .map(|(file, hashed)| playlist::Entry {
+            url: format!("{}/stream/{}", base_url, hashed.path),
+            name: file.display_title(),
+            title: if file.title.is_empty() {
+                file.file_name.clone()
+            } else {
+                file.title.clone()
+            },
+            artist: file.artist.clone(),
+            album: file.album.clone(),
+            duration: file.duration,
+        })
+        .collect();
+
+    warp::http::Response::builder()
+        .header("Content-Type", format.mime)
+        .header("Cache-Control", "no-store")
+        .body(Body::from((format.render)(&entries)))
+        .unwrap()
+}
+
+// An endless MP3 stream shared by everyone listening to the same mode. Players
+// that send `Icy-MetaData: 1` get the artist and title of what's playing.
+fn generate_radio_response(
+    radio: &Arc<radio::Radio>,
+    station: String,
+    icy_metadata: Option<String>,
+) -> warp::reply::Response {
+    let mode = match station.strip_suffix(".mp3") {
+        Some(mode) if radio::MODES.contains(&mode) => mode,
+        _ => {
+            let response = EmptyResponse {
+                status: 404,
+                message: "Unknown station, try all.mp3, tunes.mp3 or mixes.mp3".to_string(),
+            };
+
+            return warp::reply::with_status(warp::reply::json(&response), StatusCode::NOT_FOUND)
+                .into_response();
+        }
+    };
+
+    let radio::Listener {
+        burst,
+        mut receiver,
+    } = radio.tune_in(mode);
+
+    let mut icy = match icy_metadata.as_deref().map(str::trim) {
+        Some("1") => Some(radio::IcyWriter::new()),
+        _ => None,
+    };
+    let metaint = icy.is_some();
+
+    let stream = stream! {
+        let mut write = move |chunk: &radio::Chunk| match icy.as_mut() {
+            Some(icy) => icy.write(chunk),
+            None => chunk.audio.to_vec(),
+        };
+
+        for chunk in &burst {
+            yield Ok(write(chunk)) as Result<Vec<u8>, hyper::Error>;
+        }
+
+        loop {
+            match receiver.recv().await {
+                Ok(chunk) => yield Ok(write(&chunk)),
+                // a slow listener skips ahead rather than holding everyone up
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    };
+
+    let mut response = warp::http::Response::builder()
+        .header("Content-Type", "audio/mpeg")
+        .header("Cache-Control", "no-cache, no-store")
+        .header("icy-name", format!("auralist ({})", mode))
+        .header("icy-pub", "0");
+
+    if metaint {
+        response = response.header("icy-metaint", radio::ICY_METAINT);
+    }
+
+    response.body(Body::wrap_stream(stream)).unwrap()
+}
+
+#[derive(Deserialize, Debug)]
+struct TranscodeQuery {
+    pub format: Option<String>,
+    pub bitrate: Option<u32>,
+}
+
+// The file behind a play token in another format. The first request for a
+// file is encoded while it's sent, so it has no length and ignores ranges
+// (`Accept-Ranges: none`). Once a transcode has finished it's kept in the
+// transcode cache and later requests for it can seek like any other file.
+#[allow(clippy::too_many_arguments)]
+async fn get_transcode(
+    token: String,
+    query: TranscodeQuery,
+    method: Method,
+    headers: HeaderMap,
+    signer: token::Signer,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    config: Config,
+    jobs: Arc<transcode::Jobs>,
+) -> Result<warp::reply::Response, Rejection> {
+    // without a format this is a normal stream
+    let format = match query.format {
+        Some(format) => format,
+        None => return Err(warp::reject()),
+    };
 
-                if warm_the_file == true {
-                    println!("Queueing the file to be warmed...");
-                    println!("Locking to_be_warmed (get_files)...");
-                    let mut to_be_warmed = to_be_warmed_mutex.lock().unwrap();
-                    if !to_be_warmed.contains(&file_hash) {
-                        println!("Queueing file to be indexed...");
-                        to_be_warmed.push(file_hash);
-                        to_be_warmed.dedup();
-                    } else {
-                        println!("File is already queued to be indexed...");
-                    }
-                    println!("Unlocking to_be_warmed (get_files)...");
-                    drop(to_be_warmed);
-                } else {
-                    println!("Did not queue the file to be indexed...");
-                }
-            }
+    println!("START (route:transcode)...");
+
+    let target = match transcode::Target::find(&config, &format) {
+        Some(target) => target,
+        None => {
+            let response = EmptyResponse {
+                status: 400,
+                message: format!("Cannot transcode to `{}`", format),
+            };
+
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response());
         }
-        println!("END (get_files)...");
-    }
+    };
 
-    Ok(())
-}
+    let file = match get_file_from_token(&token, &signer, &files_mutex) {
+        Some(file) => file,
+        None => {
+            let response = EmptyResponse {
+                status: 404,
+                message: "Unknown token".to_string(),
+            };
 
-fn get_file_from_hash(
-    hash: String,
-    plays_mutex: Arc<Mutex<HashMap<String, File>>>,
-) -> Option<music::File> {
-    println!("Locking plays (get_file_from_hash)...");
-    let plays = plays_mutex.lock().unwrap();
-    let result = match plays.get(&hash) {
-        Some(file) => Some(file.clone()),
-        None => None,
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::NOT_FOUND,
+            )
+            .into_response());
+        }
     };
-    println!("Unocking plays (get_file_from_hash)...");
-    drop(plays);
 
-    println!("END (get_file_from_hash)...");
-    return result;
+    transcode_file(file, target, query.bitrate, method, headers, &config, &jobs).await
 }
 
-fn generate_random_response(
-    files_mutex: &Arc<std::sync::Mutex<std::collections::HashMap<u32, music::File>>>,
-    plays_mutex: &Arc<Mutex<HashMap<String, File>>>,
-    random_hash: u32,
-) -> warp::reply::Json {
-    if random_hash == 0 {
-        let response = EmptyResponse {
-            status: 404,
-            message: "No files have been indexed (yet...)".to_string(),
-        };
-
-        return warp::reply::json(&response);
+// Serves a transcode of `file` from the cache when it's there, otherwise
+// encodes it while it's sent. Listeners asking for a transcode that's already
+// running follow that one instead of starting another.
+async fn transcode_file(
+    file: File,
+    target: transcode::Target,
+    bitrate: Option<u32>,
+    method: Method,
+    headers: HeaderMap,
+    config: &Config,
+    jobs: &Arc<transcode::Jobs>,
+) -> Result<warp::reply::Response, Rejection> {
+    let bitrate = target.bitrate(bitrate);
+    let mime = target.mime;
+
+    let (output, keep) = target.output_path(config, &file, bitrate, None);
+
+    if keep && output.exists() {
+        return get_cached_transcode(&output, mime, method, headers).await;
     }
 
-    let files_mutex = Arc::clone(&files_mutex);
-    let files = files_mutex.lock().unwrap();
+    // nothing is encoded until someone actually wants it
+    if method == Method::HEAD {
+        return Ok(warp::http::Response::builder()
+            .header("Content-Type", mime)
+            .header("Accept-Ranges", "none")
+            .body(Body::empty())
+            .unwrap());
+    }
 
-    let mut random_files: Vec<File> = Vec::new();
+    let (lead, mut follower) = match jobs.join(output.clone(), keep) {
+        Ok(transcode::Joined::Lead(lead, follower)) => (Some(lead), follower),
+        Ok(transcode::Joined::Follow(follower)) => (None, follower),
+        Ok(transcode::Joined::Busy) => {
+            println!("Too many transcodes, turning one away...");
+            let response = EmptyResponse {
+                status: 503,
+                message: "Too many transcodes are running, try again soon".to_string(),
+            };
 
-    match files.get(&random_hash) {
-        Some(file) => random_files.push(file.clone()),
-        _ => println!("Hash is missing from db"),
+            return Ok(warp::reply::with_header(
+                warp::reply::with_status(
+                    warp::reply::json(&response),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                ),
+                "Retry-After",
+                "10",
+            )
+            .into_response());
+        }
+        Err(err) => {
+            println!("Could not transcode: {}", err);
+            return Ok(transcode_failed());
+        }
     };
 
-    drop(files);
+    if let Some(lead) = lead {
+        // it may have finished between looking and joining
+        if keep && output.exists() {
+            drop(lead);
+            return get_cached_transcode(&output, mime, method, headers).await;
+        }
 
-    let mut random_files_hashed: Vec<FileHashed> = Vec::new();
-    for file in random_files {
-        let file_hashed = file.clone().to_response();
-        random_files_hashed.push(file_hashed.clone());
+        println!(
+            "Transcoding `{}` to {} at {} kbps...",
+            file.path, target.name, bitrate
+        );
 
-        let plays_mutex = Arc::clone(&plays_mutex);
+        // probing and starting the encoder both block
+        let started = tokio::task::spawn_blocking(move || {
+            transcode::Transcode::start(&file.path, &file.file_ext, &target, bitrate, None)
+        })
+        .await;
 
-        // Acquire and drop mutex
-        println!("Locking plays (serve)...");
-        let mut plays = plays_mutex.lock().unwrap();
-        let mut file = file.clone();
-        file.accessed_at = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        plays.insert(file_hashed.path.clone(), file);
-        println!("Unlocking plays (serve)...");
-        drop(plays);
+        match started {
+            Ok(Ok(transcode)) => {
+                thread::spawn(move || lead.run(transcode));
+            }
+            Ok(Err(err)) => {
+                println!("Could not transcode: {}", err);
+                return Ok(transcode_failed());
+            }
+            Err(err) => {
+                println!("Could not transcode: {}", err);
+                return Err(warp::reject());
+            }
+        }
     }
 
-    let response = FileResponse {
-        status: 200,
-        message: "OK".to_string(),
-        count: random_files_hashed.len(),
-        data: random_files_hashed,
+    let stream = stream! {
+        while let Some(chunk) = follower.next().await {
+            yield chunk;
+        }
     };
 
-    return warp::reply::json(&response);
+    Ok(warp::http::Response::builder()
+        .header("Content-Type", mime)
+        .header("Accept-Ranges", "none")
+        .body(Body::wrap_stream(stream))
+        .unwrap())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct EmptyResponse {
-    pub status: i32,
-    pub message: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct FileResponse {
-    pub status: i32,
-    pub message: String,
-    pub count: usize,
-    pub data: Vec<FileHashed>,
+async fn get_cached_transcode(
+    cache_path: &Path,
+    mime: &str,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<warp::reply::Response, Rejection> {
+    println!("Serving cached transcode `{}`...", cache_path.display());
+
+    let path = cache_path.to_string_lossy().to_string();
+    // the cache file name already says which file, encoding and bitrate
+    let tag = cache_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    match internal_get_range(path, mime.to_string(), tag, method, headers).await {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            println!("Error in transcode_file: {}", err.message);
+            Err(warp::reject())
+        }
+    }
 }
 
-#[tokio::main]
-async fn serve(
-    files_mutex: Arc<std::sync::Mutex<std::collections::HashMap<u32, music::File>>>,
-    plays_mutex: Arc<Mutex<HashMap<String, File>>>,
-    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
-    mixes_mutex: Arc<Mutex<Vec<u32>>>,
-    tunes_mutex: Arc<Mutex<Vec<u32>>>,
-) {
-    println!("SERVING");
-    let files_mutex_1 = Arc::clone(&files_mutex);
-    let have_been_warmed_mutex = Arc::clone(&have_been_warmed_mutex);
-    let mixes_mutex_1 = Arc::clone(&mixes_mutex);
-    let tunes_mutex_1 = Arc::clone(&tunes_mutex);
-
-    let plays_mutex_1 = Arc::clone(&plays_mutex);
-    let plays_mutex_2 = Arc::clone(&plays_mutex);
-    let plays_mutex_3 = Arc::clone(&plays_mutex);
-
-    // default e.g https://domain.tld
-    let default = warp::path::end().and(warp::fs::file("static/index.html"));
+fn transcode_failed() -> warp::reply::Response {
+    let response = EmptyResponse {
+        status: 500,
+        message: "Could not transcode this file".to_string(),
+    };
 
-    // domain.tld/js/*
-    let js = warp::path("js")
-        .and(warp::fs::dir("static/js"))
-        .map(|res: warp::fs::File| {
-            // cache for 23 days
-            warp::reply::with_header(
-                res,
-                "cache-control",
-                "Cache-Control: public, max-age 1987200, s-maxage 1987200, immutable",
-            )
-        });
+    warp::reply::with_status(
+        warp::reply::json(&response),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+    .into_response()
+}
 
-    // domain.tld/random
-    let random = warp::path!("random" / String).map(move |mode: String| {
-        println!("START (route:random)...");
-        let all = Arc::clone(&have_been_warmed_mutex);
-        let mixes = Arc::clone(&mixes_mutex_1);
-        let tunes = Arc::clone(&tunes_mutex_1);
-        let random_hash = random_hash(all, mixes, tunes, mode.to_string());
-        let fm = Arc::clone(&files_mutex_1);
-        let response = generate_random_response(&fm, &plays_mutex_1, random_hash);
-        println!("END (route:random)...");
-        return response;
-    });
+#[derive(Deserialize, Debug)]
+struct ArtQuery {
+    pub size: Option<u32>,
+}
 
-    // domain.tld/stream/[anything] (parses range headers)
-    let stream = warp::path!("stream" / String)
-        .and(filter_range())
-        .and_then(move |hash: String, range_header: String| {
-            println!("START (stream/[anything])...");
-            let plays_mutex = plays_mutex_2.clone();
+// The cover of the file behind a play token. A token always points at the same
+// cover so browsers can keep it forever.
+async fn get_art(
+    token: String,
+    query: ArtQuery,
+    if_none_match: Option<String>,
+    signer: token::Signer,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    art_directory: String,
+    art_sizes: Vec<u32>,
+) -> Result<warp::reply::Response, Rejection> {
+    let file = match get_file_from_token(&token, &signer, &files_mutex) {
+        Some(file) => file,
+        None => return Err(warp::reject::not_found()),
+    };
 
-            // hash e.g 1f768ac1-6e83-4f12-a4c3-ad37f6d93844
-            let sliced_hash = hash[0..36].to_string();
+    if file.art.is_empty() {
+        return Err(warp::reject::not_found());
+    }
 
-            get_range(range_header, sliced_hash, plays_mutex)
-        })
-        .map(with_partial_content_status);
-
-    // domain.tld/stream/[anything] (when stream headers are missing)
-    let download = warp::path!("stream" / String).and_then(move |hash: String| {
-        let plays_mutex = plays_mutex_3.clone();
-        let range = get_range("".to_string(), hash, Arc::clone(&plays_mutex));
-        drop(plays_mutex);
-        return range;
-    });
+    let size = query.size.and_then(|size| art::snap_size(&art_sizes, size));
 
-    let cors = warp::cors()
-        .allow_origins(vec![
-            "https://randomsound.uk",
-            "http://localhost:1338",
-            "http://localhost:1337",
-            "http://192.168.2.41:1337",
-        ])
-        .allow_methods(&[Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers(vec!["Authorization", "Content-Type", "User-Agent"]);
-    //.allow_headers(vec!["Sec-Fetch-Mode", "Referer", "Origin", "Access-Control-Request-Method", "Access-Control-Request-Headers"]);
+    let etag = match size {
+        Some(size) => format!("\"{}-{}\"", file.art, size),
+        None => format!("\"{}\"", file.art),
+    };
 
-    let gets = warp::get()
-        .and(default.or(random).or(stream).or(download).or(js))
-        .with(cors)
-        .recover(handle_rejection);
+    let cache_control = "public, max-age=31536000, immutable";
+
+    if let Some(if_none_match) = if_none_match {
+        if if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        {
+            return Ok(warp::http::Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header("ETag", etag)
+                .header("Cache-Control", cache_control)
+                .body(Body::empty())
+                .unwrap());
+        }
+    }
 
-    warp::serve(gets).run(([0, 0, 0, 0], 1337)).await;
+    // resizing is slow, keep it off the async threads
+    let hash = file.art.clone();
+    let cover =
+        tokio::task::spawn_blocking(move || art::read_cover(&art_directory, &hash, size)).await;
+
+    match cover {
+        Ok(Ok((image, mime))) => Ok(warp::http::Response::builder()
+            .header("Content-Type", mime)
+            .header("Content-Length", image.len())
+            .header("ETag", etag)
+            .header("Cache-Control", cache_control)
+            .body(Body::from(image))
+            .unwrap()),
+        Ok(Err(err)) => {
+            println!("Could not read cover `{}`: {}", file.art, err);
+            Err(warp::reject::not_found())
+        }
+        Err(err) => {
+            println!("Could not read cover `{}`: {}", file.art, err);
+            Err(warp::reject::not_found())
+        }
+    }
 }
 
 async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
     let (code, message) = if err.is_not_found() {
         (StatusCode::NOT_FOUND, "Not Found".to_string())
+    } else if err.find::<warp::reject::InvalidQuery>().is_some() {
+        (StatusCode::BAD_REQUEST, "Invalid query string".to_string())
     } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
         (StatusCode::BAD_REQUEST, "Payload too large".to_string())
     } else {
@@ -724,68 +2597,236 @@ async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Inf
 
 // borrowed from warp-range
 use async_stream::stream;
-use std::{cmp::min, io::SeekFrom, num::ParseIntError};
+use std::{cmp::min, io::SeekFrom};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
-use warp::{http::HeaderValue, hyper::Body, hyper::HeaderMap, reply::WithStatus};
-
-/// This function filters and extracts the "Range"-Header
-pub fn filter_range() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
-    println!("filter_range...");
-    warp::header::<String>("Range")
-}
+use warp::{http::HeaderValue, hyper::Body, hyper::HeaderMap};
 
 /// This function retrives the range of bytes requested by the web client
 pub async fn get_range(
-    range_header: String,
-    hash: String,
-    plays_mutex: Arc<Mutex<HashMap<String, File>>>,
+    token: String,
+    method: Method,
+    headers: HeaderMap,
+    signer: token::Signer,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
 ) -> Result<impl warp::Reply, Rejection> {
-    let file_option = get_file_from_hash(hash.clone(), plays_mutex);
+    // players sometimes add an extension, e.g [token].mp3
+    let token = token.split('.').next().unwrap_or_default();
+    let file_option = get_file_from_token(token, &signer, &files_mutex);
 
     if file_option.is_none() {
         println!(
-            "Error in internal_get_range: get_file_from_hash returned None for hash: `{:?}`",
-            hash.to_string()
+            "Error in get_range: get_file_from_token returned None for token: `{:?}`",
+            token
         );
         return Err(warp::reject::custom(InvalidParameter));
     }
 
     let file = file_option.unwrap();
 
-    return internal_get_range(file, range_header).await.map_err(|e| {
-        println!("Error in get_range: {}", e.message);
-        warp::reject()
-    });
+    let mime = file.mime_type();
+    let tag = format!("{:08x}", file.id);
+
+    internal_get_range(file.path, mime, tag, method, headers)
+        .await
+        .map_err(|e| {
+            println!("Error in get_range: {}", e.message);
+            warp::reject()
+        })
+}
+
+// The file behind a play token as an attachment named after it, resumable
+// like any stream
+async fn get_download(
+    token: String,
+    method: Method,
+    headers: HeaderMap,
+    signer: token::Signer,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+) -> Result<warp::reply::Response, Rejection> {
+    let file = match get_file_from_token(&token, &signer, &files_mutex) {
+        Some(file) => file,
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let mime = file.mime_type();
+    let tag = format!("{:08x}", file.id);
+
+    let mut response = internal_get_range(file.path, mime, tag, method, headers)
+        .await
+        .map_err(|e| {
+            println!("Error in get_download: {}", e.message);
+            warp::reject()
+        })?;
+
+    response.headers_mut().insert(
+        "Content-Disposition",
+        HeaderValue::from_str(&content_disposition(&file.file_name)).unwrap(),
+    );
+
+    Ok(response)
+}
+
+// Every song on an album (the signed `album_id` in file responses) as a
+// zip, written while it's sent so nothing is held in memory
+async fn get_album_download(
+    token: String,
+    method: Method,
+    signer: token::Signer,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    all_mutex: Arc<Mutex<Vec<u32>>>,
+) -> Result<warp::reply::Response, Rejection> {
+    let mut songs = match signer.verify_album(&token) {
+        Some(id) => album_songs(&id, &files_mutex, &all_mutex),
+        None => Vec::new(),
+    };
+
+    if songs.is_empty() {
+        let response = EmptyResponse {
+            status: 404,
+            message: "Unknown album".to_string(),
+        };
+
+        return Ok(
+            warp::reply::with_status(warp::reply::json(&response), StatusCode::NOT_FOUND)
+                .into_response(),
+        );
+    }
+
+    songs.sort_by_key(|file| (file.disc_number, file.track_number, file.file_name.clone()));
+
+    // e.g Artist - Album/01 Song.mp3
+    let folder = safe_file_name(&format!(
+        "{} - {}",
+        songs[0].album_artist_name(),
+        songs[0].album_name()
+    ));
+
+    let mut names: HashSet<String> = HashSet::new();
+    let mut entries: Vec<zip::Entry> = Vec::new();
+    for song in songs {
+        let metadata = match tokio::fs::metadata(&song.path).await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                println!("Leaving `{}` out of the zip: {}", song.path, err);
+                continue;
+            }
+        };
+
+        // the same file name from two folders
+        let mut name = safe_file_name(&song.file_name);
+        let mut copy = 1;
+        while !names.insert(name.to_lowercase()) {
+            copy += 1;
+            let path = Path::new(&song.file_name);
+            name = match (path.file_stem(), path.extension()) {
+                (Some(stem), Some(extension)) => safe_file_name(&format!(
+                    "{} ({}).{}",
+                    stem.to_string_lossy(),
+                    copy,
+                    extension.to_string_lossy()
+                )),
+                _ => safe_file_name(&format!("{} ({})", song.file_name, copy)),
+            };
+        }
+
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .unwrap_or(0);
+
+        entries.push(zip::Entry {
+            path: song.path,
+            name: format!("{}/{}", folder, name),
+            size: metadata.len(),
+            modified,
+        });
+    }
+
+    let content_length = zip::content_length(&entries);
+
+    // HEAD gets the same headers without any file being read
+    let body = if method == Method::HEAD {
+        Body::empty()
+    } else {
+        zip::body(entries)
+    };
+
+    Ok(warp::http::Response::builder()
+        .header("Content-Type", "application/zip")
+        .header("Content-Length", content_length)
+        .header(
+            "Content-Disposition",
+            content_disposition(&format!("{}.zip", folder)),
+        )
+        .body(body)
+        .unwrap())
 }
 
-/// This function adds the "206 Partial Content" header
-pub fn with_partial_content_status<T: Reply>(reply: T) -> WithStatus<T> {
-    warp::reply::with_status(reply, StatusCode::PARTIAL_CONTENT)
+fn album_songs(
+    id: &str,
+    files_mutex: &Arc<Mutex<HashMap<u32, File>>>,
+    all_mutex: &Arc<Mutex<Vec<u32>>>,
+) -> Vec<File> {
+    println!("Locking files (album_songs)...");
+    let files = files_mutex.lock().unwrap();
+    let all = all_mutex.lock().unwrap();
+    let songs = all
+        .iter()
+        .filter_map(|id| files.get(id))
+        .filter(|file| file.album_id() == id)
+        .cloned()
+        .collect();
+    drop(all);
+    println!("Unlocking files (album_songs)...");
+    drop(files);
+
+    songs
 }
 
-fn get_range_params(range: &str, size: u64) -> Result<(u64, u64), Error> {
-    let range: Vec<String> = range
-        .replace("bytes=", "")
-        .split("-")
-        .filter_map(|n| {
-            if n.len() > 0 {
-                Some(n.to_string())
+// RFC 6266, a plain ASCII name for old clients and the real one for the rest
+fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' && c != '%' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let encoded: String = file_name
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                (byte as char).to_string()
             } else {
-                None
+                format!("%{:02X}", byte)
             }
         })
         .collect();
-    let start = if range.len() > 0 {
-        range[0].parse::<u64>()?
-    } else {
-        0
-    };
-    let end = if range.len() > 1 {
-        range[1].parse::<u64>()?
-    } else {
-        size - 1
-    };
-    Ok((start, end))
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+// Tags can contain anything, but a name in a zip can't climb out of its folder
+fn safe_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    match name.trim() {
+        "" | "." | ".." => "_".to_string(),
+        name => name.to_string(),
+    }
 }
 
 #[derive(Debug)]
@@ -800,101 +2841,492 @@ impl From<std::io::Error> for Error {
         }
     }
 }
-impl From<ParseIntError> for Error {
-    fn from(err: ParseIntError) -> Self {
-        Error {
-            message: err.to_string(),
-        }
-    }
-}
 
-async fn internal_get_range(file: File, range_header: String) -> Result<impl warp::Reply, Error> {
-    let path = &file.path;
-    let guess = mime_guess::from_ext(&file.file_ext).first().unwrap();
-    let mime = guess.essence_str();
+// `tag` goes into the ETag, to tell apart different things served from the
+// same kind of file
+async fn internal_get_range(
+    path: String,
+    mime: String,
+    tag: String,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<warp::reply::Response, Error> {
     let mut file = tokio::fs::File::open(path).await?;
     let metadata = file.metadata().await?;
     let size = metadata.len();
-    let (start_range, end_range) = get_range_params(&range_header, size)?;
-    let mut limited_end_range = end_range;
-    if end_range > size {
-        println!("::::::::::: Range larger than file size detected");
-        limited_end_range = size
+
+    let validators = conditional::Validators::new(&tag, &metadata);
+    let use_range = match conditional::evaluate(&method, &headers, &validators) {
+        conditional::Outcome::Proceed { range } => range,
+        conditional::Outcome::NotModified => {
+            let mut response = warp::reply::Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            insert_validators(response.headers_mut(), &validators);
+            return Ok(response);
+        }
+        conditional::Outcome::PreconditionFailed => {
+            let mut response = warp::reply::Response::new(Body::empty());
+            *response.status_mut() = StatusCode::PRECONDITION_FAILED;
+            return Ok(response);
+        }
+    };
+
+    let range_header = match headers.get("range").and_then(|range| range.to_str().ok()) {
+        Some(range_header) if use_range => range_header,
+        _ => "",
+    };
+
+    let (ranges, partial) = match range::parse(range_header, size) {
+        range::Ranges::Full => (vec![(0, size.saturating_sub(1))], false),
+        range::Ranges::Partial(ranges) => (ranges, true),
+        range::Ranges::Unsatisfiable => {
+            println!("Unsatisfiable range `{}` for {} bytes", range_header, size);
+            let mut response = warp::reply::Response::new(Body::empty());
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            let headers = response.headers_mut();
+            headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+            insert_validators(headers, &validators);
+            headers.insert(
+                "Content-Range",
+                HeaderValue::from_str(&format!("bytes */{}", size)).unwrap(),
+            );
+            return Ok(response);
+        }
+    };
+    // Each part of a multipart/byteranges response has its own headers,
+    // which go before the bytes of its range
+    let boundary = uuid::Uuid::new_v4().simple().to_string();
+    let multipart = ranges.len() > 1;
+    let mut parts: Vec<(Vec<u8>, u64, u64)> = Vec::new();
+    for (start, end) in &ranges {
+        let head = if multipart {
+            format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary, mime, start, end, size
+            )
+            .into_bytes()
+        } else {
+            Vec::new()
+        };
+        // an empty file is one range with nothing in it
+        let byte_count = if size == 0 { 0 } else { end - start + 1 };
+        parts.push((head, *start, byte_count));
     }
-    let byte_count = limited_end_range - start_range + 1;
-    file.seek(SeekFrom::Start(start_range)).await?;
+    let tail = if multipart {
+        format!("\r\n--{}--\r\n", boundary).into_bytes()
+    } else {
+        Vec::new()
+    };
 
-    let stream = stream! {
+    let content_length = parts
+        .iter()
+        .map(|(head, _, byte_count)| head.len() as u64 + byte_count)
+        .sum::<u64>()
+        + tail.len() as u64;
+
+    // HEAD gets the same headers without the file being read
+    let body = if method == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::wrap_stream(stream! {
         let bufsize = 16384;
-        let cycles = byte_count / bufsize as u64 + 1;
-        let mut sent_bytes: u64 = 0;
-        for _ in 0..cycles {
-            let mut buffer: Vec<u8> = vec![0; min(byte_count - sent_bytes, bufsize) as usize];
-            let bytes_read = file.read_exact(&mut buffer).await.unwrap();
-            sent_bytes += bytes_read as u64;
-            yield Ok(buffer) as Result<Vec<u8>, hyper::Error>;
+        for (head, start, byte_count) in parts {
+            if !head.is_empty() {
+                yield Ok(head) as Result<Vec<u8>, std::io::Error>;
+            }
+            if let Err(err) = file.seek(SeekFrom::Start(start)).await {
+                yield Err(err);
+                return;
+            }
+            let mut sent_bytes: u64 = 0;
+            while sent_bytes < byte_count {
+                let mut buffer: Vec<u8> = vec![0; min(byte_count - sent_bytes, bufsize) as usize];
+                // the file may have shrunk since its size was read
+                if let Err(err) = file.read_exact(&mut buffer).await {
+                    yield Err(err);
+                    return;
+                }
+                sent_bytes += buffer.len() as u64;
+                yield Ok(buffer);
+            }
         }
+        if !tail.is_empty() {
+            yield Ok(tail);
+        }
+        })
     };
-    let body = Body::wrap_stream(stream);
     let mut response = warp::reply::Response::new(body);
 
     let headers = response.headers_mut();
     let mut header_map = HeaderMap::new();
-    header_map.insert("Content-Type", HeaderValue::from_str(&mime).unwrap());
     header_map.insert("Accept-Ranges", HeaderValue::from_str("bytes").unwrap());
-    header_map.insert(
-        "Content-Range",
-        HeaderValue::from_str(&format!("bytes {}-{}/{}", start_range, limited_end_range, size)).unwrap(),
-    );
-    header_map.insert("Content-Length", HeaderValue::from(byte_count));
+    if multipart {
+        header_map.insert(
+            "Content-Type",
+            HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary)).unwrap(),
+        );
+    } else {
+        header_map.insert("Content-Type", HeaderValue::from_str(&mime).unwrap());
+    }
+    if partial && !multipart {
+        let (start, end) = ranges[0];
+        header_map.insert(
+            "Content-Range",
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, size)).unwrap(),
+        );
+    }
+    header_map.insert("Content-Length", HeaderValue::from(content_length));
     headers.extend(header_map);
+    insert_validators(headers, &validators);
+
+    if partial {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
 
     Ok(response)
 }
 
-fn random_hash(
+fn insert_validators(headers: &mut HeaderMap, validators: &conditional::Validators) {
+    headers.insert("ETag", HeaderValue::from_str(&validators.etag).unwrap());
+    headers.insert(
+        "Last-Modified",
+        HeaderValue::from_str(&validators.last_modified_header()).unwrap(),
+    );
+}
+
+// The ids a mode picks from
+fn mode_selection(
+    all_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+    mode: &str,
+) -> Arc<Mutex<Vec<u32>>> {
+    match mode {
+        "tunes" => tunes_mutex,
+        "mixes" => mixes_mutex,
+        // all files
+        _ => all_mutex,
+    }
+}
+
+// Up to `count` different random ids, for when one at a time is too slow
+fn random_hashes(
     all_mutex: Arc<Mutex<Vec<u32>>>,
     mixes_mutex: Arc<Mutex<Vec<u32>>>,
     tunes_mutex: Arc<Mutex<Vec<u32>>>,
-    mode: String,
+    mode: &str,
+    count: usize,
+) -> Vec<u32> {
+    let selection_mutex = mode_selection(all_mutex, mixes_mutex, tunes_mutex, mode);
+
+    println!("Locking selection_mutex (random_hashes)...");
+    let selection = selection_mutex.lock().unwrap();
+    let hashes = selection
+        .choose_multiple(&mut rand::thread_rng(), count)
+        .copied()
+        .collect();
+    println!("Unlocking selection_mutex (random_hashes)...");
+    drop(selection);
+
+    hashes
+}
+
+// A random id the session hasn't had from this bag since it last went
+// through the whole selection, 0 when the selection is empty
+fn shuffled_hash(
+    selection_mutex: Arc<Mutex<Vec<u32>>>,
+    sessions_mutex: &Arc<Mutex<shuffle::Sessions>>,
+    session: Option<&str>,
+    bag: &str,
 ) -> u32 {
-    // all files
-    let mut selection_mutex = all_mutex;
+    println!("Locking selection_mutex (shuffled_hash)...");
+    let selection = selection_mutex.lock().unwrap();
+    println!("Locking sessions (shuffled_hash)...");
+    let mut sessions = sessions_mutex.lock().unwrap();
+    let answer = shuffle::pick(&mut sessions, session, bag, &selection).unwrap_or(0);
+    println!("Unlocking sessions (shuffled_hash)...");
+    drop(sessions);
+    println!("Unlocking selection_mutex (shuffled_hash)...");
+    drop(selection);
+
+    answer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if mode == "tunes" {
-        selection_mutex = tunes_mutex;
+    fn file(path: &str) -> File {
+        File::new_empty_file_from_path(Path::new(path)).unwrap()
     }
 
-    if mode == "mixes" {
-        selection_mutex = mixes_mutex;
+    fn library(paths: &[&str]) -> HashMap<u32, File> {
+        paths
+            .iter()
+            .map(|path| file(path))
+            .map(|file| (file.id, file))
+            .collect()
     }
 
-    println!("Locking selection_mutex (random_hash)...");
-    let selection = selection_mutex.lock().unwrap();
-    let ten_random_hashes: Vec<&u32> = selection.choose_multiple(&mut rand::thread_rng(), 30).collect();
+    fn walk(seen: &[&str], failed: &[&str]) -> WalkResult {
+        WalkResult {
+            seen: seen.iter().map(|path| file(path).id).collect(),
+            new: Vec::new(),
+            changed: 0,
+            unchanged: seen.len(),
+            errors: Vec::new(),
+            failed: failed.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    #[test]
+    fn stale_files_skip_failed_paths() {
+        let files = library(&[
+            "/music/readable/a.mp3",
+            "/music/readable/deleted.mp3",
+            "/music/unreadable/b.mp3",
+            "/music/unreadable/deeper/c.mp3",
+            "/music/unreadable-too/d.mp3",
+            "/music/copying.mp3",
+        ]);
+        let walk = walk(
+            &["/music/readable/a.mp3"],
+            &["/music/unreadable", "/music/copying.mp3"],
+        );
 
-    let random_hash_opt: Option<&u32>;
+        let mut stale: Vec<String> = stale_files(&files, &walk)
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        stale.sort();
 
-    let mut answer = 0;
+        // whole folder names only, `unreadable` doesn't cover `unreadable-too`
+        assert_eq!(
+            stale,
+            vec!["/music/readable/deleted.mp3", "/music/unreadable-too/d.mp3"]
+        );
+    }
 
-    if ten_random_hashes.len() == 0 {
-        println!("WARN: Fall back to 'old random'");
-        random_hash_opt = selection.choose(&mut rand::thread_rng());
+    #[test]
+    fn random_query_filters() {
+        let mut short = file("/music/radio/Short.MP3");
+        short.duration = 60;
+        short.year = 1999;
+        short.artist = "Daft Punk".to_string();
+        short.album = "Homework".to_string();
+        short.genre = "House".to_string();
+
+        let mut long = file("/music/radio-shows/2019/long.flac");
+        long.duration = 3600;
+        long.album_artist = "Various Artists".to_string();
+        long.genre = "Electronic".to_string();
+
+        let mut tagless = file("/music/loose.ogg");
+        tagless.duration = 600;
+
+        let cases = vec![
+            (RandomQuery::default(), vec![true, true, true]),
+            // durations, both ends included
+            (
+                RandomQuery {
+                    min_duration: Some(600),
+                    ..RandomQuery::default()
+                },
+                vec![false, true, true],
+            ),
+            (
+                RandomQuery {
+                    max_duration: Some(600),
+                    ..RandomQuery::default()
+                },
+                vec![true, false, true],
+            ),
+            (
+                RandomQuery {
+                    min_duration: Some(61),
+                    max_duration: Some(3599),
+                    ..RandomQuery::default()
+                },
+                vec![false, false, true],
+            ),
+            // files without a year are left out
+            (
+                RandomQuery {
+                    min_year: Some(1990),
+                    ..RandomQuery::default()
+                },
+                vec![true, false, false],
+            ),
+            (
+                RandomQuery {
+                    max_year: Some(2030),
+                    ..RandomQuery::default()
+                },
+                vec![true, false, false],
+            ),
+            (
+                RandomQuery {
+                    min_year: Some(2000),
+                    ..RandomQuery::default()
+                },
+                vec![false, false, false],
+            ),
+            // extensions ignore case and a leading dot
+            (
+                RandomQuery {
+                    ext: Some("mp3, .FLAC".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![true, true, false],
+            ),
+            (
+                RandomQuery {
+                    ext: Some("ogg".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![false, false, true],
+            ),
+            // tags match part of the name ignoring case, the album artist
+            // counts as the artist
+            (
+                RandomQuery {
+                    artist: Some("punk".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![true, false, false],
+            ),
+            (
+                RandomQuery {
+                    artist: Some("various".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![false, true, false],
+            ),
+            (
+                RandomQuery {
+                    album: Some(" HOME ".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![true, false, false],
+            ),
+            (
+                RandomQuery {
+                    genre: Some("o".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![true, true, false],
+            ),
+            (
+                RandomQuery {
+                    artist: Some("punk".to_string()),
+                    genre: Some("electronic".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![false, false, false],
+            ),
+            // whole folder names only, `radio` doesn't match `radio-shows`
+            (
+                RandomQuery {
+                    folder: Some("radio".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![true, false, false],
+            ),
+            (
+                RandomQuery {
+                    folder: Some("/radio-shows/2019/".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![false, true, false],
+            ),
+            (
+                RandomQuery {
+                    folder: Some("radio-shows/2".to_string()),
+                    ..RandomQuery::default()
+                },
+                vec![false, false, false],
+            ),
+        ];
+
+        for (query, expected) in cases {
+            let matched: Vec<bool> = [&short, &long, &tagless]
+                .iter()
+                .map(|file| query.matches(file, "/music"))
+                .collect();
+            assert_eq!(matched, expected, "{:?}", query);
+        }
+    }
 
-        answer = match random_hash_opt {
-            Some(random_hash_opt) => u32::from(random_hash_opt.clone()),
-            None => 0,
-        };
-    } else {
-        println!("OK: Picked a tune'");
-        let random_hash = ten_random_hashes.choose(&mut rand::thread_rng()).clone().unwrap();        
-        answer = random_hash.clone().clone()
+    #[test]
+    fn sweep_keeps_rows_under_a_failing_directory() {
+        let paths = [
+            "/music/readable/a.mp3",
+            "/music/unreadable/b.mp3",
+            "/music/unreadable/c.mp3",
+        ];
+        let files = library(&paths);
+        let ids: Vec<u32> = files.keys().copied().collect();
+
+        let files_mutex = Arc::new(Mutex::new(files));
+        let have_been_warmed_mutex = Arc::new(Mutex::new(ids.clone()));
+        let tunes_mutex = Arc::new(Mutex::new(ids));
+
+        let (moved, removed) = sweep(
+            &Config::default(),
+            walk(&["/music/readable/a.mp3"], &["/music/unreadable"]),
+            files_mutex.clone(),
+            Arc::new(Mutex::new(Vec::new())),
+            have_been_warmed_mutex.clone(),
+            Arc::new(Mutex::new(Vec::new())),
+            tunes_mutex.clone(),
+        );
+
+        assert_eq!((moved, removed), (0, 0));
+        assert_eq!(files_mutex.lock().unwrap().len(), 3);
+        assert_eq!(have_been_warmed_mutex.lock().unwrap().len(), 3);
+        assert_eq!(tunes_mutex.lock().unwrap().len(), 3);
     }
 
-    // wait for a bit...
-    println!("OK: Waiting for a bit...'");
-    let duration = Duration::new(0, 500_000_000);
-    thread::sleep(duration);
+    #[test]
+    fn replace_library_swaps_in_what_the_database_has() {
+        let config = Config::default();
+        let old = library(&["/music/removed.mp3", "/music/kept.mp3"]);
+        let old_ids: Vec<u32> = old.keys().copied().collect();
+        let files_mutex = Arc::new(Mutex::new(old));
+        let have_been_warmed_mutex = Arc::new(Mutex::new(old_ids.clone()));
+        let mixes_mutex = Arc::new(Mutex::new(Vec::new()));
+        let tunes_mutex = Arc::new(Mutex::new(old_ids));
+
+        let kept = file("/music/kept.mp3");
+        let mut mix = file("/music/mix.mp3");
+        mix.duration = config.mix_threshold + 1;
+        let mut too_long = file("/music/too-long.mp3");
+        too_long.duration = config.max_duration + 1;
+        let mut broken = file("/music/broken.mp3");
+        broken.parse_fail = true;
+
+        replace_library(
+            &config,
+            vec![kept.clone(), mix.clone(), too_long.clone(), broken.clone()],
+            &files_mutex,
+            &have_been_warmed_mutex,
+            &mixes_mutex,
+            &tunes_mutex,
+        );
+
+        let mut ids: Vec<u32> = files_mutex.lock().unwrap().keys().copied().collect();
+        ids.sort_unstable();
+        let mut expected = vec![kept.id, mix.id, too_long.id, broken.id];
+        expected.sort_unstable();
+        assert_eq!(ids, expected);
 
-    return answer;
+        let mut warmed = have_been_warmed_mutex.lock().unwrap().clone();
+        warmed.sort_unstable();
+        let mut expected = vec![kept.id, mix.id];
+        expected.sort_unstable();
+        assert_eq!(warmed, expected);
+
+        assert_eq!(*mixes_mutex.lock().unwrap(), vec![mix.id]);
+        assert_eq!(*tunes_mutex.lock().unwrap(), vec![kept.id]);
+    }
 }
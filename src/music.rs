@@ -1,14 +1,22 @@
 use crate::database::SQLite;
+use crate::error::IndexError;
+use lofty::file::FileType;
 use lofty::prelude::{Accessor, AudioFile, TaggedFileExt};
 use lofty::probe::Probe;
-use lofty::tag::Tag;
+use lofty::tag::{ItemKey, Tag};
 use murmurhash32::murmurhash3;
-use rusqlite::params;
+use rusqlite::{params, Row};
 use serde::{Deserialize, Serialize};
 use std::fs::File as StdFsFile;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
-use uuid::Uuid;
+
+// ReplayGain 2 and most players aim for this, in LUFS
+const TARGET_LOUDNESS: f64 = -18.0;
+
+// The highest a suggested gain may push a file's true peak, in dBTP
+const MAX_PEAK: f64 = -1.0;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct File {
@@ -25,6 +33,40 @@ pub struct File {
     pub indexed_at: u64,
     pub accessed_at: u64,
     pub parse_fail: bool,
+    pub content_hash: u32,
+    pub format: String,
+    pub album_artist: String,
+    pub track_number: u32,
+    pub track_total: u32,
+    pub disc_number: u32,
+    pub disc_total: u32,
+    pub year: u32,
+    pub date: String,
+    pub genre: String,
+    pub composer: String,
+    pub comment: String,
+    pub label: String,
+    pub bpm: u32,
+    pub isrc: String,
+    pub musicbrainz_recording_id: String,
+    pub musicbrainz_track_id: String,
+    pub musicbrainz_release_id: String,
+    pub musicbrainz_release_group_id: String,
+    pub musicbrainz_artist_id: String,
+    pub musicbrainz_album_artist_id: String,
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub bit_depth: u8,
+    pub channels: u8,
+    pub art: String,
+    pub analysed_at: u64,
+    pub loudness: Option<f64>,
+    pub true_peak: Option<f64>,
+    pub loudness_range: Option<f64>,
+    pub replaygain_track_gain: Option<f64>,
+    pub replaygain_track_peak: Option<f64>,
+    pub replaygain_album_gain: Option<f64>,
+    pub replaygain_album_peak: Option<f64>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -35,24 +77,156 @@ pub struct FileHashed {
     pub artist: String,
     pub album: String,
     pub file: String,
+    pub format: String,
+    pub duration: u64,
+    pub album_artist: String,
+    pub track_number: u32,
+    pub track_total: u32,
+    pub disc_number: u32,
+    pub disc_total: u32,
+    pub year: u32,
+    pub date: String,
+    pub genre: String,
+    pub composer: String,
+    pub comment: String,
+    pub label: String,
+    pub bpm: u32,
+    pub isrc: String,
+    pub musicbrainz_recording_id: String,
+    pub musicbrainz_track_id: String,
+    pub musicbrainz_release_id: String,
+    pub musicbrainz_release_group_id: String,
+    pub musicbrainz_artist_id: String,
+    pub musicbrainz_album_artist_id: String,
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub bit_depth: u8,
+    pub channels: u8,
+    pub has_art: bool,
+    pub album_id: String,
+    pub loudness: Option<f64>,
+    pub true_peak: Option<f64>,
+    pub loudness_range: Option<f64>,
+    pub replaygain_track_gain: Option<f64>,
+    pub replaygain_track_peak: Option<f64>,
+    pub replaygain_album_gain: Option<f64>,
+    pub replaygain_album_peak: Option<f64>,
+    pub suggested_gain: Option<f64>,
 }
 
 impl File {
-    pub fn to_response(&mut self) -> FileHashed {
+    // `token` is what the stream routes will accept in place of the path and
+    // `album_token` what /download/album will accept for the album
+    pub fn to_response(&self, token: String, album_token: String) -> FileHashed {
         FileHashed {
-            path: self.get_unique_id(),
+            path: token,
             ext: self.file_ext.clone(),
             title: self.title.clone(),
             artist: self.artist.clone(),
             album: self.album.clone(),
             file: self.file_name.clone(),
+            format: self.format.clone(),
+            duration: self.duration,
+            album_artist: self.album_artist.clone(),
+            track_number: self.track_number,
+            track_total: self.track_total,
+            disc_number: self.disc_number,
+            disc_total: self.disc_total,
+            year: self.year,
+            date: self.date.clone(),
+            genre: self.genre.clone(),
+            composer: self.composer.clone(),
+            comment: self.comment.clone(),
+            label: self.label.clone(),
+            bpm: self.bpm,
+            isrc: self.isrc.clone(),
+            musicbrainz_recording_id: self.musicbrainz_recording_id.clone(),
+            musicbrainz_track_id: self.musicbrainz_track_id.clone(),
+            musicbrainz_release_id: self.musicbrainz_release_id.clone(),
+            musicbrainz_release_group_id: self.musicbrainz_release_group_id.clone(),
+            musicbrainz_artist_id: self.musicbrainz_artist_id.clone(),
+            musicbrainz_album_artist_id: self.musicbrainz_album_artist_id.clone(),
+            bitrate: self.bitrate,
+            sample_rate: self.sample_rate,
+            bit_depth: self.bit_depth,
+            channels: self.channels,
+            has_art: !self.art.is_empty(),
+            album_id: album_token,
+            loudness: self.loudness,
+            true_peak: self.true_peak,
+            loudness_range: self.loudness_range,
+            replaygain_track_gain: self.replaygain_track_gain,
+            replaygain_track_peak: self.replaygain_track_peak,
+            replaygain_album_gain: self.replaygain_album_gain,
+            replaygain_album_peak: self.replaygain_album_peak,
+            suggested_gain: self.suggested_gain(),
         }
     }
 
-    pub fn new_empty_file_from_path(path: &Path) -> File {
+    // Maps a row from `SELECT * FROM files` back into a file struct
+    pub fn from_row(row: &Row) -> rusqlite::Result<File> {
+        Ok(File {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            file_name: row.get(2)?,
+            file_ext: row.get(3)?,
+            file_size: row.get(4)?,
+            file_modified: row.get(5)?,
+            title: row.get(6)?,
+            artist: row.get(7)?,
+            album: row.get(8)?,
+            duration: row.get(9)?,
+            indexed_at: row.get(10)?,
+            accessed_at: row.get(11)?,
+            parse_fail: row.get(12)?,
+            content_hash: row.get(13)?,
+            format: row.get(14)?,
+            album_artist: row.get(15)?,
+            track_number: row.get(16)?,
+            track_total: row.get(17)?,
+            disc_number: row.get(18)?,
+            disc_total: row.get(19)?,
+            year: row.get(20)?,
+            date: row.get(21)?,
+            genre: row.get(22)?,
+            composer: row.get(23)?,
+            comment: row.get(24)?,
+            label: row.get(25)?,
+            bpm: row.get(26)?,
+            isrc: row.get(27)?,
+            musicbrainz_recording_id: row.get(28)?,
+            musicbrainz_track_id: row.get(29)?,
+            musicbrainz_release_id: row.get(30)?,
+            musicbrainz_release_group_id: row.get(31)?,
+            musicbrainz_artist_id: row.get(32)?,
+            musicbrainz_album_artist_id: row.get(33)?,
+            bitrate: row.get(34)?,
+            sample_rate: row.get(35)?,
+            bit_depth: row.get(36)?,
+            channels: row.get(37)?,
+            art: row.get(38)?,
+            analysed_at: row.get(39)?,
+            loudness: row.get(40)?,
+            true_peak: row.get(41)?,
+            loudness_range: row.get(42)?,
+            replaygain_track_gain: row.get(43)?,
+            replaygain_track_peak: row.get(44)?,
+            replaygain_album_gain: row.get(45)?,
+            replaygain_album_peak: row.get(46)?,
+        })
+    }
+
+    pub fn new_empty_file_from_path(path: &Path) -> Result<File, IndexError> {
         println!("Creating a new empty file struct based on path...");
-        let path_string = path.to_str().unwrap().to_string();
-        let file_name = String::from(path.file_name().unwrap().to_string_lossy());
+        // the murmur id is a hash of the path, so it has to be exact
+        let path_string = match path.to_str() {
+            Some(path_string) => path_string.to_string(),
+            None => return Err(IndexError::InvalidPath(path.to_path_buf())),
+        };
+        let file_name = match path.file_name() {
+            Some(file_name) => String::from(file_name.to_string_lossy()),
+            None => return Err(IndexError::InvalidPath(path.to_path_buf())),
+        };
 
         println!("--- File: {} ---", file_name);
 
@@ -61,10 +235,10 @@ impl File {
             None => String::from(""),
         };
 
-        File {
+        Ok(File {
             id: murmurhash3(path_string.as_bytes()),
             path: path_string,
-            file_name: file_name,
+            file_name,
             file_ext: file_ext.clone(),
             file_size: 0,
             file_modified: 0,
@@ -75,7 +249,41 @@ impl File {
             indexed_at: 0,
             accessed_at: 0,
             parse_fail: false,
-        }
+            content_hash: 0,
+            format: "".to_string(),
+            album_artist: "".to_string(),
+            track_number: 0,
+            track_total: 0,
+            disc_number: 0,
+            disc_total: 0,
+            year: 0,
+            date: "".to_string(),
+            genre: "".to_string(),
+            composer: "".to_string(),
+            comment: "".to_string(),
+            label: "".to_string(),
+            bpm: 0,
+            isrc: "".to_string(),
+            musicbrainz_recording_id: "".to_string(),
+            musicbrainz_track_id: "".to_string(),
+            musicbrainz_release_id: "".to_string(),
+            musicbrainz_release_group_id: "".to_string(),
+            musicbrainz_artist_id: "".to_string(),
+            musicbrainz_album_artist_id: "".to_string(),
+            bitrate: 0,
+            sample_rate: 0,
+            bit_depth: 0,
+            channels: 0,
+            art: "".to_string(),
+            analysed_at: 0,
+            loudness: None,
+            true_peak: None,
+            loudness_range: None,
+            replaygain_track_gain: None,
+            replaygain_track_peak: None,
+            replaygain_album_gain: None,
+            replaygain_album_peak: None,
+        })
     }
 
     pub fn insert_into_memory(
@@ -92,9 +300,9 @@ impl File {
     }
 
     // Gets basic file info - no tags
-    pub fn populate_from_path(&mut self) {
+    pub fn populate_from_path(&mut self) -> Result<(), IndexError> {
         println!("Run populate_from_path()...");
-        if self.path == "" {
+        if self.path.is_empty() {
             panic!("Can't populate from path when file struct has no path!");
         }
 
@@ -102,10 +310,11 @@ impl File {
             panic!("Can't populate from path when file struct has no id!");
         }
 
-        let file = StdFsFile::open(&self.path).unwrap();
+        let file = StdFsFile::open(&self.path)?;
+        let metadata = file.metadata()?;
 
         println!("--- System time... ---");
-        let modified_system_time = match file.metadata().unwrap().modified() {
+        let modified_system_time = match metadata.modified() {
             Ok(modified_system_time) => modified_system_time,
             Err(_) => SystemTime::now(),
         };
@@ -113,12 +322,12 @@ impl File {
         println!("--- Modified time... ---");
         let file_mtime = modified_system_time
             .duration_since(UNIX_EPOCH)
-            .unwrap()
+            .unwrap_or_default()
             .as_secs();
 
         println!("--- Populating file struct with basic info (index)... ---");
 
-        self.file_size = file.metadata().unwrap().len();
+        self.file_size = metadata.len();
         self.file_modified = file_mtime;
         self.title = "".to_string();
         self.artist = "".to_string();
@@ -128,13 +337,65 @@ impl File {
         self.accessed_at = 0;
         self.parse_fail = false;
         println!("END populate_from_path()...");
+
+        Ok(())
     }
 
     pub fn save_to_database(&self) {
-        let conn = SQLite::initialize();
+        let conn = SQLite::connect();
 
+        // An upsert rather than INSERT OR REPLACE, so the update trigger keeps
+        // the search index in sync instead of a silent delete and insert
         match conn.execute(
-            "INSERT OR REPLACE INTO files (id, path, file_name, file_ext, file_size, file_modified, title, artist, album, duration, indexed_at, accessed_at, parse_fail) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            "INSERT INTO files (id, path, file_name, file_ext, file_size, file_modified, title, artist, album, duration, indexed_at, accessed_at, parse_fail, content_hash, format, album_artist, track_number, track_total, disc_number, disc_total, year, date, genre, composer, comment, label, bpm, isrc, musicbrainz_recording_id, musicbrainz_track_id, musicbrainz_release_id, musicbrainz_release_group_id, musicbrainz_artist_id, musicbrainz_album_artist_id, bitrate, sample_rate, bit_depth, channels, art, analysed_at, loudness, true_peak, loudness_range, replaygain_track_gain, replaygain_track_peak, replaygain_album_gain, replaygain_album_peak)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32, ?33, ?34, ?35, ?36, ?37, ?38, ?39, ?40, ?41, ?42, ?43, ?44, ?45, ?46, ?47)
+            ON CONFLICT (id) DO UPDATE SET
+                path = excluded.path,
+                file_name = excluded.file_name,
+                file_ext = excluded.file_ext,
+                file_size = excluded.file_size,
+                file_modified = excluded.file_modified,
+                title = excluded.title,
+                artist = excluded.artist,
+                album = excluded.album,
+                duration = excluded.duration,
+                indexed_at = excluded.indexed_at,
+                accessed_at = excluded.accessed_at,
+                parse_fail = excluded.parse_fail,
+                content_hash = excluded.content_hash,
+                format = excluded.format,
+                album_artist = excluded.album_artist,
+                track_number = excluded.track_number,
+                track_total = excluded.track_total,
+                disc_number = excluded.disc_number,
+                disc_total = excluded.disc_total,
+                year = excluded.year,
+                date = excluded.date,
+                genre = excluded.genre,
+                composer = excluded.composer,
+                comment = excluded.comment,
+                label = excluded.label,
+                bpm = excluded.bpm,
+                isrc = excluded.isrc,
+                musicbrainz_recording_id = excluded.musicbrainz_recording_id,
+                musicbrainz_track_id = excluded.musicbrainz_track_id,
+                musicbrainz_release_id = excluded.musicbrainz_release_id,
+                musicbrainz_release_group_id = excluded.musicbrainz_release_group_id,
+                musicbrainz_artist_id = excluded.musicbrainz_artist_id,
+                musicbrainz_album_artist_id = excluded.musicbrainz_album_artist_id,
+                bitrate = excluded.bitrate,
+                sample_rate = excluded.sample_rate,
+                bit_depth = excluded.bit_depth,
+                channels = excluded.channels,
+                art = excluded.art,
+                analysed_at = excluded.analysed_at,
+                loudness = excluded.loudness,
+                true_peak = excluded.true_peak,
+                loudness_range = excluded.loudness_range,
+                replaygain_track_gain = excluded.replaygain_track_gain,
+                replaygain_track_peak = excluded.replaygain_track_peak,
+                replaygain_album_gain = excluded.replaygain_album_gain,
+                replaygain_album_peak = excluded.replaygain_album_peak",
             params![
                 self.id,
                 self.path,
@@ -149,43 +410,98 @@ impl File {
                 self.indexed_at,
                 self.accessed_at,
                 self.parse_fail,
+                self.content_hash,
+                self.format,
+                self.album_artist,
+                self.track_number,
+                self.track_total,
+                self.disc_number,
+                self.disc_total,
+                self.year,
+                self.date,
+                self.genre,
+                self.composer,
+                self.comment,
+                self.label,
+                self.bpm,
+                self.isrc,
+                self.musicbrainz_recording_id,
+                self.musicbrainz_track_id,
+                self.musicbrainz_release_id,
+                self.musicbrainz_release_group_id,
+                self.musicbrainz_artist_id,
+                self.musicbrainz_album_artist_id,
+                self.bitrate,
+                self.sample_rate,
+                self.bit_depth,
+                self.channels,
+                self.art,
+                self.analysed_at,
+                self.loudness,
+                self.true_peak,
+                self.loudness_range,
+                self.replaygain_track_gain,
+                self.replaygain_track_peak,
+                self.replaygain_album_gain,
+                self.replaygain_album_peak,
             ],
         ) {
             Ok(_) => println!("Inserting into files..."),
             Err(err) => println!("Update failed (files): {}", err),
         }
+    }
 
-        match conn.execute(
-            "INSERT INTO search (path, file_name, file_ext, title, artist, album) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![
-                self.path,
-                self.file_name,
-                self.file_ext,
-                self.title,
-                self.artist,
-                self.album,
-            ],
-        ) {
-            Ok(_) => println!("Inserting into search..."),
-            Err(err) => println!("Update failed (search): {}", err),
+    pub fn delete_from_database(id: u32) {
+        let conn = SQLite::connect();
+
+        match conn.execute("DELETE FROM files WHERE id = ?1", params![id]) {
+            Ok(_) => println!("Deleting from files..."),
+            Err(err) => println!("Delete failed (files): {}", err),
+        }
+    }
+
+    // Murmur of the size plus the first and last 64KiB. Cheap enough to run on
+    // every warm and good enough to tell a moved file from a different one.
+    pub fn compute_content_hash(&self) -> std::io::Result<u32> {
+        let sample_size: u64 = 65536;
+        let mut file = StdFsFile::open(&self.path)?;
+        let size = file.metadata()?.len();
+
+        let mut buffer = size.to_le_bytes().to_vec();
+
+        let mut head = Vec::new();
+        file.by_ref().take(sample_size).read_to_end(&mut head)?;
+        buffer.extend(head);
+
+        if size > sample_size {
+            let mut tail = Vec::new();
+            file.seek(SeekFrom::Start(sample_size.max(size - sample_size)))?;
+            file.read_to_end(&mut tail)?;
+            buffer.extend(tail);
         }
+
+        Ok(murmurhash3(&buffer))
     }
 
-    pub fn populate_lofty(&mut self) {
+    // Marks the file as a parse fail when lofty can't read it, so it isn't retried.
+    // The format comes from the file's content, the extension is only a fallback.
+    pub fn populate_lofty(&mut self) -> Result<(), IndexError> {
         let path: &Path = Path::new(&self.path);
-        let potentially_tagged_file =
-            match Probe::open(path).expect("ERROR: Bad path provided!").read() {
-                Ok(file) => file,
-                Err(error) => {
-                    self.parse_fail = true;
-                    println!(
-                        "Error: Can't parse file `{}`. Error: {}",
-                        self.path,
-                        error.to_string()
-                    );
-                    return;
-                }
-            };
+        let probed = Probe::open(path).and_then(|probe| Ok(probe.guess_file_type()?));
+        let potentially_tagged_file = match probed.and_then(|probe| {
+            if let Some(file_type) = probe.file_type() {
+                self.format = format_name(file_type).to_string();
+                println!("Format: {}", self.format);
+            }
+            probe.read()
+        }) {
+            Ok(file) => file,
+            Err(error) => {
+                self.parse_fail = true;
+                println!("Error: Can't parse file `{}`. Error: {}", self.path, error);
+                return Err(IndexError::from(error));
+            }
+        };
 
         let properties = potentially_tagged_file.properties();
 
@@ -194,17 +510,117 @@ impl File {
         self.duration = duration.as_secs();
         println!("Duration (s): {}", self.duration);
 
+        // Audio properties, any the format doesn't report are left at 0
+        self.bitrate = properties
+            .audio_bitrate()
+            .or(properties.overall_bitrate())
+            .unwrap_or(0);
+        self.sample_rate = properties.sample_rate().unwrap_or(0);
+        self.bit_depth = properties.bit_depth().unwrap_or(0);
+        self.channels = properties.channels().unwrap_or(0);
+        println!(
+            "Bitrate (kbps): {}, sample rate (Hz): {}, bit depth: {}, channels: {}",
+            self.bitrate, self.sample_rate, self.bit_depth, self.channels
+        );
+
         // Try to get the tag info
         match potentially_tagged_file.primary_tag() {
             Some(primary_tag) => self.fill_tags(primary_tag),
             // If the "primary" tag doesn't exist, we just grab the
             // first tag we can find. Realistically, a tag reader would likely
             // iterate through the tags to find a suitable one.
-            None => match potentially_tagged_file.first_tag() {
-                Some(next_tag) => self.fill_tags(next_tag),
-                None => (),
-            },
+            None => {
+                if let Some(next_tag) = potentially_tagged_file.first_tag() {
+                    self.fill_tags(next_tag)
+                }
+            }
         };
+
+        Ok(())
+    }
+
+    // How many dB to add so every file plays at about the same loudness. The
+    // measured loudness wins over ReplayGain tags, and nothing is boosted so
+    // far that it would clip.
+    pub fn suggested_gain(&self) -> Option<f64> {
+        let (gain, peak) = match self.loudness {
+            Some(loudness) => (TARGET_LOUDNESS - loudness, self.true_peak),
+            None => (
+                self.replaygain_track_gain?,
+                self.replaygain_track_peak
+                    .filter(|peak| *peak > 0.0)
+                    .map(|peak| 20.0 * peak.log10()),
+            ),
+        };
+
+        let gain = match peak {
+            Some(peak) => gain.min(MAX_PEAK - peak),
+            None => gain,
+        };
+
+        Some((gain * 100.0).round() / 100.0)
+    }
+
+    // What to send as the Content-Type, the detected format wins over the extension
+    pub fn mime_type(&self) -> String {
+        let mime = match self.format.as_str() {
+            "aac" => "audio/aac",
+            "aiff" => "audio/aiff",
+            "ape" => "audio/ape",
+            "flac" => "audio/flac",
+            "mp4" => "audio/mp4",
+            "mpc" => "audio/musepack",
+            "mpeg" => "audio/mpeg",
+            "opus" | "speex" | "vorbis" => "audio/ogg",
+            "wav" => "audio/wav",
+            "wavpack" => "audio/wavpack",
+            _ => {
+                return mime_guess::from_ext(&self.file_ext)
+                    .first_or_octet_stream()
+                    .essence_str()
+                    .to_string()
+            }
+        };
+
+        mime.to_string()
+    }
+
+    // "Artist - Title", falling back to the file name when the tags are missing
+    pub fn display_title(&self) -> String {
+        match (self.artist.is_empty(), self.title.is_empty()) {
+            (false, false) => format!("{} - {}", self.artist, self.title),
+            (true, false) => self.title.clone(),
+            _ => self.file_name.clone(),
+        }
+    }
+
+    // The album artist when there is one, so compilations stay together
+    pub fn album_artist_name(&self) -> String {
+        match (self.album_artist.is_empty(), self.artist.is_empty()) {
+            (false, _) => self.album_artist.clone(),
+            (true, false) => self.artist.clone(),
+            _ => "[Unknown Artist]".to_string(),
+        }
+    }
+
+    // Untagged files are grouped by the folder they're in
+    pub fn album_name(&self) -> String {
+        if !self.album.is_empty() {
+            return self.album.clone();
+        }
+
+        Path::new(&self.path)
+            .parent()
+            .and_then(|parent| parent.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "[Unknown Album]".to_string())
+    }
+
+    // A hash of the album artist and album names, so it survives a restart
+    // and a rescan. Used by the Subsonic API, and signed by /download/album.
+    pub fn album_id(&self) -> String {
+        let key = format!("{}\u{1f}{}", self.album_artist_name(), self.album_name()).to_lowercase();
+        format!("al-{:08x}", murmurhash3(key.as_bytes()))
     }
 
     pub fn fill_tags(&mut self, tag: &Tag) {
@@ -215,19 +631,168 @@ impl File {
         self.title = tag.title().as_deref().unwrap_or("").to_string();
         self.artist = tag.artist().as_deref().unwrap_or("").to_string();
         self.album = tag.album().as_deref().unwrap_or("").to_string();
+
+        self.album_artist = tag_string(tag, &ItemKey::AlbumArtist);
+        self.track_number = tag.track().unwrap_or(0);
+        self.track_total = tag.track_total().unwrap_or(0);
+        self.disc_number = tag.disk().unwrap_or(0);
+        self.disc_total = tag.disk_total().unwrap_or(0);
+        self.year = tag.year().unwrap_or(0);
+        self.date = match tag.get_string(&ItemKey::RecordingDate) {
+            Some(date) => date.trim().to_string(),
+            None => tag_string(tag, &ItemKey::Year),
+        };
+        self.genre = tag.genre().as_deref().unwrap_or("").trim().to_string();
+        self.composer = tag_string(tag, &ItemKey::Composer);
+        self.comment = tag.comment().as_deref().unwrap_or("").trim().to_string();
+        self.label = tag_string(tag, &ItemKey::Label);
+        self.bpm = tag_bpm(tag);
+        self.replaygain_track_gain = tag_number(tag, &ItemKey::ReplayGainTrackGain);
+        self.replaygain_track_peak = tag_number(tag, &ItemKey::ReplayGainTrackPeak);
+        self.replaygain_album_gain = tag_number(tag, &ItemKey::ReplayGainAlbumGain);
+        self.replaygain_album_peak = tag_number(tag, &ItemKey::ReplayGainAlbumPeak);
+        self.isrc = tag_string(tag, &ItemKey::Isrc);
+        self.musicbrainz_recording_id = tag_string(tag, &ItemKey::MusicBrainzRecordingId);
+        self.musicbrainz_track_id = tag_string(tag, &ItemKey::MusicBrainzTrackId);
+        self.musicbrainz_release_id = tag_string(tag, &ItemKey::MusicBrainzReleaseId);
+        self.musicbrainz_release_group_id = tag_string(tag, &ItemKey::MusicBrainzReleaseGroupId);
+        self.musicbrainz_artist_id = tag_string(tag, &ItemKey::MusicBrainzArtistId);
+        self.musicbrainz_album_artist_id = tag_string(tag, &ItemKey::MusicBrainzReleaseArtistId);
+
+        println!("Album artist: {}", self.album_artist);
+        println!(
+            "Track: {}/{}, disc: {}/{}",
+            self.track_number, self.track_total, self.disc_number, self.disc_total
+        );
+        println!("Year: {}, genre: {}", self.year, self.genre);
     }
+}
 
-    pub fn get_unique_id(&mut self) -> String {
-        let uuid = Uuid::new_v4().to_string();
+fn tag_string(tag: &Tag, key: &ItemKey) -> String {
+    tag.get_string(key).unwrap_or("").trim().to_string()
+}
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+// ReplayGain is written as e.g "-6.54 dB" for gains and "0.988547" for peaks
+fn tag_number(tag: &Tag, key: &ItemKey) -> Option<f64> {
+    let value = tag.get_string(key)?.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite())
+}
+
+// BPM is sometimes written with decimals (e.g "127.5"), it's rounded to the
+// nearest beat
+fn tag_bpm(tag: &Tag) -> u32 {
+    tag.get_string(&ItemKey::IntegerBpm)
+        .or_else(|| tag.get_string(&ItemKey::Bpm))
+        .and_then(|bpm| bpm.trim().parse::<f64>().ok())
+        .filter(|bpm| bpm.is_finite() && *bpm > 0.0)
+        .map_or(0, |bpm| bpm.round() as u32)
+}
 
-        self.accessed_at = now;
-        self.path = uuid.clone();
+// A stable lowercase name for the database, lofty's Debug output isn't one
+fn format_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Aac => "aac",
+        FileType::Aiff => "aiff",
+        FileType::Ape => "ape",
+        FileType::Flac => "flac",
+        FileType::Mpeg => "mpeg",
+        FileType::Mp4 => "mp4",
+        FileType::Mpc => "mpc",
+        FileType::Opus => "opus",
+        FileType::Vorbis => "vorbis",
+        FileType::Speex => "speex",
+        FileType::Wav => "wav",
+        FileType::WavPack => "wavpack",
+        FileType::Custom(name) => name,
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(
+        loudness: Option<f64>,
+        true_peak: Option<f64>,
+        replaygain_track_gain: Option<f64>,
+        replaygain_track_peak: Option<f64>,
+    ) -> File {
+        let mut file = File::new_empty_file_from_path(Path::new("/music/a.flac")).unwrap();
+        file.loudness = loudness;
+        file.true_peak = true_peak;
+        file.replaygain_track_gain = replaygain_track_gain;
+        file.replaygain_track_peak = replaygain_track_peak;
+        file
+    }
+
+    #[test]
+    fn suggested_gain_reaches_the_target() {
+        assert_eq!(
+            file(Some(-23.0), Some(-10.0), None, None).suggested_gain(),
+            Some(5.0)
+        );
+        assert_eq!(
+            file(Some(-10.0), Some(-0.1), None, None).suggested_gain(),
+            Some(-8.0)
+        );
+        assert_eq!(
+            file(Some(-23.456), None, None, None).suggested_gain(),
+            Some(5.46)
+        );
+    }
+
+    #[test]
+    fn suggested_gain_never_clips() {
+        // only 2 dB of headroom below -1 dBTP
+        assert_eq!(
+            file(Some(-30.0), Some(-3.0), None, None).suggested_gain(),
+            Some(2.0)
+        );
+        // already over the ceiling, so it's turned down even though it's quiet
+        assert_eq!(
+            file(Some(-20.0), Some(0.5), None, None).suggested_gain(),
+            Some(-1.5)
+        );
+    }
+
+    #[test]
+    fn suggested_gain_falls_back_to_replaygain() {
+        // measured loudness wins
+        assert_eq!(
+            file(Some(-23.0), Some(-10.0), Some(-9.0), Some(0.5)).suggested_gain(),
+            Some(5.0)
+        );
+        assert_eq!(
+            file(None, None, Some(-6.5), Some(0.9)).suggested_gain(),
+            Some(-6.5)
+        );
+        // a peak of 0.5 is -6.02 dBFS, 5.02 dB from the ceiling
+        assert_eq!(
+            file(None, None, Some(8.0), Some(0.5)).suggested_gain(),
+            Some(5.02)
+        );
+        // a peak of zero isn't a peak
+        assert_eq!(
+            file(None, None, Some(8.0), Some(0.0)).suggested_gain(),
+            Some(8.0)
+        );
+    }
 
-        uuid
+    #[test]
+    fn no_suggested_gain_without_a_measurement() {
+        assert_eq!(
+            file(None, Some(-3.0), None, Some(0.5)).suggested_gain(),
+            None
+        );
     }
 }
@@ -0,0 +1,223 @@
+// Playlists any media player can open, every entry is a /stream URL with a
+// freshly issued play token
+
+pub struct Entry {
+    pub url: String,
+    // "Artist - Title" for formats with a single line per entry
+    pub name: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    // seconds
+    pub duration: u64,
+}
+
+pub struct Format {
+    pub extension: &'static str,
+    pub mime: &'static str,
+    pub render: fn(&[Entry]) -> String,
+}
+
+pub const FORMATS: &[Format] = &[
+    Format {
+        extension: "m3u8",
+        mime: "audio/x-mpegurl; charset=utf-8",
+        render: m3u8,
+    },
+    Format {
+        extension: "m3u",
+        mime: "audio/x-mpegurl; charset=utf-8",
+        render: m3u8,
+    },
+    Format {
+        extension: "pls",
+        mime: "audio/x-scpls; charset=utf-8",
+        render: pls,
+    },
+    Format {
+        extension: "xspf",
+        mime: "application/xspf+xml; charset=utf-8",
+        render: xspf,
+    },
+];
+
+pub fn find_format(extension: &str) -> Option<&'static Format> {
+    FORMATS
+        .iter()
+        .find(|format| format.extension.eq_ignore_ascii_case(extension))
+}
+
+// https://en.wikipedia.org/wiki/M3U#Extended_M3U
+fn m3u8(entries: &[Entry]) -> String {
+    let mut playlist = String::from("#EXTM3U\n");
+
+    for entry in entries {
+        playlist.push_str(&format!(
+            "#EXTINF:{},{}\n{}\n",
+            entry.duration,
+            single_line(&entry.name),
+            entry.url
+        ));
+    }
+
+    playlist
+}
+
+// https://en.wikipedia.org/wiki/PLS_(file_format)
+fn pls(entries: &[Entry]) -> String {
+    let mut playlist = String::from("[playlist]\n");
+
+    for (number, entry) in entries.iter().enumerate() {
+        let number = number + 1;
+        playlist.push_str(&format!(
+            "File{0}={1}\nTitle{0}={2}\nLength{0}={3}\n",
+            number,
+            entry.url,
+            single_line(&entry.name),
+            entry.duration
+        ));
+    }
+
+    playlist.push_str(&format!("NumberOfEntries={}\nVersion=2\n", entries.len()));
+
+    playlist
+}
+
+// https://www.xspf.org/spec
+fn xspf(entries: &[Entry]) -> String {
+    let mut playlist = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n",
+    );
+
+    for entry in entries {
+        playlist.push_str("    <track>\n");
+        playlist.push_str(&format!(
+            "      <location>{}</location>\n",
+            escape_xml(&entry.url)
+        ));
+        playlist.push_str(&format!(
+            "      <title>{}</title>\n",
+            escape_xml(&entry.title)
+        ));
+        if !entry.artist.is_empty() {
+            playlist.push_str(&format!(
+                "      <creator>{}</creator>\n",
+                escape_xml(&entry.artist)
+            ));
+        }
+        if !entry.album.is_empty() {
+            playlist.push_str(&format!(
+                "      <album>{}</album>\n",
+                escape_xml(&entry.album)
+            ));
+        }
+        // in milliseconds
+        playlist.push_str(&format!(
+            "      <duration>{}</duration>\n",
+            entry.duration * 1000
+        ));
+        playlist.push_str("    </track>\n");
+    }
+
+    playlist.push_str("  </trackList>\n</playlist>\n");
+
+    playlist
+}
+
+// A title with a line break in it would start a new entry in M3U and PLS
+fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry {
+                url: "https://example.com/stream/a?format=mp3&bitrate=128".to_string(),
+                name: "Simon & <Garfunkel> - Line\r\nbreak".to_string(),
+                title: "Line\nbreak \"quoted\" 'too'".to_string(),
+                artist: "Simon & <Garfunkel>".to_string(),
+                album: "".to_string(),
+                duration: 61,
+            },
+            Entry {
+                url: "https://example.com/stream/b".to_string(),
+                name: "Second".to_string(),
+                title: "Second".to_string(),
+                artist: "".to_string(),
+                album: "Album".to_string(),
+                duration: 0,
+            },
+        ]
+    }
+
+    #[test]
+    fn m3u8_keeps_every_entry_on_two_lines() {
+        assert_eq!(
+            m3u8(&entries()),
+            "#EXTM3U\n\
+             #EXTINF:61,Simon & <Garfunkel> - Line  break\n\
+             https://example.com/stream/a?format=mp3&bitrate=128\n\
+             #EXTINF:0,Second\n\
+             https://example.com/stream/b\n"
+        );
+    }
+
+    #[test]
+    fn pls_numbers_entries_from_one() {
+        assert_eq!(
+            pls(&entries()),
+            "[playlist]\n\
+             File1=https://example.com/stream/a?format=mp3&bitrate=128\n\
+             Title1=Simon & <Garfunkel> - Line  break\n\
+             Length1=61\n\
+             File2=https://example.com/stream/b\n\
+             Title2=Second\n\
+             Length2=0\n\
+             NumberOfEntries=2\n\
+             Version=2\n"
+        );
+        assert_eq!(pls(&[]), "[playlist]\nNumberOfEntries=0\nVersion=2\n");
+    }
+
+    #[test]
+    fn xspf_escapes_tags() {
+        assert_eq!(
+            xspf(&entries()),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n    <track>\n      \
+             <location>https://example.com/stream/a?format=mp3&amp;bitrate=128</location>\n      \
+             <title>Line\nbreak &quot;quoted&quot; &apos;too&apos;</title>\n      \
+             <creator>Simon &amp; &lt;Garfunkel&gt;</creator>\n      \
+             <duration>61000</duration>\n    </track>\n    <track>\n      \
+             <location>https://example.com/stream/b</location>\n      \
+             <title>Second</title>\n      \
+             <album>Album</album>\n      \
+             <duration>0</duration>\n    </track>\n  </trackList>\n</playlist>\n"
+        );
+    }
+
+    #[test]
+    fn formats_are_found_ignoring_case() {
+        assert_eq!(
+            find_format("M3U8").map(|format| format.extension),
+            Some("m3u8")
+        );
+        assert_eq!(
+            find_format("xspf").map(|format| format.mime),
+            Some("application/xspf+xml; charset=utf-8")
+        );
+        assert!(find_format("txt").is_none());
+    }
+}
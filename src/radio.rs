@@ -0,0 +1,721 @@
+use crate::config::Config;
+use crate::music::File;
+use crate::transcode::{Jobs, Joined, Resample, Target, Transcode};
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::broadcast;
+
+// How much audio goes out at once, in seconds
+const CHUNK_SECONDS: f64 = 0.5;
+
+// New listeners are sent the last few chunks straight away so their player
+// can start without waiting for its buffer to fill in real time
+const BURST_CHUNKS: usize = 8;
+
+// How far behind a listener can fall before it skips ahead
+const CHANNEL_CHUNKS: usize = 32;
+
+// How many bytes of audio go between ICY metadata blocks
+pub const ICY_METAINT: usize = 16000;
+
+pub const MODES: &[&str] = &["all", "tunes", "mixes"];
+
+// A piece of the station's output and what was playing when it went out
+#[derive(Clone)]
+pub struct Chunk {
+    pub audio: Arc<Vec<u8>>,
+    pub title: Arc<String>,
+}
+
+struct Station {
+    sender: broadcast::Sender<Chunk>,
+    burst: Mutex<VecDeque<Chunk>>,
+}
+
+impl Station {
+    fn new() -> Station {
+        let (sender, _) = broadcast::channel(CHANNEL_CHUNKS);
+
+        Station {
+            sender,
+            burst: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Returns false once nobody is listening
+    fn publish(&self, chunk: Chunk) -> bool {
+        let mut burst = self.burst.lock().unwrap();
+        burst.push_back(chunk.clone());
+        if burst.len() > BURST_CHUNKS {
+            burst.pop_front();
+        }
+
+        self.sender.send(chunk).is_ok()
+    }
+}
+
+pub struct Listener {
+    pub burst: Vec<Chunk>,
+    pub receiver: broadcast::Receiver<Chunk>,
+}
+
+// Why a file didn't go out
+enum Failure {
+    // it never will, so the station stops picking it
+    Unplayable(String),
+    // `max_jobs` transcodes are running, it may work later
+    Busy,
+}
+
+// One station per mode, every listener of a mode hears the same thing at the
+// same time. A station starts with its first listener and stops when its last
+// one leaves.
+pub struct Radio {
+    config: Config,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    all_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+    jobs: Arc<Jobs>,
+    // stations run on their own threads but follow transcodes like any
+    // other listener
+    runtime: Handle,
+    stations: Mutex<HashMap<String, Arc<Station>>>,
+}
+
+impl Radio {
+    pub fn new(
+        config: Config,
+        files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+        all_mutex: Arc<Mutex<Vec<u32>>>,
+        mixes_mutex: Arc<Mutex<Vec<u32>>>,
+        tunes_mutex: Arc<Mutex<Vec<u32>>>,
+        jobs: Arc<Jobs>,
+    ) -> Radio {
+        Radio {
+            config,
+            files_mutex,
+            all_mutex,
+            mixes_mutex,
+            tunes_mutex,
+            jobs,
+            runtime: Handle::current(),
+            stations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn tune_in(self: &Arc<Radio>, mode: &str) -> Listener {
+        println!("Locking stations (tune_in)...");
+        let mut stations = self.stations.lock().unwrap();
+
+        let station = stations
+            .entry(mode.to_string())
+            .or_insert_with(|| {
+                println!("Starting the `{}` station...", mode);
+                let station = Arc::new(Station::new());
+                let radio = Arc::clone(self);
+                let mode = mode.to_string();
+                let broadcasting = Arc::clone(&station);
+                thread::spawn(move || radio.broadcast(&mode, broadcasting));
+                station
+            })
+            .clone();
+
+        // subscribing while the burst is locked means no chunk is missed or
+        // sent twice
+        let burst = station.burst.lock().unwrap();
+        let listener = Listener {
+            burst: burst.iter().cloned().collect(),
+            receiver: station.sender.subscribe(),
+        };
+        drop(burst);
+
+        println!("Unlocking stations (tune_in)...");
+        drop(stations);
+
+        listener
+    }
+
+    fn broadcast(&self, mode: &str, station: Arc<Station>) {
+        let mut clock = Clock::new();
+        // files that couldn't be played, left out for as long as the station
+        // is on air
+        let mut failed: HashSet<u32> = HashSet::new();
+
+        loop {
+            // checked under the lock so nobody can tune in to a station that
+            // is about to stop
+            let mut stations = self.stations.lock().unwrap();
+            if station.sender.receiver_count() == 0 {
+                println!("Stopping the `{}` station, nobody is listening...", mode);
+                stations.remove(mode);
+                return;
+            }
+            drop(stations);
+
+            let file = match self.pick(mode, &failed) {
+                Some(file) => file,
+                None => {
+                    println!("Nothing to play on the `{}` station (yet...)", mode);
+                    thread::sleep(Duration::from_secs(5));
+                    continue;
+                }
+            };
+
+            println!("Now playing on `{}`: `{}`", mode, file.path);
+
+            let title = Arc::new(file.display_title());
+
+            match self.play(&file, title, &station, &mut clock) {
+                Ok(()) => continue,
+                Err(Failure::Unplayable(err)) => {
+                    println!("Could not play `{}`: {}", file.path, err);
+                    failed.insert(file.id);
+                }
+                Err(Failure::Busy) => {
+                    println!("Too many transcodes to play `{}`...", file.path);
+                }
+            }
+
+            // a run of files that fail straight away mustn't spin
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    // A random file the station can play that hasn't failed
+    fn pick(&self, mode: &str, failed: &HashSet<u32>) -> Option<File> {
+        let selection_mutex = crate::mode_selection(
+            Arc::clone(&self.all_mutex),
+            Arc::clone(&self.mixes_mutex),
+            Arc::clone(&self.tunes_mutex),
+            mode,
+        );
+        let encoder = Target::find(&self.config, "mp3");
+
+        println!("Locking files (pick)...");
+        let files = self.files_mutex.lock().unwrap();
+        let selection = selection_mutex.lock().unwrap();
+        let playable: Vec<&File> = selection
+            .iter()
+            .filter(|id| !failed.contains(id))
+            .filter_map(|id| files.get(id))
+            .filter(|file| playable(file, &self.config, encoder.as_ref()))
+            .collect();
+        let file = playable
+            .choose(&mut rand::thread_rng())
+            .map(|file| (*file).clone());
+        drop(selection);
+        println!("Unlocking files (pick)...");
+        drop(files);
+
+        file
+    }
+
+    // MP3s in the station's format are sent as they are, everything else
+    // goes through the mp3 encoder from the [transcode] section, sharing its
+    // cache and running transcodes with /stream
+    fn play(
+        &self,
+        file: &File,
+        title: Arc<String>,
+        station: &Station,
+        clock: &mut Clock,
+    ) -> Result<(), Failure> {
+        let matches = in_station_format(file, &self.config);
+
+        if let Some(byte_rate) = mp3_byte_rate(file).filter(|_| matches) {
+            return play_mp3(&file.path, byte_rate, title, station, clock)
+                .map_err(Failure::Unplayable);
+        }
+
+        let target = match Target::find(&self.config, "mp3") {
+            Some(target) => target,
+            None => {
+                return Err(Failure::Unplayable(
+                    "no mp3 encoder is configured".to_string(),
+                ))
+            }
+        };
+
+        let bitrate = target.bitrate(Some(self.config.radio_bitrate));
+        let byte_rate = f64::from(bitrate) * 125.0;
+        let resample = if matches {
+            None
+        } else {
+            Some(station_format(&self.config))
+        };
+
+        let (output, keep) = target.output_path(&self.config, file, bitrate, resample);
+        let cached = || keep && output.exists();
+
+        if cached() {
+            let path = output.to_string_lossy().to_string();
+            return play_mp3(&path, byte_rate, title, station, clock).map_err(Failure::Unplayable);
+        }
+
+        let mut follower = match self.jobs.join(output.clone(), keep) {
+            Ok(Joined::Lead(lead, follower)) => {
+                // it may have finished between looking and joining
+                if cached() {
+                    drop(lead);
+                    let path = output.to_string_lossy().to_string();
+                    return play_mp3(&path, byte_rate, title, station, clock)
+                        .map_err(Failure::Unplayable);
+                }
+
+                // dropping the lead when this fails ends the job
+                let transcode =
+                    Transcode::start(&file.path, &file.file_ext, &target, bitrate, resample)
+                        .map_err(|err| Failure::Unplayable(err.to_string()))?;
+                thread::spawn(move || lead.run(transcode));
+                follower
+            }
+            Ok(Joined::Follow(follower)) => follower,
+            Ok(Joined::Busy) => return Err(Failure::Busy),
+            Err(err) => return Err(Failure::Unplayable(err.to_string())),
+        };
+
+        let mut chunker = Chunker::new(byte_rate, title);
+
+        // dropping the follower stops the encoder, unless someone else is
+        // following it too
+        while let Some(encoded) = self.runtime.block_on(follower.next()) {
+            let encoded = encoded.map_err(|err| Failure::Unplayable(err.to_string()))?;
+            for chunk in chunker.push(&encoded) {
+                if !send(station, chunk, byte_rate, clock) {
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(chunk) = chunker.finish() {
+            send(station, chunk, byte_rate, clock);
+        }
+
+        Ok(())
+    }
+}
+
+// What every file on the station is sent as. MP3 decoders tend to give up
+// when the sample rate or channels change in the middle of a stream.
+fn station_format(config: &Config) -> Resample {
+    Resample {
+        sample_rate: config.radio_sample_rate,
+        channels: usize::from(config.radio_channels),
+    }
+}
+
+fn in_station_format(file: &File, config: &Config) -> bool {
+    file.sample_rate == config.radio_sample_rate && file.channels == config.radio_channels
+}
+
+// How fast an MP3 that can be sent as it is goes out, None for anything else
+fn mp3_byte_rate(file: &File) -> Option<f64> {
+    if file.format != "mpeg" || !file.file_ext.eq_ignore_ascii_case("mp3") {
+        return None;
+    }
+
+    match (file.duration, file.bitrate) {
+        (_, bitrate) if bitrate > 0 => Some(f64::from(bitrate) * 125.0),
+        (duration, _) if duration > 0 => Some(file.file_size as f64 / duration as f64),
+        _ => None,
+    }
+}
+
+// MP3s already in the station's format, and anything the encoder can bring
+// to it. Encoders that can't resample only get files already in it.
+fn playable(file: &File, config: &Config, encoder: Option<&Target>) -> bool {
+    let matches = in_station_format(file, config);
+
+    if matches && mp3_byte_rate(file).is_some() {
+        return true;
+    }
+
+    encoder.is_some_and(|encoder| matches || encoder.resamples())
+}
+
+fn play_mp3(
+    path: &str,
+    byte_rate: f64,
+    title: Arc<String>,
+    station: &Station,
+    clock: &mut Clock,
+) -> Result<(), String> {
+    let mut source = fs::File::open(path).map_err(|err| err.to_string())?;
+    let (start, end) = audio_bounds(&mut source).map_err(|err| err.to_string())?;
+    source
+        .seek(SeekFrom::Start(start))
+        .map_err(|err| err.to_string())?;
+
+    let mut remaining = end - start;
+    let chunk_size = ((byte_rate * CHUNK_SECONDS) as usize).max(1);
+
+    while remaining > 0 {
+        let mut audio = vec![0; chunk_size.min(remaining as usize)];
+        source
+            .read_exact(&mut audio)
+            .map_err(|err| err.to_string())?;
+        remaining -= audio.len() as u64;
+
+        let chunk = Chunk {
+            audio: Arc::new(audio),
+            title: Arc::clone(&title),
+        };
+
+        if !send(station, chunk, byte_rate, clock) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Where the MPEG frames start and end, leaving out ID3v2 and ID3v1 tags so a
+// listener never gets a tag in the middle of the stream
+fn audio_bounds(source: &mut fs::File) -> std::io::Result<(u64, u64)> {
+    let length = source.metadata()?.len();
+    let mut start = 0;
+    let mut end = length;
+
+    let mut header = [0u8; 10];
+    if length >= 10 {
+        source.read_exact(&mut header)?;
+        if &header[0..3] == b"ID3" {
+            // the size is syncsafe, 7 bits per byte
+            let size = header[6..10]
+                .iter()
+                .fold(0u64, |size, byte| (size << 7) | u64::from(byte & 0x7f));
+            let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+            start = (10 + size + footer).min(length);
+        }
+    }
+
+    if end - start >= 128 {
+        let mut tag = [0u8; 3];
+        source.seek(SeekFrom::Start(end - 128))?;
+        source.read_exact(&mut tag)?;
+        if &tag == b"TAG" {
+            end -= 128;
+        }
+    }
+
+    Ok((start, end))
+}
+
+// Holds the station to real time, returns false once nobody is listening
+fn send(station: &Station, chunk: Chunk, byte_rate: f64, clock: &mut Clock) -> bool {
+    let seconds = chunk.audio.len() as f64 / byte_rate;
+    clock.wait();
+    clock.advance(seconds);
+    station.publish(chunk)
+}
+
+// How much audio has been sent against how long the station has been on air
+struct Clock {
+    started: Instant,
+    sent: Duration,
+}
+
+impl Clock {
+    fn new() -> Clock {
+        Clock {
+            started: Instant::now(),
+            sent: Duration::ZERO,
+        }
+    }
+
+    fn wait(&mut self) {
+        let due = self.started + self.sent;
+        let now = Instant::now();
+
+        if due > now {
+            thread::sleep(due - now);
+        } else if now - due > Duration::from_secs(5) {
+            // a slow encoder left the station behind, catching up would
+            // flood every listener
+            self.started = now - self.sent;
+        }
+    }
+
+    fn advance(&mut self, seconds: f64) {
+        self.sent += Duration::from_secs_f64(seconds);
+    }
+}
+
+// Cuts encoder output, which comes in whatever sizes it likes, into chunks
+struct Chunker {
+    size: usize,
+    title: Arc<String>,
+    pending: Vec<u8>,
+}
+
+impl Chunker {
+    fn new(byte_rate: f64, title: Arc<String>) -> Chunker {
+        Chunker {
+            size: ((byte_rate * CHUNK_SECONDS) as usize).max(1),
+            title,
+            pending: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Vec<Chunk> {
+        self.pending.extend_from_slice(bytes);
+
+        let mut chunks = Vec::new();
+        while self.pending.len() >= self.size {
+            let rest = self.pending.split_off(self.size);
+            let audio = std::mem::replace(&mut self.pending, rest);
+            chunks.push(Chunk {
+                audio: Arc::new(audio),
+                title: Arc::clone(&self.title),
+            });
+        }
+
+        chunks
+    }
+
+    fn finish(self) -> Option<Chunk> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        Some(Chunk {
+            audio: Arc::new(self.pending),
+            title: self.title,
+        })
+    }
+}
+
+// Puts a metadata block after every ICY_METAINT bytes of audio, as SHOUTcast
+// does. The title is only sent again when it changes, otherwise the block is a
+// single zero byte.
+pub struct IcyWriter {
+    until_metadata: usize,
+    last_title: Option<Arc<String>>,
+}
+
+impl IcyWriter {
+    pub fn new() -> IcyWriter {
+        IcyWriter {
+            until_metadata: ICY_METAINT,
+            last_title: None,
+        }
+    }
+
+    pub fn write(&mut self, chunk: &Chunk) -> Vec<u8> {
+        let mut output = Vec::with_capacity(chunk.audio.len() + 64);
+        let mut audio = &chunk.audio[..];
+
+        while !audio.is_empty() {
+            let take = self.until_metadata.min(audio.len());
+            output.extend_from_slice(&audio[..take]);
+            audio = &audio[take..];
+            self.until_metadata -= take;
+
+            if self.until_metadata == 0 {
+                self.write_metadata(&mut output, &chunk.title);
+                self.until_metadata = ICY_METAINT;
+            }
+        }
+
+        output
+    }
+
+    fn write_metadata(&mut self, output: &mut Vec<u8>, title: &Arc<String>) {
+        if self
+            .last_title
+            .as_ref()
+            .is_some_and(|last| Arc::ptr_eq(last, title) || last == title)
+        {
+            output.push(0);
+            return;
+        }
+
+        let mut metadata = stream_title(title).into_bytes();
+
+        // the length is one byte counting 16 byte blocks
+        let blocks = metadata.len().div_ceil(16);
+        metadata.resize(blocks * 16, 0);
+
+        output.push(blocks as u8);
+        output.extend_from_slice(&metadata);
+
+        self.last_title = Some(Arc::clone(title));
+    }
+}
+
+// `StreamTitle='...';`, never longer than one metadata block can be
+fn stream_title(title: &str) -> String {
+    const PREFIX: &str = "StreamTitle='";
+    const SUFFIX: &str = "';";
+
+    // single quotes end the title early in most players
+    let title = title.replace('\'', "’");
+
+    // cut on a character boundary so the title stays valid UTF-8
+    let mut end = title.len().min(255 * 16 - PREFIX.len() - SUFFIX.len());
+    while !title.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}{}", PREFIX, &title[..end], SUFFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(audio: usize, title: &Arc<String>) -> Chunk {
+        Chunk {
+            audio: Arc::new(vec![1; audio]),
+            title: Arc::clone(title),
+        }
+    }
+
+    // The metadata blocks in a stream, padding left out
+    fn metadata(stream: &[u8]) -> Vec<String> {
+        let mut blocks = Vec::new();
+        let mut rest = stream;
+
+        while rest.len() > ICY_METAINT {
+            let length = rest[ICY_METAINT] as usize * 16;
+            let block = &rest[ICY_METAINT + 1..ICY_METAINT + 1 + length];
+            let text = std::str::from_utf8(block).expect("valid UTF-8");
+            blocks.push(text.trim_end_matches('\0').to_string());
+            rest = &rest[ICY_METAINT + 1 + length..];
+        }
+
+        blocks
+    }
+
+    fn file(path: &str, format: &str, sample_rate: u32, channels: u8) -> File {
+        let mut file = File::new_empty_file_from_path(std::path::Path::new(path)).unwrap();
+        file.format = format.to_string();
+        file.sample_rate = sample_rate;
+        file.channels = channels;
+        file.bitrate = 320;
+        file
+    }
+
+    fn encoder(command: &str) -> Option<Target> {
+        let config = Config {
+            transcode_mp3: command.to_string(),
+            ..Config::default()
+        };
+        Target::find(&config, "mp3")
+    }
+
+    #[test]
+    fn playable_files() {
+        let config = Config::default();
+        let plain = encoder("lame -r -s {sample_rate} - -");
+        let resampling = encoder("ffmpeg -ar {out_sample_rate} -ac {out_channels}");
+
+        let mp3 = file("/music/a.mp3", "mpeg", 44100, 2);
+        let mp3_48k = file("/music/b.mp3", "mpeg", 48000, 2);
+        let mut mp3_unknown_rate = file("/music/c.mp3", "mpeg", 44100, 2);
+        mp3_unknown_rate.bitrate = 0;
+        let flac = file("/music/d.flac", "flac", 44100, 2);
+        let flac_mono = file("/music/e.flac", "flac", 44100, 1);
+        let flac_48k = file("/music/f.flac", "flac", 48000, 2);
+        let unknown = file("/music/g.flac", "flac", 0, 0);
+
+        // (file, without an encoder, with one that can't resample, with one that can)
+        let cases = [
+            (&mp3, true, true, true),
+            (&mp3_48k, false, false, true),
+            (&mp3_unknown_rate, false, true, true),
+            (&flac, false, true, true),
+            (&flac_mono, false, false, true),
+            (&flac_48k, false, false, true),
+            (&unknown, false, false, true),
+        ];
+
+        for (file, none, can_not, can) in cases {
+            assert_eq!(playable(file, &config, None), none, "{}", file.path);
+            assert_eq!(
+                playable(file, &config, plain.as_ref()),
+                can_not,
+                "{}",
+                file.path
+            );
+            assert_eq!(
+                playable(file, &config, resampling.as_ref()),
+                can,
+                "{}",
+                file.path
+            );
+        }
+    }
+
+    #[test]
+    fn resampled_transcodes_are_cached_apart() {
+        let target = encoder("ffmpeg -ar {out_sample_rate} -ac {out_channels}").unwrap();
+        let resample = station_format(&Config::default());
+
+        assert_eq!(
+            target.cache_path("/cache", 1, 2, 128, None),
+            std::path::PathBuf::from("/cache/00000001-00000002-128.mp3")
+        );
+        assert_eq!(
+            target.cache_path("/cache", 1, 2, 128, Some(resample)),
+            std::path::PathBuf::from("/cache/00000001-00000002-128-44100x2.mp3")
+        );
+    }
+
+    #[test]
+    fn stream_title() {
+        assert_eq!(
+            super::stream_title("Artist - Title"),
+            "StreamTitle='Artist - Title';"
+        );
+        assert_eq!(
+            super::stream_title("Rock 'n' Roll';"),
+            "StreamTitle='Rock ’n’ Roll’;';"
+        );
+    }
+
+    #[test]
+    fn long_titles_are_cut_between_characters() {
+        for title in [
+            "a".repeat(5000),
+            "é".repeat(5000),
+            "’".repeat(5000),
+            "'".repeat(5000),
+        ] {
+            let stream_title = super::stream_title(&title);
+
+            assert!(stream_title.len() <= 255 * 16, "{}", stream_title.len());
+            assert!(stream_title.len() > 255 * 16 - 4);
+            assert!(stream_title.starts_with("StreamTitle='"));
+            assert!(stream_title.ends_with("';"));
+        }
+    }
+
+    #[test]
+    fn metadata_goes_every_metaint_bytes() {
+        let first = Arc::new("First".to_string());
+        let second = Arc::new("é".repeat(3000));
+        let mut writer = IcyWriter::new();
+
+        let mut stream = writer.write(&chunk(ICY_METAINT * 2, &first));
+        stream.extend(writer.write(&chunk(ICY_METAINT, &second)));
+        stream.extend(writer.write(&chunk(ICY_METAINT + 1, &second)));
+
+        let blocks = metadata(&stream);
+
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0], "StreamTitle='First';");
+        // unchanged titles aren't sent again
+        assert_eq!(blocks[1], "");
+        assert!(blocks[2].starts_with("StreamTitle='éé"));
+        assert!(blocks[2].ends_with("';"));
+        assert_eq!(blocks[3], "");
+    }
+}
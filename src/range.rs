@@ -0,0 +1,227 @@
+// Range requests as RFC 9110 describes them
+// https://www.rfc-editor.org/rfc/rfc9110#name-range-requests
+
+// More ranges than this in one request is more likely abuse than a player
+// seeking, so the whole file is sent instead
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    // no usable Range header, send everything with a 200
+    Full,
+    // inclusive byte positions, one for a plain 206 and more for
+    // multipart/byteranges
+    Partial(Vec<(u64, u64)>),
+    // 416 with `Content-Range: bytes */size`
+    Unsatisfiable,
+}
+
+// e.g `bytes=0-499`, `bytes=500-`, `bytes=-500` or `bytes=0-0, -1`
+pub fn parse(header: &str, size: u64) -> Ranges {
+    let header = header.trim();
+    if header.is_empty() {
+        return Ranges::Full;
+    }
+
+    // other units have to be ignored
+    let specs = match header.split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Full,
+    };
+
+    let mut ranges = Vec::new();
+    let mut specs_seen = 0;
+
+    // empty list elements are allowed, e.g `bytes=0-1,,5-6`
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        specs_seen += 1;
+
+        let (first, last) = match spec.split_once('-') {
+            Some(positions) => positions,
+            // not a range header we understand
+            None => return Ranges::Full,
+        };
+
+        let first = match parse_position(first) {
+            Ok(first) => first,
+            Err(_) => return Ranges::Full,
+        };
+        let last = match parse_position(last) {
+            Ok(last) => last,
+            Err(_) => return Ranges::Full,
+        };
+
+        // `last` before `first` isn't a range at all, rather than one
+        // that can't be satisfied
+        if let (Some(first), Some(last)) = (first, last) {
+            if last < first {
+                return Ranges::Full;
+            }
+        }
+
+        if let Some(range) = resolve(first, last, size) {
+            ranges.push(range);
+        }
+    }
+
+    if specs_seen == 0 {
+        return Ranges::Full;
+    }
+
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    let ranges = coalesce(ranges);
+
+    if ranges.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+
+    Ranges::Partial(ranges)
+}
+
+// Missing positions are None, anything else has to be plain digits
+fn parse_position(position: &str) -> Result<Option<u64>, ()> {
+    let position = position.trim();
+
+    if position.is_empty() {
+        return Ok(None);
+    }
+
+    if !position.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(());
+    }
+
+    // bigger than any file, which makes it unsatisfiable as a start or the
+    // end of the file as an end or suffix
+    Ok(Some(position.parse().unwrap_or(u64::MAX)))
+}
+
+// The bytes a single range-spec asks for, None when it's invalid or starts
+// past the end of the file
+fn resolve(first: Option<u64>, last: Option<u64>, size: u64) -> Option<(u64, u64)> {
+    match (first, last) {
+        // `first-last`
+        (Some(first), Some(last)) if first < size => Some((first, last.min(size - 1))),
+        // `first-`
+        (Some(first), None) if first < size => Some((first, size - 1)),
+        // `-suffix`, the last `suffix` bytes
+        (None, Some(suffix)) if suffix > 0 && size > 0 => Some((size - suffix.min(size), size - 1)),
+        _ => None,
+    }
+}
+
+// Overlapping or touching ranges are sent once, otherwise the order they
+// were asked for is kept
+fn coalesce(ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    let overlapping = ranges.iter().enumerate().any(|(i, a)| {
+        ranges[i + 1..]
+            .iter()
+            .any(|b| a.0 <= b.1.saturating_add(1) && b.0 <= a.1.saturating_add(1))
+    });
+
+    if !overlapping {
+        return ranges;
+    }
+
+    let mut sorted = ranges;
+    sorted.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(sorted.len());
+    for (start, end) in sorted {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_range_headers() {
+        let size = 1000;
+        let many: Vec<String> = (0..17).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        let many = format!("bytes={}", many.join(","));
+
+        let cases: &[(&str, Ranges)] = &[
+            ("", Ranges::Full),
+            ("bytes=0-0", Ranges::Partial(vec![(0, 0)])),
+            ("bytes=0-499", Ranges::Partial(vec![(0, 499)])),
+            ("bytes=-500", Ranges::Partial(vec![(500, 999)])),
+            ("bytes=-5000", Ranges::Partial(vec![(0, 999)])),
+            ("bytes=-0", Ranges::Unsatisfiable),
+            ("bytes=500-", Ranges::Partial(vec![(500, 999)])),
+            ("bytes=999-", Ranges::Partial(vec![(999, 999)])),
+            // past the end of the file
+            ("bytes=1000-", Ranges::Unsatisfiable),
+            ("bytes=5000-6000", Ranges::Unsatisfiable),
+            ("bytes=900-5000", Ranges::Partial(vec![(900, 999)])),
+            ("bytes=99999999999999999999-", Ranges::Unsatisfiable),
+            (
+                "bytes=0-99999999999999999999",
+                Ranges::Partial(vec![(0, 999)]),
+            ),
+            // backwards
+            ("bytes=5-2", Ranges::Full),
+            ("bytes=0-1,5-2", Ranges::Full),
+            // overlapping and touching ranges are merged
+            ("bytes=0-1,1-2", Ranges::Partial(vec![(0, 2)])),
+            ("bytes=0-1,2-3", Ranges::Partial(vec![(0, 3)])),
+            (
+                "bytes=500-600,0-1,550-",
+                Ranges::Partial(vec![(0, 1), (500, 999)]),
+            ),
+            // otherwise the order asked for is kept
+            (
+                "bytes=500-600,0-1",
+                Ranges::Partial(vec![(500, 600), (0, 1)]),
+            ),
+            // only the satisfiable ones are sent
+            ("bytes=0-1,5000-", Ranges::Partial(vec![(0, 1)])),
+            (&many, Ranges::Full),
+            ("items=0-1", Ranges::Full),
+            ("bytes 0-1", Ranges::Full),
+            ("bytes=", Ranges::Full),
+            ("bytes=,", Ranges::Full),
+            ("bytes=a-b", Ranges::Full),
+            ("bytes=1", Ranges::Full),
+            ("bytes=+1-2", Ranges::Full),
+            (
+                " BYTES = 0-1 ,, 4 - 5 ",
+                Ranges::Partial(vec![(0, 1), (4, 5)]),
+            ),
+        ];
+
+        for (header, expected) in cases {
+            assert_eq!(&parse(header, size), expected, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn nothing_in_an_empty_file_is_satisfiable() {
+        assert_eq!(parse("bytes=0-0", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-1", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn sixteen_ranges_are_allowed() {
+        let specs: Vec<String> = (0..16).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        let header = format!("bytes={}", specs.join(","));
+
+        match parse(&header, 1000) {
+            Ranges::Partial(ranges) => assert_eq!(ranges.len(), 16),
+            ranges => panic!("{:?}", ranges),
+        }
+    }
+}
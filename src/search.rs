@@ -0,0 +1,201 @@
+use crate::database::SQLite;
+use crate::music::File;
+use rusqlite::params;
+use std::collections::HashSet;
+
+// Columns a search is allowed to match against
+const SEARCH_COLUMNS: &str = "{path file_name title artist album}";
+
+// Turns what a listener typed into a safe FTS5 query.
+//
+// Words are quoted so FTS5 syntax characters (-, :, ^ etc) are treated as text,
+// a trailing `*` makes a word or phrase a prefix query and anything between
+// double quotes is matched as a phrase, e.g `"live at" fabric ben*`.
+pub fn to_fts_query(query: &str) -> Option<String> {
+    let mut terms: Vec<String> = Vec::new();
+    let mut chars = query.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        let mut term = String::new();
+
+        if c == '"' {
+            // a phrase runs until the closing quote (or the end of the query)
+            for c in chars.by_ref() {
+                if c == '"' {
+                    break;
+                }
+                term.push(c);
+            }
+        } else {
+            term.push(c);
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' {
+                    break;
+                }
+                term.push(c);
+                chars.next();
+            }
+        }
+
+        let mut prefix = false;
+
+        if term.ends_with('*') {
+            term = term.trim_end_matches('*').to_string();
+            prefix = true;
+        }
+
+        if chars.peek() == Some(&'*') {
+            chars.next();
+            prefix = true;
+        }
+
+        let term = term.trim();
+
+        if term.is_empty() {
+            continue;
+        }
+
+        let mut quoted = format!("\"{}\"", term.replace('"', "\"\""));
+
+        if prefix {
+            quoted.push('*');
+        }
+
+        terms.push(quoted);
+    }
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(format!("{} : ({})", SEARCH_COLUMNS, terms.join(" ")))
+}
+
+// Returns one page of playable files matching the query, best match first,
+// along with the total number of matches. Playable is what /random picks
+// from: warmed, parsed and no longer than `max_duration` seconds.
+pub fn search_files(
+    fts_query: &str,
+    max_duration: u64,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<(Vec<File>, usize)> {
+    let conn = SQLite::connect();
+
+    let total: usize = conn.query_row(
+        "SELECT count(*) FROM search
+        JOIN files ON files.id = search.rowid
+        WHERE search MATCH ?1 AND files.parse_fail = 0
+        AND files.indexed_at > 0 AND files.duration <= ?2",
+        params![fts_query, max_duration],
+        |row| row.get(0),
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT files.* FROM search
+        JOIN files ON files.id = search.rowid
+        WHERE search MATCH ?1 AND files.parse_fail = 0
+        AND files.indexed_at > 0 AND files.duration <= ?2
+        ORDER BY search.rank
+        LIMIT ?3 OFFSET ?4",
+    )?;
+
+    let files = stmt
+        .query_map(
+            params![fts_query, max_duration, limit, offset],
+            File::from_row,
+        )?
+        .collect::<rusqlite::Result<Vec<File>>>()?;
+
+    Ok((files, total))
+}
+
+// Every file matching the query, for narrowing down a random pick
+pub fn matching_ids(fts_query: &str) -> rusqlite::Result<HashSet<u32>> {
+    let conn = SQLite::connect();
+
+    let mut stmt = conn.prepare("SELECT rowid FROM search WHERE search MATCH ?1")?;
+
+    let ids = stmt
+        .query_map(params![fts_query], |row| row.get(0))?
+        .collect::<rusqlite::Result<HashSet<u32>>>()?;
+
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(terms: &str) -> Option<String> {
+        Some(format!("{} : ({})", SEARCH_COLUMNS, terms))
+    }
+
+    #[test]
+    fn empty_queries() {
+        for input in ["", " ", "\t\n ", "\"\"", "\" \"", "*", "\"*\""] {
+            assert_eq!(to_fts_query(input), None, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn words_are_quoted() {
+        assert_eq!(to_fts_query("ben"), query("\"ben\""));
+        assert_eq!(to_fts_query("  ben   klock "), query("\"ben\" \"klock\""));
+    }
+
+    #[test]
+    fn syntax_is_matched_as_text() {
+        let cases = [
+            ("-ben", "\"-ben\""),
+            ("title:ben", "\"title:ben\""),
+            ("ben NEAR klock", "\"ben\" \"NEAR\" \"klock\""),
+            ("NEAR(ben klock)", "\"NEAR(ben\" \"klock)\""),
+            ("ben AND NOT klock", "\"ben\" \"AND\" \"NOT\" \"klock\""),
+            ("^ben", "\"^ben\""),
+            ("{artist}", "\"{artist}\""),
+            ("ben'", "\"ben'\""),
+        ];
+
+        for (input, terms) in cases {
+            assert_eq!(to_fts_query(input), query(terms), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn quotes() {
+        let cases = [
+            ("\"live at\" fabric", "\"live at\" \"fabric\""),
+            // an unclosed phrase runs to the end
+            ("\"live at fabric", "\"live at fabric\""),
+            // quotes inside a word end it
+            ("live\"at", "\"live\" \"at\""),
+            ("\"\"\"\"", ""),
+        ];
+
+        for (input, terms) in cases {
+            let expected = if terms.is_empty() { None } else { query(terms) };
+            assert_eq!(to_fts_query(input), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        let cases = [
+            ("ben*", "\"ben\"*"),
+            ("ben**", "\"ben\"*"),
+            ("\"live at\"*", "\"live at\"*"),
+            ("\"live at*\"", "\"live at\"*"),
+            // only a trailing star makes a prefix
+            ("b*n", "\"b*n\""),
+        ];
+
+        for (input, terms) in cases {
+            assert_eq!(to_fts_query(input), query(terms), "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use rand::seq::SliceRandom;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Set on listeners that don't send a `session` of their own
+pub const COOKIE: &str = "auralist_session";
+
+// Past this the least recently heard session is forgotten to make room, so
+// clients that drop cookies can't use up all the memory
+const MAX_SESSIONS: usize = 10000;
+
+// Bags per session, one for each mode or set of /random filters
+const MAX_BAGS: usize = 16;
+
+// Every listener's session, kept in order of when they were last used so
+// the oldest can be found without looking through all of them
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<String, Session>,
+    by_access: BTreeSet<(u64, String)>,
+}
+
+// A listener's shuffle bags, everything they've heard from each pool since it
+// was last used up
+#[derive(Default)]
+struct Session {
+    bags: HashMap<String, Bag>,
+    accessed_at: u64,
+}
+
+#[derive(Default)]
+struct Bag {
+    played: HashSet<u32>,
+    last: Option<u32>,
+    used_at: u64,
+}
+
+// Ids sent by clients are kept short and boring, e.g a uuid
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+pub fn new_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+// Picks from `pool` without repeating anything this session has had from
+// the `bag` until all of it has been played. Files added to the pool join
+// the current bag, files removed from it are never picked. Without a session
+// (a new listener whose cookie hasn't come back yet) nothing is remembered,
+// so clients that drop cookies don't fill up the sessions.
+pub fn pick(
+    sessions: &mut Sessions,
+    session: Option<&str>,
+    bag: &str,
+    pool: &[u32],
+) -> Option<u32> {
+    pick_at(sessions, session, bag, pool, now())
+}
+
+fn pick_at(
+    sessions: &mut Sessions,
+    session: Option<&str>,
+    bag: &str,
+    pool: &[u32],
+    now: u64,
+) -> Option<u32> {
+    let session = match session {
+        Some(session) => sessions.touch(session, now),
+        None => return pool.choose(&mut rand::thread_rng()).copied(),
+    };
+
+    if !session.bags.contains_key(bag) && session.bags.len() >= MAX_BAGS {
+        if let Some(oldest) = session
+            .bags
+            .iter()
+            .min_by_key(|(_, bag)| bag.used_at)
+            .map(|(key, _)| key.clone())
+        {
+            session.bags.remove(&oldest);
+        }
+    }
+
+    let bag = session.bags.entry(bag.to_string()).or_default();
+    bag.used_at = now;
+
+    let mut remaining: Vec<u32> = pool
+        .iter()
+        .filter(|id| !bag.played.contains(id))
+        .copied()
+        .collect();
+
+    if remaining.is_empty() {
+        // everything has been heard, start again without the last one so it
+        // isn't heard twice in a row
+        bag.played.clear();
+        remaining = pool
+            .iter()
+            .filter(|id| pool.len() == 1 || Some(**id) != bag.last)
+            .copied()
+            .collect();
+    }
+
+    let picked = *remaining.choose(&mut rand::thread_rng())?;
+    bag.played.insert(picked);
+    bag.last = Some(picked);
+
+    Some(picked)
+}
+
+impl Sessions {
+    // The session, made if need be, marked as used at `now`
+    fn touch(&mut self, id: &str, now: u64) -> &mut Session {
+        match self.sessions.get(id) {
+            Some(session) => {
+                self.by_access
+                    .remove(&(session.accessed_at, id.to_string()));
+            }
+            None if self.sessions.len() >= MAX_SESSIONS => {
+                if let Some((_, oldest)) = self.by_access.pop_first() {
+                    self.sessions.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+
+        self.by_access.insert((now, id.to_string()));
+        let session = self.sessions.entry(id.to_string()).or_default();
+        session.accessed_at = now;
+        session
+    }
+
+    // Forgets sessions nobody has used for `lifetime` seconds
+    pub fn expire(&mut self, lifetime: u64) {
+        self.expire_at(lifetime, now());
+    }
+
+    fn expire_at(&mut self, lifetime: u64, now: u64) {
+        let before = self.sessions.len();
+
+        while let Some((accessed_at, _)) = self.by_access.first() {
+            if now.saturating_sub(*accessed_at) < lifetime {
+                break;
+            }
+            if let Some((_, id)) = self.by_access.pop_first() {
+                self.sessions.remove(&id);
+            }
+        }
+
+        println!("Expired {} sessions...", before - self.sessions.len());
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_repeats_until_the_pool_is_used_up() {
+        let mut sessions = Sessions::default();
+        let pool = [1, 2, 3];
+        let mut last = None;
+
+        for _ in 0..20 {
+            let mut round: Vec<u32> = (0..3)
+                .map(|_| pick(&mut sessions, Some("a"), "all", &pool).unwrap())
+                .collect();
+
+            // and nothing is heard twice in a row across rounds
+            assert_ne!(Some(round[0]), last);
+            last = round.last().copied();
+
+            round.sort_unstable();
+            assert_eq!(round, pool);
+        }
+    }
+
+    #[test]
+    fn a_pool_of_one_keeps_working() {
+        let mut sessions = Sessions::default();
+
+        for _ in 0..5 {
+            assert_eq!(pick(&mut sessions, Some("a"), "all", &[7]), Some(7));
+        }
+        assert_eq!(pick(&mut sessions, Some("a"), "all", &[]), None);
+    }
+
+    #[test]
+    fn bags_and_sessions_are_kept_apart() {
+        let mut sessions = Sessions::default();
+        let pool = [1, 2];
+
+        let a = pick(&mut sessions, Some("a"), "all", &pool).unwrap();
+        let b = pick(&mut sessions, Some("a"), "tunes", &pool).unwrap();
+        let c = pick(&mut sessions, Some("b"), "all", &pool).unwrap();
+
+        assert!(pool.contains(&a) && pool.contains(&b) && pool.contains(&c));
+        assert_eq!(sessions.sessions["a"].bags.len(), 2);
+        assert_eq!(sessions.sessions["b"].bags.len(), 1);
+    }
+
+    #[test]
+    fn listeners_without_a_session_are_not_remembered() {
+        let mut sessions = Sessions::default();
+
+        assert!(pick(&mut sessions, None, "all", &[1, 2, 3]).is_some());
+        assert!(sessions.sessions.is_empty());
+        assert!(sessions.by_access.is_empty());
+    }
+
+    #[test]
+    fn the_least_recently_used_session_makes_room() {
+        let mut sessions = Sessions::default();
+
+        for i in 0..MAX_SESSIONS as u64 {
+            pick_at(&mut sessions, Some(&i.to_string()), "all", &[1], 100 + i);
+        }
+        // the oldest is used again, so the second oldest goes instead
+        pick_at(&mut sessions, Some("0"), "all", &[1], 100_000);
+        pick_at(&mut sessions, Some("new"), "all", &[1], 100_001);
+
+        assert_eq!(sessions.sessions.len(), MAX_SESSIONS);
+        assert_eq!(sessions.by_access.len(), MAX_SESSIONS);
+        assert!(sessions.sessions.contains_key("0"));
+        assert!(sessions.sessions.contains_key("new"));
+        assert!(!sessions.sessions.contains_key("1"));
+        assert!(sessions.sessions.contains_key("2"));
+    }
+
+    #[test]
+    fn the_least_recently_used_bag_makes_room() {
+        let mut sessions = Sessions::default();
+
+        for i in 0..MAX_BAGS as u64 {
+            pick_at(&mut sessions, Some("a"), &i.to_string(), &[1], 100 + i);
+        }
+        pick_at(&mut sessions, Some("a"), "0", &[1], 1000);
+        pick_at(&mut sessions, Some("a"), "new", &[1], 1001);
+
+        let bags = &sessions.sessions["a"].bags;
+        assert_eq!(bags.len(), MAX_BAGS);
+        assert!(bags.contains_key("0"));
+        assert!(bags.contains_key("new"));
+        assert!(!bags.contains_key("1"));
+    }
+
+    #[test]
+    fn quiet_sessions_expire() {
+        let mut sessions = Sessions::default();
+
+        pick_at(&mut sessions, Some("old"), "all", &[1], 100);
+        pick_at(&mut sessions, Some("new"), "all", &[1], 200);
+        sessions.expire_at(50, 220);
+
+        assert!(!sessions.sessions.contains_key("old"));
+        assert!(sessions.sessions.contains_key("new"));
+        assert_eq!(sessions.by_access.len(), 1);
+    }
+}
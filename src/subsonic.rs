@@ -0,0 +1,1255 @@
+// A Subsonic (and OpenSubsonic) compatible API over the warmed files, so
+// existing clients like DSub, Symfonium and Feishin can browse and play them
+// http://www.subsonic.org/pages/api.jsp
+// https://opensubsonic.netlify.app/docs/
+
+use crate::config::Config;
+use crate::database::SQLite;
+use crate::date;
+use crate::music::File;
+use crate::search;
+use crate::token;
+use md5::{Digest, Md5};
+use murmurhash32::murmurhash3;
+use rand::seq::SliceRandom;
+use rusqlite::params;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+pub const API_VERSION: &str = "1.16.1";
+
+const IGNORED_ARTICLES: &[&str] = &["The", "El", "La", "Los", "Las", "Le", "Les"];
+
+const DEFAULT_LIST_SIZE: usize = 10;
+const DEFAULT_SEARCH_SIZE: usize = 20;
+const MAX_LIST_SIZE: usize = 500;
+
+// http://www.subsonic.org/pages/api.jsp#error-codes
+const ERROR_GENERIC: u32 = 0;
+const ERROR_MISSING_PARAMETER: u32 = 10;
+const ERROR_WRONG_CREDENTIALS: u32 = 40;
+const ERROR_NOT_FOUND: u32 = 70;
+
+// What a request turned into, main.rs turns it into a response
+pub enum Outcome {
+    // the payload that goes inside <subsonic-response>
+    Ok(Map<String, Value>),
+    Failed(u32, String),
+    // the original file, or transcoded when a format is given
+    Stream {
+        file: Box<File>,
+        format: Option<String>,
+        bitrate: Option<u32>,
+    },
+    Cover {
+        hash: String,
+        size: Option<u32>,
+    },
+}
+
+// Query string and form parameters, a name can be given more than once
+pub struct Params(pub Vec<(String, String)>);
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn get_all(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|value| value.parse().ok())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, Outcome> {
+        self.get(name).ok_or_else(|| {
+            Outcome::Failed(
+                ERROR_MISSING_PARAMETER,
+                format!("Required parameter `{}` is missing", name),
+            )
+        })
+    }
+
+    pub fn json(&self) -> bool {
+        self.get("f") == Some("json")
+    }
+}
+
+struct Album<'a> {
+    id: String,
+    name: String,
+    artist: String,
+    artist_id: String,
+    songs: Vec<&'a File>,
+}
+
+struct Artist<'a> {
+    id: String,
+    name: String,
+    albums: Vec<&'a Album<'a>>,
+}
+
+pub struct Subsonic {
+    config: Config,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    all_mutex: Arc<Mutex<Vec<u32>>>,
+}
+
+impl Subsonic {
+    pub fn new(
+        config: Config,
+        files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+        all_mutex: Arc<Mutex<Vec<u32>>>,
+    ) -> Subsonic {
+        Subsonic {
+            config,
+            files_mutex,
+            all_mutex,
+        }
+    }
+
+    // Without a password there's nobody to log in as
+    pub fn enabled(&self) -> bool {
+        !self.config.subsonic_password.is_empty()
+    }
+
+    // `method` is the last part of the path, with or without `.view`
+    pub fn handle(&self, method: &str, params: &Params) -> Outcome {
+        let method = method.strip_suffix(".view").unwrap_or(method);
+
+        if let Err(outcome) = self.authenticate(params) {
+            return outcome;
+        }
+
+        let result = match method {
+            "ping" => Ok(Map::new()),
+            "getLicense" => Ok(payload("license", json!({ "valid": true }))),
+            "getOpenSubsonicExtensions" => Ok(payload(
+                "openSubsonicExtensions",
+                json!([{ "name": "formPost", "versions": [1] }]),
+            )),
+            "getUser" => self.get_user(params),
+            "getMusicFolders" => Ok(payload(
+                "musicFolders",
+                json!({ "musicFolder": [{ "id": 1, "name": "Music" }] }),
+            )),
+            "getIndexes" => Ok(self.get_indexes()),
+            "getMusicDirectory" => self.get_music_directory(params),
+            "getArtists" => Ok(self.get_artists()),
+            "getArtist" => self.get_artist(params),
+            "getAlbum" => self.get_album(params),
+            "getSong" => self.get_song(params),
+            "getGenres" => Ok(self.get_genres()),
+            "getAlbumList2" => Ok(self.get_album_list(params)),
+            "getRandomSongs" => Ok(self.get_random_songs(params)),
+            "search3" => Ok(self.search(params)),
+            "scrobble" => self.scrobble(params),
+            "stream" | "download" => match self.stream(method, params) {
+                Ok(outcome) | Err(outcome) => return outcome,
+            },
+            "getCoverArt" => match self.get_cover_art(params) {
+                Ok(outcome) | Err(outcome) => return outcome,
+            },
+            _ => Err(Outcome::Failed(
+                ERROR_GENERIC,
+                format!("`{}` isn't supported", method),
+            )),
+        };
+
+        match result {
+            Ok(payload) => Outcome::Ok(payload),
+            Err(outcome) => outcome,
+        }
+    }
+
+    // Either t=md5(password + s) with a random salt s, or the password itself
+    // in p, optionally hex encoded as enc:...
+    fn authenticate(&self, params: &Params) -> Result<(), Outcome> {
+        let username = params.required("u")?;
+
+        let password_matches = match (params.get("t"), params.get("s"), params.get("p")) {
+            (Some(token), Some(salt), _) => {
+                let expected = Md5::digest(format!("{}{}", self.config.subsonic_password, salt));
+                token::from_hex(token).is_some_and(|token| bool::from(token.ct_eq(&expected)))
+            }
+            (_, _, Some(password)) => {
+                let password = match password.strip_prefix("enc:") {
+                    Some(hex) => token::from_hex(hex)
+                        .and_then(|bytes| String::from_utf8(bytes).ok())
+                        .unwrap_or_default(),
+                    None => password.to_string(),
+                };
+                bool::from(
+                    password
+                        .as_bytes()
+                        .ct_eq(self.config.subsonic_password.as_bytes()),
+                )
+            }
+            _ => {
+                return Err(Outcome::Failed(
+                    ERROR_MISSING_PARAMETER,
+                    "Required parameter `t` and `s`, or `p` is missing".to_string(),
+                ))
+            }
+        };
+
+        // compared in constant time so the timing doesn't give away how much
+        // of a guess was right
+        let username_matches = bool::from(
+            username
+                .as_bytes()
+                .ct_eq(self.config.subsonic_username.as_bytes()),
+        );
+
+        if !username_matches || !password_matches {
+            return Err(Outcome::Failed(
+                ERROR_WRONG_CREDENTIALS,
+                "Wrong username or password".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Runs `f` over every warmed file. They're copied out first so grouping
+    // a big library doesn't keep the scanner and the other handlers waiting.
+    fn with_library<R>(&self, f: impl FnOnce(&[&File]) -> R) -> R {
+        println!("Locking files (subsonic)...");
+        let files = self.files_mutex.lock().unwrap();
+        let all = self.all_mutex.lock().unwrap();
+
+        let library: Vec<File> = all.iter().filter_map(|id| files.get(id)).cloned().collect();
+
+        drop(all);
+        println!("Unlocking files (subsonic)...");
+        drop(files);
+
+        let library: Vec<&File> = library.iter().collect();
+        f(&library)
+    }
+
+    fn get_user(&self, params: &Params) -> Result<Map<String, Value>, Outcome> {
+        let username = params.required("username")?;
+
+        if username != self.config.subsonic_username {
+            return Err(not_found("User"));
+        }
+
+        Ok(payload(
+            "user",
+            json!({
+                "username": username,
+                "scrobblingEnabled": true,
+                "adminRole": false,
+                "settingsRole": false,
+                "downloadRole": true,
+                "uploadRole": false,
+                "playlistRole": false,
+                "coverArtRole": true,
+                "commentRole": false,
+                "podcastRole": false,
+                "streamRole": true,
+                "jukeboxRole": false,
+                "shareRole": false,
+                "folder": [1],
+            }),
+        ))
+    }
+
+    fn get_indexes(&self) -> Map<String, Value> {
+        self.with_library(|library| {
+            let albums = group_albums(library);
+            let artists = group_artists(&albums);
+
+            let last_modified = library
+                .iter()
+                .map(|file| file.indexed_at)
+                .max()
+                .unwrap_or(0);
+
+            payload(
+                "indexes",
+                json!({
+                    "lastModified": last_modified * 1000,
+                    "ignoredArticles": IGNORED_ARTICLES.join(" "),
+                    "index": index(&artists, |artist| json!({
+                        "id": artist.id,
+                        "name": artist.name,
+                    })),
+                }),
+            )
+        })
+    }
+
+    // Artists are folders of albums, albums are folders of songs
+    fn get_music_directory(&self, params: &Params) -> Result<Map<String, Value>, Outcome> {
+        let id = params.required("id")?;
+
+        self.with_library(|library| {
+            let albums = group_albums(library);
+
+            if let Some(album) = albums.iter().find(|album| album.id == id) {
+                return Ok(payload(
+                    "directory",
+                    prune(json!({
+                        "id": album.id,
+                        "parent": album.artist_id,
+                        "name": album.name,
+                        "child": album.songs.iter().map(|file| self.song(file)).collect::<Vec<Value>>(),
+                    })),
+                ));
+            }
+
+            let artists = group_artists(&albums);
+
+            match artists.iter().find(|artist| artist.id == id) {
+                Some(artist) => Ok(payload(
+                    "directory",
+                    json!({
+                        "id": artist.id,
+                        "name": artist.name,
+                        "child": artist
+                            .albums
+                            .iter()
+                            .map(|album| prune(json!({
+                                "id": album.id,
+                                "parent": artist.id,
+                                "isDir": true,
+                                "title": album.name,
+                                "album": album.name,
+                                "artist": album.artist,
+                                "coverArt": cover_art(album),
+                            })))
+                            .collect::<Vec<Value>>(),
+                    }),
+                )),
+                None => Err(not_found("Directory")),
+            }
+        })
+    }
+
+    fn get_artists(&self) -> Map<String, Value> {
+        self.with_library(|library| {
+            let albums = group_albums(library);
+            let artists = group_artists(&albums);
+
+            payload(
+                "artists",
+                json!({
+                    "ignoredArticles": IGNORED_ARTICLES.join(" "),
+                    "index": index(&artists, artist_value),
+                }),
+            )
+        })
+    }
+
+    fn get_artist(&self, params: &Params) -> Result<Map<String, Value>, Outcome> {
+        let id = params.required("id")?;
+
+        self.with_library(|library| {
+            let albums = group_albums(library);
+            let artists = group_artists(&albums);
+
+            let artist = match artists.iter().find(|artist| artist.id == id) {
+                Some(artist) => artist,
+                None => return Err(not_found("Artist")),
+            };
+
+            let mut value = artist_value(artist);
+            value["album"] = artist
+                .albums
+                .iter()
+                .map(|album| album_value(album))
+                .collect();
+
+            Ok(payload("artist", value))
+        })
+    }
+
+    fn get_album(&self, params: &Params) -> Result<Map<String, Value>, Outcome> {
+        let id = params.required("id")?;
+
+        self.with_library(|library| {
+            let albums = group_albums(library);
+
+            let album = match albums.iter().find(|album| album.id == id) {
+                Some(album) => album,
+                None => return Err(not_found("Album")),
+            };
+
+            let mut value = album_value(album);
+            value["song"] = album.songs.iter().map(|file| self.song(file)).collect();
+
+            Ok(payload("album", value))
+        })
+    }
+
+    fn get_song(&self, params: &Params) -> Result<Map<String, Value>, Outcome> {
+        let file = self.find_song(params)?;
+        Ok(payload("song", self.song(&file)))
+    }
+
+    fn get_genres(&self) -> Map<String, Value> {
+        self.with_library(|library| {
+            let mut genres: BTreeMap<&str, (usize, Vec<String>)> = BTreeMap::new();
+
+            for file in library.iter().filter(|file| !file.genre.is_empty()) {
+                let (songs, albums) = genres.entry(&file.genre).or_default();
+                *songs += 1;
+                let album = file.album_id();
+                if !albums.contains(&album) {
+                    albums.push(album);
+                }
+            }
+
+            let genres: Vec<Value> = genres
+                .into_iter()
+                .map(|(name, (songs, albums))| {
+                    json!({ "value": name, "songCount": songs, "albumCount": albums.len() })
+                })
+                .collect();
+
+            payload("genres", json!({ "genre": genres }))
+        })
+    }
+
+    fn get_album_list(&self, params: &Params) -> Map<String, Value> {
+        let size = list_size(params.number("size"), DEFAULT_LIST_SIZE);
+        let offset = params.number("offset").unwrap_or(0);
+        let list_type = params.get("type").unwrap_or("random");
+        let from_year = params.number::<u32>("fromYear");
+        let to_year = params.number::<u32>("toYear");
+        let genre = params.get("genre");
+
+        self.with_library(|library| {
+            let mut albums = group_albums(library);
+
+            match list_type {
+                "random" => albums.shuffle(&mut rand::thread_rng()),
+                "newest" => albums.sort_by_key(|album| {
+                    std::cmp::Reverse(album.songs.iter().map(|file| file.indexed_at).max())
+                }),
+                "recent" => {
+                    albums.retain(|album| album.songs.iter().any(|file| file.accessed_at > 0));
+                    albums.sort_by_key(|album| {
+                        std::cmp::Reverse(album.songs.iter().map(|file| file.accessed_at).max())
+                    });
+                }
+                "alphabeticalByArtist" => albums
+                    .sort_by_key(|album| (album.artist.to_lowercase(), album.name.to_lowercase())),
+                "byYear" => {
+                    let (from, to) = (from_year.unwrap_or(0), to_year.unwrap_or(u32::MAX));
+                    albums.retain(|album| {
+                        let year = album_year(album);
+                        year >= from.min(to) && year <= from.max(to)
+                    });
+                    albums.sort_by_key(|album| album_year(album));
+                    if from > to {
+                        albums.reverse();
+                    }
+                }
+                "byGenre" => albums.retain(|album| {
+                    album
+                        .songs
+                        .iter()
+                        .any(|file| Some(file.genre.as_str()) == genre)
+                }),
+                // nothing is starred or rated
+                "starred" | "highest" | "frequent" => albums.clear(),
+                // alphabeticalByName and anything unknown
+                _ => {}
+            }
+
+            let albums: Vec<Value> = albums
+                .iter()
+                .skip(offset)
+                .take(size)
+                .map(|album| album_value(album))
+                .collect();
+
+            payload("albumList2", json!({ "album": albums }))
+        })
+    }
+
+    fn get_random_songs(&self, params: &Params) -> Map<String, Value> {
+        let size = list_size(params.number("size"), DEFAULT_LIST_SIZE);
+        let genre = params.get("genre");
+        let from_year = params.number::<u32>("fromYear").unwrap_or(0);
+        let to_year = params.number::<u32>("toYear").unwrap_or(u32::MAX);
+
+        self.with_library(|library| {
+            let candidates: Vec<&&File> = library
+                .iter()
+                .filter(|file| genre.is_none_or(|genre| file.genre.eq_ignore_ascii_case(genre)))
+                .filter(|file| {
+                    (from_year == 0 && to_year == u32::MAX)
+                        || (file.year >= from_year && file.year <= to_year)
+                })
+                .collect();
+
+            let songs: Vec<Value> = candidates
+                .choose_multiple(&mut rand::thread_rng(), size)
+                .map(|file| self.song(file))
+                .collect();
+
+            payload("randomSongs", json!({ "song": songs }))
+        })
+    }
+
+    // An empty query lists everything, clients use it to sync the library
+    fn search(&self, params: &Params) -> Map<String, Value> {
+        let query = params.get("query").unwrap_or_default().trim_matches('"');
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(|word| word.trim_end_matches('*').to_lowercase())
+            .collect();
+        let matches = |name: &str| {
+            let name = name.to_lowercase();
+            words.iter().all(|word| name.contains(word))
+        };
+
+        let artist_count = list_size(params.number("artistCount"), DEFAULT_SEARCH_SIZE);
+        let album_count = list_size(params.number("albumCount"), DEFAULT_SEARCH_SIZE);
+        let song_count = list_size(params.number("songCount"), DEFAULT_SEARCH_SIZE);
+        let artist_offset = params.number("artistOffset").unwrap_or(0);
+        let album_offset = params.number("albumOffset").unwrap_or(0);
+        let song_offset = params.number("songOffset").unwrap_or(0);
+
+        // ranked by the full text index, which doesn't need the files locked
+        let ranked = match search::to_fts_query(query) {
+            Some(fts_query) => match search::search_files(
+                &fts_query,
+                self.config.max_duration,
+                song_count,
+                song_offset,
+            ) {
+                Ok((files, _)) => Some(files),
+                Err(err) => {
+                    println!("Search failed: {}", err);
+                    Some(Vec::new())
+                }
+            },
+            None => None,
+        };
+
+        self.with_library(|library| {
+            let albums = group_albums(library);
+            let artists = group_artists(&albums);
+
+            let artists: Vec<Value> = artists
+                .iter()
+                .filter(|artist| matches(&artist.name))
+                .skip(artist_offset)
+                .take(artist_count)
+                .map(artist_value)
+                .collect();
+
+            let albums: Vec<Value> = albums
+                .iter()
+                .filter(|album| matches(&album.name) || matches(&album.artist))
+                .skip(album_offset)
+                .take(album_count)
+                .map(|album| album_value(album))
+                .collect();
+
+            let songs: Vec<Value> = match ranked {
+                Some(files) => {
+                    let warmed: HashSet<u32> = library.iter().map(|file| file.id).collect();
+                    files
+                        .iter()
+                        .filter(|file| warmed.contains(&file.id))
+                        .map(|file| self.song(file))
+                        .collect()
+                }
+                None => library
+                    .iter()
+                    .skip(song_offset)
+                    .take(song_count)
+                    .map(|file| self.song(file))
+                    .collect(),
+            };
+
+            payload(
+                "searchResult3",
+                json!({ "artist": artists, "album": albums, "song": songs }),
+            )
+        })
+    }
+
+    // Remembers when each song was last played, `submission=false` only
+    // means "now playing" so it's ignored
+    fn scrobble(&self, params: &Params) -> Result<Map<String, Value>, Outcome> {
+        params.required("id")?;
+
+        if params.get("submission") == Some("false") {
+            return Ok(Map::new());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let ids: Vec<u32> = params
+            .get_all("id")
+            .iter()
+            .filter_map(|id| id.parse().ok())
+            .collect();
+
+        println!("Locking files (scrobble)...");
+        let mut files = self.files_mutex.lock().unwrap();
+        for id in &ids {
+            if let Some(file) = files.get_mut(id) {
+                file.accessed_at = now;
+            }
+        }
+        println!("Unlocking files (scrobble)...");
+        drop(files);
+
+        let conn = SQLite::connect();
+        for id in ids {
+            if let Err(err) = conn.execute(
+                "UPDATE files SET accessed_at = ?1 WHERE id = ?2",
+                params![now, id],
+            ) {
+                println!("Update failed (scrobble): {}", err);
+            }
+        }
+
+        Ok(Map::new())
+    }
+
+    // `format=raw` or no format sends the original, anything else is passed
+    // to the transcoder. A `maxBitRate` below the file's own means mp3.
+    fn stream(&self, method: &str, params: &Params) -> Result<Outcome, Outcome> {
+        let file = self.find_song(params)?;
+
+        let max_bitrate = params.number::<u32>("maxBitRate").filter(|max| *max > 0);
+
+        let format = match (method, params.get("format")) {
+            ("download", _) | (_, Some("raw")) => None,
+            (_, Some(format)) => Some(format.to_string()),
+            (_, None) if max_bitrate.is_some_and(|max| file.bitrate == 0 || max < file.bitrate) => {
+                Some("mp3".to_string())
+            }
+            (_, None) => None,
+        };
+
+        Ok(Outcome::Stream {
+            file: Box::new(file),
+            format,
+            bitrate: max_bitrate,
+        })
+    }
+
+    fn get_cover_art(&self, params: &Params) -> Result<Outcome, Outcome> {
+        let hash = params.required("id")?;
+
+        Ok(Outcome::Cover {
+            hash: hash.to_string(),
+            size: params.number("size"),
+        })
+    }
+
+    fn find_song(&self, params: &Params) -> Result<File, Outcome> {
+        let id: u32 = params
+            .required("id")?
+            .parse()
+            .map_err(|_| not_found("Song"))?;
+
+        self.with_library(|library| {
+            library
+                .iter()
+                .find(|file| file.id == id)
+                .map(|file| (*file).clone())
+                .ok_or_else(|| not_found("Song"))
+        })
+    }
+
+    fn song(&self, file: &File) -> Value {
+        let path = Path::new(&file.path)
+            .strip_prefix(&self.config.directory)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| file.file_name.clone());
+
+        let replay_gain = prune(json!({
+            "trackGain": file.replaygain_track_gain,
+            "trackPeak": file.replaygain_track_peak,
+            "albumGain": file.replaygain_album_gain,
+            "albumPeak": file.replaygain_album_peak,
+        }));
+
+        prune(json!({
+            "id": file.id.to_string(),
+            "parent": file.album_id(),
+            "isDir": false,
+            "title": song_title(file),
+            "album": file.album_name(),
+            "artist": file.artist,
+            "track": file.track_number,
+            "discNumber": file.disc_number,
+            "year": file.year,
+            "genre": file.genre,
+            "coverArt": file.art,
+            "size": file.file_size,
+            "contentType": file.mime_type(),
+            "suffix": file.file_ext.to_lowercase(),
+            "duration": file.duration,
+            "bitRate": file.bitrate,
+            "path": path,
+            "albumId": file.album_id(),
+            "artistId": artist_id(&file.album_artist_name()),
+            "type": "music",
+            "mediaType": "song",
+            "created": iso8601(file.indexed_at),
+            "played": if file.accessed_at > 0 { iso8601(file.accessed_at) } else { String::new() },
+            "bpm": file.bpm,
+            "comment": file.comment,
+            "musicBrainzId": file.musicbrainz_recording_id,
+            "samplingRate": file.sample_rate,
+            "channelCount": file.channels,
+            "bitDepth": file.bit_depth,
+            "replayGain": replay_gain,
+        }))
+    }
+}
+
+// Wraps a payload in its element name, e.g {"album": {...}}
+fn payload(name: &str, value: Value) -> Map<String, Value> {
+    let mut map = Map::new();
+    map.insert(name.to_string(), value);
+    map
+}
+
+fn not_found(what: &str) -> Outcome {
+    Outcome::Failed(ERROR_NOT_FOUND, format!("{} not found", what))
+}
+
+fn list_size(requested: Option<usize>, default: usize) -> usize {
+    requested.unwrap_or(default).min(MAX_LIST_SIZE)
+}
+
+// Leaves out unknown values (empty strings, zeros and nulls) the way the
+// reference server does, so clients don't show a track 0 from 0000
+fn prune(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, value)| match value {
+                    Value::Null => false,
+                    Value::String(string) => !string.is_empty(),
+                    Value::Number(number) => number.as_f64() != Some(0.0),
+                    Value::Object(map) => !map.is_empty(),
+                    _ => true,
+                })
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn song_title(file: &File) -> String {
+    if file.title.is_empty() {
+        file.file_name.clone()
+    } else {
+        file.title.clone()
+    }
+}
+
+// Ids are hashes of the names so they survive a restart and a rescan, see
+// `File::album_id` for albums
+fn artist_id(name: &str) -> String {
+    format!("ar-{:08x}", murmurhash3(name.to_lowercase().as_bytes()))
+}
+
+// Albums by name, songs by disc and track
+fn group_albums<'a>(library: &[&'a File]) -> Vec<Album<'a>> {
+    let mut albums: HashMap<String, Album<'a>> = HashMap::new();
+
+    for file in library {
+        let artist = file.album_artist_name();
+        albums
+            .entry(file.album_id())
+            .or_insert_with(|| Album {
+                id: file.album_id(),
+                name: file.album_name(),
+                artist_id: artist_id(&artist),
+                artist,
+                songs: Vec::new(),
+            })
+            .songs
+            .push(file);
+    }
+
+    let mut albums: Vec<Album<'a>> = albums.into_values().collect();
+
+    for album in &mut albums {
+        album
+            .songs
+            .sort_by_key(|file| (file.disc_number, file.track_number, file.file_name.clone()));
+    }
+
+    albums.sort_by_key(|album| (album.name.to_lowercase(), album.artist.to_lowercase()));
+
+    albums
+}
+
+fn group_artists<'a>(albums: &'a [Album<'a>]) -> Vec<Artist<'a>> {
+    let mut artists: BTreeMap<String, Artist<'a>> = BTreeMap::new();
+
+    for album in albums {
+        artists
+            .entry(sort_name(&album.artist))
+            .or_insert_with(|| Artist {
+                id: album.artist_id.clone(),
+                name: album.artist.clone(),
+                albums: Vec::new(),
+            })
+            .albums
+            .push(album);
+    }
+
+    artists.into_values().collect()
+}
+
+// "The Beatles" sorts as "beatles"
+fn sort_name(name: &str) -> String {
+    let lower = name.to_lowercase();
+
+    IGNORED_ARTICLES
+        .iter()
+        .find_map(|article| lower.strip_prefix(&format!("{} ", article.to_lowercase())))
+        .unwrap_or(&lower)
+        .to_string()
+}
+
+// Artists grouped by the first letter of their sort name, `#` for the rest
+fn index(artists: &[Artist], value: impl Fn(&Artist) -> Value) -> Vec<Value> {
+    let mut index: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+
+    for artist in artists {
+        let letter = match sort_name(&artist.name).chars().next() {
+            Some(letter) if letter.is_alphabetic() => letter.to_uppercase().to_string(),
+            _ => "#".to_string(),
+        };
+
+        index.entry(letter).or_default().push(value(artist));
+    }
+
+    index
+        .into_iter()
+        .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+        .collect()
+}
+
+fn cover_art(album: &Album) -> String {
+    album
+        .songs
+        .iter()
+        .find(|file| !file.art.is_empty())
+        .map(|file| file.art.clone())
+        .unwrap_or_default()
+}
+
+fn album_year(album: &Album) -> u32 {
+    album.songs.iter().map(|file| file.year).max().unwrap_or(0)
+}
+
+fn artist_value(artist: &Artist) -> Value {
+    prune(json!({
+        "id": artist.id,
+        "name": artist.name,
+        "albumCount": artist.albums.len(),
+        "coverArt": artist
+            .albums
+            .iter()
+            .map(|album| cover_art(album))
+            .find(|art| !art.is_empty())
+            .unwrap_or_default(),
+    }))
+}
+
+fn album_value(album: &Album) -> Value {
+    let genre = album
+        .songs
+        .iter()
+        .map(|file| file.genre.as_str())
+        .find(|genre| !genre.is_empty())
+        .unwrap_or_default();
+
+    prune(json!({
+        "id": album.id,
+        "name": album.name,
+        "artist": album.artist,
+        "artistId": album.artist_id,
+        "coverArt": cover_art(album),
+        "songCount": album.songs.len(),
+        "duration": album.songs.iter().map(|file| file.duration).sum::<u64>(),
+        "created": iso8601(album.songs.iter().map(|file| file.indexed_at).min().unwrap_or(0)),
+        "year": album_year(album),
+        "genre": genre,
+    }))
+}
+
+// Seconds since the epoch as e.g 2024-03-01T12:00:00Z
+fn iso8601(seconds: u64) -> String {
+    let civil = date::from_unix(seconds);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        civil.year, civil.month, civil.day, civil.hour, civil.minute, civil.second
+    )
+}
+
+// The full response body, `f=json` for JSON, XML otherwise
+pub fn render(json: bool, result: Result<Map<String, Value>, (u32, String)>) -> String {
+    let mut response = Map::new();
+
+    if !json {
+        response.insert("xmlns".to_string(), json!("http://subsonic.org/restapi"));
+    }
+
+    let status = if result.is_ok() { "ok" } else { "failed" };
+
+    response.insert("status".to_string(), json!(status));
+    response.insert("version".to_string(), json!(API_VERSION));
+    response.insert("type".to_string(), json!("auralist"));
+    response.insert(
+        "serverVersion".to_string(),
+        json!(env!("CARGO_PKG_VERSION")),
+    );
+    response.insert("openSubsonic".to_string(), json!(true));
+
+    match result {
+        Ok(payload) => response.extend(payload),
+        Err((code, message)) => {
+            response.insert(
+                "error".to_string(),
+                json!({ "code": code, "message": message }),
+            );
+        }
+    }
+
+    if json {
+        return json!({ "subsonic-response": response }).to_string();
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    write_element(&mut xml, "subsonic-response", &Value::Object(response));
+    xml
+}
+
+// The XML is the JSON turned inside out: values become attributes, objects
+// become child elements and arrays become repeated child elements. A `value`
+// is the element's text, e.g <genre songCount="1">Techno</genre>.
+fn write_element(xml: &mut String, name: &str, value: &Value) {
+    xml.push('<');
+    xml.push_str(name);
+
+    let mut children: Vec<(&str, &Value)> = Vec::new();
+    let mut text: Option<&Value> = None;
+
+    if let Value::Object(map) = value {
+        for (key, value) in map {
+            match value {
+                _ if key == "value" => text = Some(value),
+                Value::Object(_) => children.push((key, value)),
+                // arrays of values (e.g folder ids) are elements too
+                Value::Array(items) => {
+                    children.extend(items.iter().map(|item| (key.as_str(), item)))
+                }
+                Value::Null => {}
+                _ => {
+                    xml.push_str(&format!(" {}=\"{}\"", key, escape_attribute(value)));
+                }
+            }
+        }
+    }
+
+    if let Some(text) = text {
+        return xml.push_str(&format!(">{}</{}>", escape_attribute(text), name));
+    }
+
+    match value {
+        Value::Object(_) if children.is_empty() => xml.push_str("/>"),
+        Value::Object(_) => {
+            xml.push('>');
+            for (name, child) in children {
+                write_element(xml, name, child);
+            }
+            xml.push_str(&format!("</{}>", name));
+        }
+        scalar => xml.push_str(&format!(">{}</{}>", escape_attribute(scalar), name)),
+    }
+}
+
+fn escape_attribute(value: &Value) -> String {
+    let text = match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    };
+
+    // control characters other than tab and line breaks aren't allowed in
+    // XML at all, not even escaped, and tags do turn up with them
+    text.chars()
+        .filter(|c| *c >= ' ' || matches!(c, '\t' | '\n' | '\r'))
+        .collect::<String>()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subsonic() -> Subsonic {
+        let config = Config {
+            subsonic_username: "listener".to_string(),
+            subsonic_password: "sesame".to_string(),
+            ..Config::default()
+        };
+
+        Subsonic::new(
+            config,
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(Vec::new())),
+        )
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> Params {
+        Params(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn failure(result: Result<(), Outcome>) -> Option<u32> {
+        match result {
+            Ok(()) => None,
+            Err(Outcome::Failed(code, _)) => Some(code),
+            Err(_) => panic!("not a failure"),
+        }
+    }
+
+    fn file(artist: &str, album: &str) -> File {
+        let mut file = File::new_empty_file_from_path(Path::new("/music/Album/track.mp3")).unwrap();
+        file.artist = artist.to_string();
+        file.album = album.to_string();
+        file
+    }
+
+    #[test]
+    fn authenticate_checks_the_token_and_salt() {
+        let subsonic = subsonic();
+        let token = token::to_hex(&Md5::digest("sesamec19b2d"));
+
+        let good = params(&[("u", "listener"), ("t", &token), ("s", "c19b2d")]);
+        assert_eq!(failure(subsonic.authenticate(&good)), None);
+
+        let other_salt = params(&[("u", "listener"), ("t", &token), ("s", "c19b2e")]);
+        assert_eq!(
+            failure(subsonic.authenticate(&other_salt)),
+            Some(ERROR_WRONG_CREDENTIALS)
+        );
+
+        let not_hex = params(&[("u", "listener"), ("t", "zz"), ("s", "c19b2d")]);
+        assert_eq!(
+            failure(subsonic.authenticate(&not_hex)),
+            Some(ERROR_WRONG_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn authenticate_checks_plain_and_hex_passwords() {
+        let subsonic = subsonic();
+
+        let plain = params(&[("u", "listener"), ("p", "sesame")]);
+        assert_eq!(failure(subsonic.authenticate(&plain)), None);
+
+        let encoded = params(&[("u", "listener"), ("p", "enc:736573616d65")]);
+        assert_eq!(failure(subsonic.authenticate(&encoded)), None);
+
+        let wrong = params(&[("u", "listener"), ("p", "enc:736573616d66")]);
+        assert_eq!(
+            failure(subsonic.authenticate(&wrong)),
+            Some(ERROR_WRONG_CREDENTIALS)
+        );
+
+        let not_hex = params(&[("u", "listener"), ("p", "enc:sesame")]);
+        assert_eq!(
+            failure(subsonic.authenticate(&not_hex)),
+            Some(ERROR_WRONG_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn authenticate_refuses_other_users() {
+        let subsonic = subsonic();
+
+        let other = params(&[("u", "admin"), ("p", "sesame")]);
+        assert_eq!(
+            failure(subsonic.authenticate(&other)),
+            Some(ERROR_WRONG_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn authenticate_needs_a_user_and_a_password() {
+        let subsonic = subsonic();
+
+        let cases: &[&[(&str, &str)]] = &[
+            &[],
+            &[("p", "sesame")],
+            &[("u", "listener")],
+            &[("u", "listener"), ("t", "00")],
+            &[("u", "listener"), ("s", "c19b2d")],
+        ];
+
+        for case in cases {
+            assert_eq!(
+                failure(subsonic.authenticate(&params(case))),
+                Some(ERROR_MISSING_PARAMETER),
+                "{:?}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn library_is_grouped_with_the_files_unlocked() {
+        let subsonic = subsonic();
+        subsonic
+            .files_mutex
+            .lock()
+            .unwrap()
+            .insert(1, file("A", "B"));
+        subsonic.all_mutex.lock().unwrap().push(1);
+
+        let count = subsonic.with_library(|library| {
+            assert!(subsonic.files_mutex.try_lock().is_ok());
+            assert!(subsonic.all_mutex.try_lock().is_ok());
+            library.len()
+        });
+
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn render_json() {
+        let body = render(
+            true,
+            Ok(payload("genre", json!({ "value": "R&B", "songCount": 1 }))),
+        );
+        let response: Value = serde_json::from_str(&body).unwrap();
+        let response = &response["subsonic-response"];
+
+        assert_eq!(response["status"], "ok");
+        assert_eq!(response["version"], API_VERSION);
+        assert_eq!(response["openSubsonic"], true);
+        assert_eq!(response["genre"]["value"], "R&B");
+        assert!(response.get("xmlns").is_none());
+
+        let body = render(true, Err((ERROR_NOT_FOUND, "Song not found".to_string())));
+        let response: Value = serde_json::from_str(&body).unwrap();
+        let response = &response["subsonic-response"];
+
+        assert_eq!(response["status"], "failed");
+        assert_eq!(response["error"]["code"], ERROR_NOT_FOUND);
+        assert_eq!(response["error"]["message"], "Song not found");
+    }
+
+    #[test]
+    fn render_xml() {
+        let body = render(
+            false,
+            Ok(payload(
+                "genres",
+                json!({ "genre": [
+                    { "value": "R&B <live>", "songCount": 2 },
+                    { "value": "Pop", "songCount": 1 },
+                ] }),
+            )),
+        );
+
+        assert_eq!(
+            body,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <subsonic-response openSubsonic=\"true\" serverVersion=\"{}\" status=\"ok\" \
+                 type=\"auralist\" version=\"{}\" xmlns=\"http://subsonic.org/restapi\">\
+                 <genres><genre songCount=\"2\">R&amp;B &lt;live&gt;</genre>\
+                 <genre songCount=\"1\">Pop</genre></genres></subsonic-response>",
+                env!("CARGO_PKG_VERSION"),
+                API_VERSION
+            )
+        );
+
+        let body = render(false, Err((ERROR_NOT_FOUND, "\"x\" not found".to_string())));
+        assert!(body.contains("status=\"failed\""));
+        assert!(body.contains("<error code=\"70\" message=\"&quot;x&quot; not found\"/>"));
+    }
+
+    #[test]
+    fn attributes_drop_control_characters() {
+        assert_eq!(
+            escape_attribute(&json!("a\u{0}b\u{1b}c\td\ne\rf\u{7f}")),
+            "abc\td\ne\rf\u{7f}"
+        );
+        assert_eq!(escape_attribute(&json!(128)), "128");
+    }
+
+    #[test]
+    fn sort_names_skip_articles() {
+        assert_eq!(sort_name("The Beatles"), "beatles");
+        assert_eq!(sort_name("Los Lobos"), "lobos");
+        assert_eq!(sort_name("Les Negresses Vertes"), "negresses vertes");
+        // only whole words at the start
+        assert_eq!(sort_name("Theatre of Tragedy"), "theatre of tragedy");
+        assert_eq!(sort_name("Them"), "them");
+        assert_eq!(sort_name("Elastica"), "elastica");
+        assert_eq!(sort_name("Talking Heads The"), "talking heads the");
+    }
+
+    #[test]
+    fn index_groups_by_the_sort_name() {
+        let files = [
+            file("The Beatles", "Abbey Road"),
+            file("Björk", "Debut"),
+            file("La Roux", "La Roux"),
+            file("2Pac", "Me Against the World"),
+            file("The The", "Soul Mining"),
+        ];
+        let library: Vec<&File> = files.iter().collect();
+        let albums = group_albums(&library);
+        let artists = group_artists(&albums);
+
+        let index = index(&artists, |artist| json!(artist.name));
+
+        assert_eq!(
+            Value::Array(index),
+            json!([
+                { "name": "#", "artist": ["2Pac"] },
+                { "name": "B", "artist": ["The Beatles", "Björk"] },
+                { "name": "R", "artist": ["La Roux"] },
+                { "name": "T", "artist": ["The The"] },
+            ])
+        );
+    }
+}
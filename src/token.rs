@@ -0,0 +1,281 @@
+use crate::database::SQLite;
+use crate::music::File;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rusqlite::{params, OptionalExtension};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Only the first half of the HMAC is kept, 128 bits is plenty for a link
+// that expires
+const SIGNATURE_BYTES: usize = 16;
+
+// Play tokens carry the file and when they stop working, signed so nobody
+// can make their own. Nothing is stored, so any instance sharing the secret
+// can check any token, before or after a restart.
+//
+// e.g 0fdd6cca-671f4c2e-9f86d081884c7d659a2feaa0c55ad015
+//     file id  expires  signature
+#[derive(Clone)]
+pub struct Signer {
+    secret: Vec<u8>,
+    lifetime: u64,
+}
+
+impl Signer {
+    // An empty secret means one generated once and kept in the database
+    pub fn new(secret: &str, lifetime: u64) -> Signer {
+        let secret = if secret.is_empty() {
+            stored_secret()
+        } else {
+            secret.to_string()
+        };
+
+        Signer {
+            secret: secret.into_bytes(),
+            lifetime,
+        }
+    }
+
+    // Long enough to play the file twice over, and never shorter than the
+    // configured lifetime so a playlist can be worked through
+    pub fn issue(&self, file: &File) -> String {
+        self.sign(
+            &format!("{:08x}", file.id),
+            (file.duration * 2).max(self.lifetime),
+        )
+    }
+
+    // The file id, when the token was signed here and hasn't expired
+    pub fn verify(&self, token: &str) -> Option<u32> {
+        let id = self.subject(token)?;
+        if id.len() != 8 {
+            return None;
+        }
+
+        u32::from_str_radix(id, 16).ok()
+    }
+
+    // Album ids are a hash of names anyone can guess, so /download/album takes
+    // them signed as well, e.g al-e1518cf9-671f4c2e-9f86d081884c7d659a2feaa0c55ad015
+    pub fn issue_album(&self, album_id: &str) -> String {
+        self.sign(album_id, self.lifetime)
+    }
+
+    // The album id, when the token was signed here and hasn't expired
+    pub fn verify_album(&self, token: &str) -> Option<String> {
+        let album_id = self.subject(token)?;
+        if !album_id.starts_with("al-") {
+            return None;
+        }
+
+        Some(album_id.to_string())
+    }
+
+    fn sign(&self, subject: &str, lifetime: u64) -> String {
+        // tokens stop at 2106, when the expiry no longer fits in 8 hex digits
+        let expires = (now() + lifetime).min(u64::from(u32::MAX));
+
+        let payload = format!("{}-{:08x}", subject, expires);
+        let signature = to_hex(&self.mac(&payload).finalize().into_bytes()[..SIGNATURE_BYTES]);
+
+        format!("{}-{}", payload, signature)
+    }
+
+    // Whatever was signed, a file or album id, from the front of the token
+    fn subject<'a>(&self, token: &'a str) -> Option<&'a str> {
+        let mut parts = token.rsplitn(3, '-');
+        let signature = from_hex(parts.next()?)?;
+        let expires = parts.next()?;
+        let subject = parts.next()?;
+
+        if expires.len() != 8 || signature.len() != SIGNATURE_BYTES {
+            return None;
+        }
+
+        // compared in constant time
+        self.mac(&format!("{}-{}", subject, expires))
+            .verify_truncated_left(&signature)
+            .ok()?;
+
+        if u64::from_str_radix(expires, 16).ok()? < now() {
+            return None;
+        }
+
+        Some(subject)
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        // any key length works for HMAC
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+fn stored_secret() -> String {
+    let conn = SQLite::connect();
+
+    let stored: Option<String> = conn
+        .query_row(
+            "SELECT value FROM settings WHERE name = 'token_secret'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()
+        .unwrap_or_else(|err| {
+            println!("Could not read the token secret: {}", err);
+            None
+        });
+
+    if let Some(secret) = stored {
+        return secret;
+    }
+
+    println!("Generating a token secret...");
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = to_hex(&bytes);
+
+    // another instance sharing the database may have got there first
+    if let Err(err) = conn.execute(
+        "INSERT OR IGNORE INTO settings (name, value) VALUES ('token_secret', ?1)",
+        params![secret],
+    ) {
+        println!("Could not store the token secret: {}", err);
+        return secret;
+    }
+
+    conn.query_row(
+        "SELECT value FROM settings WHERE name = 'token_secret'",
+        [],
+        |row| row.get(0),
+    )
+    .unwrap_or(secret)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn file() -> File {
+        File::new_empty_file_from_path(Path::new("/music/a.flac")).unwrap()
+    }
+
+    fn signer(secret: &str) -> Signer {
+        Signer::new(secret, 3600)
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        let file = file();
+        let token = signer("secret").issue(&file);
+
+        assert_eq!(signer("secret").verify(&token), Some(file.id));
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let signer = signer("secret");
+        let payload = format!("{:08x}-{:08x}", file().id, now() - 1);
+        let signature = to_hex(&signer.mac(&payload).finalize().into_bytes()[..SIGNATURE_BYTES]);
+
+        assert_eq!(signer.verify(&format!("{}-{}", payload, signature)), None);
+    }
+
+    #[test]
+    fn tampered_tokens_are_refused() {
+        let signer = signer("secret");
+        let token = signer.issue(&file());
+
+        // a different file, a later expiry or a flipped signature bit
+        let (id, rest) = token.split_at(8);
+        let other_id = format!("{:08x}", u32::from_str_radix(id, 16).unwrap() ^ 1);
+        assert_eq!(signer.verify(&format!("{}{}", other_id, rest)), None);
+
+        let expires = format!("{:08x}", u32::MAX);
+        let forged = format!("{}-{}{}", id, expires, &token[17..]);
+        assert_eq!(signer.verify(&forged), None);
+
+        let last = token.chars().last().unwrap();
+        let flipped = if last == '0' { '1' } else { '0' };
+        let forged = format!("{}{}", &token[..token.len() - 1], flipped);
+        assert_eq!(signer.verify(&forged), None);
+    }
+
+    #[test]
+    fn truncated_tokens_are_refused() {
+        let signer = signer("secret");
+        let token = signer.issue(&file());
+
+        for length in [0, 8, 9, 17, 18, token.len() - 2, token.len() - 1] {
+            assert_eq!(signer.verify(&token[..length]), None, "{}", length);
+        }
+        assert_eq!(signer.verify(&format!("{}00", token)), None);
+    }
+
+    #[test]
+    fn tokens_from_another_secret_are_refused() {
+        let token = signer("secret").issue(&file());
+
+        assert_eq!(signer("another secret").verify(&token), None);
+    }
+
+    #[test]
+    fn album_tokens_verify() {
+        let signer = signer("secret");
+        let album_id = file().album_id();
+        let token = signer.issue_album(&album_id);
+
+        assert_eq!(signer.verify_album(&token), Some(album_id));
+        assert_eq!(signer.verify(&token), None);
+        assert_eq!(
+            Signer::new("another secret", 3600).verify_album(&token),
+            None
+        );
+        assert_eq!(signer.verify_album(&token.replacen("al-", "al-0", 1)), None);
+    }
+
+    #[test]
+    fn file_tokens_are_not_album_tokens() {
+        let signer = signer("secret");
+        let token = signer.issue(&file());
+
+        assert_eq!(signer.verify_album(&token), None);
+        assert_eq!(signer.verify_album(&format!("al-{}", token)), None);
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = [0x00, 0x0f, 0xa0, 0xff];
+
+        assert_eq!(to_hex(&bytes), "000fa0ff");
+        assert_eq!(from_hex("000fa0ff"), Some(bytes.to_vec()));
+        assert_eq!(from_hex("000FA0FF"), Some(bytes.to_vec()));
+        assert_eq!(from_hex("000"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,682 @@
+use crate::config::Config;
+use crate::decode::PcmReader;
+use crate::error::IndexError;
+use crate::music::File;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::io::AsyncReadExt;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+pub const DEFAULT_BITRATE: u32 = 96;
+const MIN_BITRATE: u32 = 8;
+const MAX_BITRATE: u32 = 320;
+
+// How much encoded output is read before it's passed on to the listener
+const CHUNK_SIZE: usize = 16384;
+
+// What a file can be transcoded to. WAV is written here, anything else is
+// piped through the encoder command in the [transcode] config section.
+// Decoding is done in-process, but there is no maintained pure Rust Opus or
+// MP3 encoder and binding libopus or LAME would make everyone build them,
+// transcoding or not. So those formats are opt in and need an encoder
+// (e.g ffmpeg) installed, `check_encoders` turns off any that can't be found.
+pub struct Target {
+    pub name: &'static str,
+    pub extension: &'static str,
+    pub mime: &'static str,
+    pub command: Option<String>,
+}
+
+// A sample rate and channel count the encoder is asked to convert to, rather
+// than keeping the file's own
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Resample {
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+impl Target {
+    // None when the format is unknown or its encoder isn't configured
+    pub fn find(config: &Config, format: &str) -> Option<Target> {
+        let (name, extension, mime, command) = match format {
+            "wav" => ("wav", "wav", "audio/wav", None),
+            "opus" => ("opus", "opus", "audio/ogg", Some(&config.transcode_opus)),
+            "mp3" => ("mp3", "mp3", "audio/mpeg", Some(&config.transcode_mp3)),
+            _ => return None,
+        };
+
+        let command = match command {
+            Some(command) if command.trim().is_empty() => return None,
+            Some(command) => Some(command.clone()),
+            None => None,
+        };
+
+        Some(Target {
+            name,
+            extension,
+            mime,
+            command,
+        })
+    }
+
+    // The bitrate is meaningless for WAV so it isn't part of its cache key
+    pub fn bitrate(&self, requested: Option<u32>) -> u32 {
+        match self.command {
+            Some(_) => requested
+                .unwrap_or(DEFAULT_BITRATE)
+                .clamp(MIN_BITRATE, MAX_BITRATE),
+            None => 0,
+        }
+    }
+
+    // Whether the command has `{out_sample_rate}` and `{out_channels}` for
+    // the encoder to convert to
+    pub fn resamples(&self) -> bool {
+        self.command.as_ref().is_some_and(|command| {
+            command.contains("{out_sample_rate}") && command.contains("{out_channels}")
+        })
+    }
+
+    // e.g ./transcodes/3a0c91f2-9d1e0b77-96.opus, the content hash means a
+    // changed file never gets an old transcode. Resampled ones end in the
+    // format, e.g 3a0c91f2-9d1e0b77-128-44100x2.mp3
+    pub fn cache_path(
+        &self,
+        cache: impl AsRef<Path>,
+        id: u32,
+        content_hash: u32,
+        bitrate: u32,
+        resample: Option<Resample>,
+    ) -> PathBuf {
+        let format = match resample {
+            Some(resample) => format!("-{}x{}", resample.sample_rate, resample.channels),
+            None => String::new(),
+        };
+
+        cache.as_ref().join(format!(
+            "{:08x}-{:08x}-{}{}.{}",
+            id, content_hash, bitrate, format, self.extension
+        ))
+    }
+
+    // Where a transcode is written and whether it's kept there once it's
+    // done. Without a cache the output is only kept while it's being
+    // listened to.
+    pub fn output_path(
+        &self,
+        config: &Config,
+        file: &File,
+        bitrate: u32,
+        resample: Option<Resample>,
+    ) -> (PathBuf, bool) {
+        let keep = !config.transcode_cache.is_empty();
+        let directory = if keep {
+            PathBuf::from(&config.transcode_cache)
+        } else {
+            env::temp_dir().join("auralist-transcodes")
+        };
+
+        (
+            self.cache_path(directory, file.id, file.content_hash, bitrate, resample),
+            keep,
+        )
+    }
+}
+
+// A transcode that has decoded its first packet and started its encoder, so
+// anything that would stop it from working has already gone wrong
+pub struct Transcode {
+    reader: PcmReader,
+    first: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+    encoder: Option<Child>,
+}
+
+impl Transcode {
+    // `resample` is only honoured by targets that `resamples`
+    pub fn start(
+        path: &str,
+        extension: &str,
+        target: &Target,
+        bitrate: u32,
+        resample: Option<Resample>,
+    ) -> Result<Transcode, IndexError> {
+        let mut reader = PcmReader::open(path, extension)?;
+
+        let (first, channels, sample_rate) = match reader.next_samples()? {
+            Some(pcm) => (pcm.samples.to_vec(), pcm.channels, pcm.sample_rate),
+            None => return Err(IndexError::InvalidPath(PathBuf::from(path))),
+        };
+
+        let encoder = match &target.command {
+            Some(command) => Some(spawn_encoder(
+                command,
+                bitrate,
+                channels,
+                sample_rate,
+                resample,
+            )?),
+            None => None,
+        };
+
+        Ok(Transcode {
+            reader,
+            first,
+            channels,
+            sample_rate,
+            encoder,
+        })
+    }
+
+    // Passes the output to `write` until the file ends or `write` returns
+    // false, returns whether everything was encoded
+    fn encode(self, write: impl FnMut(Vec<u8>) -> bool) -> bool {
+        if self.encoder.is_none() {
+            self.run_wav(write)
+        } else {
+            self.run_encoder(write)
+        }
+    }
+
+    fn run_wav(mut self, mut write: impl FnMut(Vec<u8>) -> bool) -> bool {
+        let mut chunk = wav_header(self.channels, self.sample_rate);
+        push_samples(&mut chunk, &self.first);
+
+        loop {
+            match self.reader.next_samples() {
+                Ok(Some(pcm)) => {
+                    if pcm.channels != self.channels || pcm.sample_rate != self.sample_rate {
+                        continue;
+                    }
+
+                    push_samples(&mut chunk, pcm.samples);
+
+                    if chunk.len() >= CHUNK_SIZE && !write(std::mem::take(&mut chunk)) {
+                        return false;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    println!("Transcode stopped early: {}", err);
+                    // whatever was decoded is still worth hearing
+                    write(chunk);
+                    return false;
+                }
+            }
+        }
+
+        chunk.is_empty() || write(chunk)
+    }
+
+    fn run_encoder(self, mut write: impl FnMut(Vec<u8>) -> bool) -> bool {
+        let Transcode {
+            reader,
+            first,
+            channels,
+            sample_rate,
+            encoder,
+        } = self;
+
+        // only called with an encoder, which was spawned with both piped
+        let mut encoder = encoder.unwrap();
+        let stdin = encoder.stdin.take().unwrap();
+        let mut stdout = encoder.stdout.take().unwrap();
+
+        // The encoder is fed on its own thread so a full stdout pipe can't
+        // stop it from being read
+        let feeder =
+            thread::spawn(move || feed_encoder(reader, first, channels, sample_rate, stdin));
+
+        let mut listening = true;
+        let mut buffer = vec![0; CHUNK_SIZE];
+
+        loop {
+            match stdout.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => {
+                    if !write(buffer[..read].to_vec()) {
+                        listening = false;
+                        break;
+                    }
+                }
+                Err(err) => {
+                    println!("Could not read from the encoder: {}", err);
+                    listening = false;
+                    break;
+                }
+            }
+        }
+
+        if !listening {
+            let _ = encoder.kill();
+        }
+
+        let fed = matches!(feeder.join(), Ok(true));
+        let encoded = matches!(encoder.wait(), Ok(status) if status.success());
+
+        listening && fed && encoded
+    }
+}
+
+// How much of a job's output is on disk, and whether there'll be any more
+#[derive(Clone, Copy, Default)]
+struct Progress {
+    written: u64,
+    done: bool,
+}
+
+// Transcodes for listeners, at most `max_jobs` at once (0 for no limit).
+// Each output is only encoded once, anyone asking for one that's running
+// follows along as it's written.
+pub struct Jobs {
+    permits: Option<Arc<Semaphore>>,
+    running: Mutex<HashMap<PathBuf, Running>>,
+}
+
+struct Running {
+    temporary: PathBuf,
+    progress: Arc<watch::Sender<Progress>>,
+}
+
+pub enum Joined {
+    // nobody is encoding it yet, the `Lead` has to be run
+    Lead(Lead, Follower),
+    Follow(Follower),
+    // `max_jobs` are running
+    Busy,
+}
+
+impl Jobs {
+    pub fn new(max_jobs: usize) -> Jobs {
+        Jobs {
+            permits: (max_jobs > 0).then(|| Arc::new(Semaphore::new(max_jobs))),
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Follows the job writing `path`, or reserves a new one. `keep` leaves
+    // the finished output at `path`, otherwise it's thrown away.
+    pub fn join(self: &Arc<Self>, path: PathBuf, keep: bool) -> std::io::Result<Joined> {
+        println!("Locking running (join)...");
+        let mut running = self.running.lock().unwrap();
+
+        if let Some(job) = running.get(&path) {
+            println!("Following the transcode to `{}`...", path.display());
+            let follower = Follower::open(&job.temporary, job.progress.subscribe())?;
+            return Ok(Joined::Follow(follower));
+        }
+
+        let permit = match &self.permits {
+            Some(permits) => match Arc::clone(permits).try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => return Ok(Joined::Busy),
+            },
+            None => None,
+        };
+
+        let writer = CacheWriter::create(path.clone())?;
+        let progress = Arc::new(watch::Sender::new(Progress::default()));
+        let follower = match Follower::open(&writer.temporary, progress.subscribe()) {
+            Ok(follower) => follower,
+            Err(err) => {
+                writer.abandon();
+                return Err(err);
+            }
+        };
+
+        running.insert(
+            path.clone(),
+            Running {
+                temporary: writer.temporary.clone(),
+                progress: Arc::clone(&progress),
+            },
+        );
+        println!("Unlocking running (join)...");
+        drop(running);
+
+        let lead = Lead {
+            jobs: Arc::clone(self),
+            path,
+            keep,
+            writer: Some(writer),
+            progress,
+            _permit: permit,
+        };
+
+        Ok(Joined::Lead(lead, follower))
+    }
+}
+
+// The one encoding a job. Dropping it without running it, e.g when the file
+// can't be decoded, ends the job for its followers.
+pub struct Lead {
+    jobs: Arc<Jobs>,
+    path: PathBuf,
+    keep: bool,
+    writer: Option<CacheWriter>,
+    progress: Arc<watch::Sender<Progress>>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Lead {
+    // Encodes until the file ends or nobody is following any more
+    pub fn run(mut self, transcode: Transcode) {
+        let wav = transcode.encoder.is_none();
+        let complete = transcode.encode(|chunk| self.write(&chunk));
+
+        // nobody new can follow a file that's about to move or go
+        self.leave();
+
+        if let Some(writer) = self.writer.take() {
+            if complete && self.keep {
+                writer.finish(wav);
+            } else {
+                writer.abandon();
+            }
+        }
+    }
+
+    // Returns false once there's no point carrying on
+    fn write(&mut self, chunk: &[u8]) -> bool {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return false,
+        };
+
+        if let Err(err) = writer.file.write_all(chunk) {
+            println!("Could not write transcode: {}", err);
+            return false;
+        }
+
+        self.progress
+            .send_modify(|progress| progress.written += chunk.len() as u64);
+
+        self.followed()
+    }
+
+    // Checked again with the jobs locked, so nobody can start following a
+    // job that's being given up on
+    fn followed(&self) -> bool {
+        if self.progress.receiver_count() > 0 {
+            return true;
+        }
+
+        println!("Locking running (followed)...");
+        let mut running = self.jobs.running.lock().unwrap();
+        if self.progress.receiver_count() > 0 {
+            return true;
+        }
+        running.remove(&self.path);
+        println!("Nobody is listening, stopping the transcode...");
+
+        false
+    }
+
+    fn leave(&self) {
+        println!("Locking running (leave)...");
+        self.jobs.running.lock().unwrap().remove(&self.path);
+    }
+}
+
+impl Drop for Lead {
+    fn drop(&mut self) {
+        self.leave();
+
+        if let Some(writer) = self.writer.take() {
+            writer.abandon();
+        }
+
+        self.progress.send_modify(|progress| progress.done = true);
+    }
+}
+
+// Reads a job's output as it's written. The file stays readable after it has
+// been moved into the cache or removed.
+pub struct Follower {
+    file: tokio::fs::File,
+    progress: watch::Receiver<Progress>,
+    read: u64,
+}
+
+impl Follower {
+    fn open(path: &Path, progress: watch::Receiver<Progress>) -> std::io::Result<Follower> {
+        Ok(Follower {
+            file: tokio::fs::File::from_std(fs::File::open(path)?),
+            progress,
+            read: 0,
+        })
+    }
+
+    // The next piece of output, None once the job is over and all of it
+    // has been read
+    pub async fn next(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        loop {
+            let progress = *self.progress.borrow_and_update();
+
+            if self.read < progress.written {
+                let length = min(progress.written - self.read, CHUNK_SIZE as u64);
+                let mut buffer = vec![0; length as usize];
+                if let Err(err) = self.file.read_exact(&mut buffer).await {
+                    return Some(Err(err));
+                }
+                self.read += length;
+                return Some(Ok(buffer));
+            }
+
+            if progress.done || self.progress.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+// Turns off any configured encoder that can't be found, so a missing ffmpeg
+// is reported at startup and the format is refused rather than failing for
+// every listener. Everything else carries on working.
+pub fn check_encoders(config: &mut Config) {
+    let encoders = [
+        ("opus", &mut config.transcode_opus),
+        ("mp3", &mut config.transcode_mp3),
+    ];
+
+    for (name, command) in encoders {
+        let program = match command.split_whitespace().next() {
+            Some(program) => program.to_string(),
+            None => continue,
+        };
+
+        if !on_path(&program) {
+            eprintln!(
+                "The {} encoder `{}` can't be found, {} transcoding is off. Install it or set `[transcode] {}` to an empty value.",
+                name, program, name, name
+            );
+            command.clear();
+        }
+    }
+}
+
+fn on_path(program: &str) -> bool {
+    if program.contains(std::path::MAIN_SEPARATOR) {
+        return Path::new(program).is_file();
+    }
+
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|path| path.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+// Returns whether every sample made it to the encoder
+fn feed_encoder(
+    mut reader: PcmReader,
+    first: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+    mut stdin: ChildStdin,
+) -> bool {
+    let mut bytes = Vec::new();
+    push_samples(&mut bytes, &first);
+
+    if stdin.write_all(&bytes).is_err() {
+        return false;
+    }
+
+    loop {
+        match reader.next_samples() {
+            Ok(Some(pcm)) => {
+                if pcm.channels != channels || pcm.sample_rate != sample_rate {
+                    continue;
+                }
+
+                bytes.clear();
+                push_samples(&mut bytes, pcm.samples);
+
+                // the encoder was killed or gave up
+                if stdin.write_all(&bytes).is_err() {
+                    return false;
+                }
+            }
+            // dropping stdin tells the encoder the input is over
+            Ok(None) => return true,
+            Err(err) => {
+                println!("Transcode stopped early: {}", err);
+                return false;
+            }
+        }
+    }
+}
+
+// Placeholders: {bitrate} in kbps, {sample_rate} in Hz, {channels}, and
+// {out_sample_rate} and {out_channels} which are the same unless `resample`
+// says otherwise. The encoder is given signed 16 bit little endian PCM on
+// stdin and has to write the encoded file to stdout.
+fn spawn_encoder(
+    command: &str,
+    bitrate: u32,
+    channels: usize,
+    sample_rate: u32,
+    resample: Option<Resample>,
+) -> Result<Child, IndexError> {
+    let out = resample.unwrap_or(Resample {
+        sample_rate,
+        channels,
+    });
+
+    let mut parts = command.split_whitespace().map(|part| {
+        part.replace("{bitrate}", &bitrate.to_string())
+            .replace("{out_sample_rate}", &out.sample_rate.to_string())
+            .replace("{out_channels}", &out.channels.to_string())
+            .replace("{sample_rate}", &sample_rate.to_string())
+            .replace("{channels}", &channels.to_string())
+    });
+
+    // an empty command never makes it this far
+    let program = parts.next().unwrap_or_default();
+
+    println!("Starting encoder `{}`...", program);
+
+    Ok(Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?)
+}
+
+fn push_samples(bytes: &mut Vec<u8>, samples: &[f32]) {
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+}
+
+// The sizes aren't known while streaming so they're left at their maximum,
+// which players treat as "until the end". Cached copies get the real sizes.
+fn wav_header(channels: usize, sample_rate: u32) -> Vec<u8> {
+    let channels = channels as u16;
+    let block_align = channels * 2;
+    let byte_rate = sample_rate * u32::from(block_align);
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+// Passes a chunk to the listener and the cache, returns false once the
+// listener has gone away
+// Output is written next to where it'll end up and only renamed into place
+// once it's complete, so a half finished transcode is never served
+struct CacheWriter {
+    file: fs::File,
+    temporary: PathBuf,
+    path: PathBuf,
+}
+
+impl CacheWriter {
+    fn create(path: PathBuf) -> std::io::Result<CacheWriter> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let temporary = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        let file = fs::File::create(&temporary)?;
+
+        Ok(CacheWriter {
+            file,
+            temporary,
+            path,
+        })
+    }
+
+    fn finish(mut self, wav: bool) {
+        if wav {
+            if let Err(err) = fix_wav_sizes(&mut self.file) {
+                println!("Could not finish cached WAV: {}", err);
+                return self.abandon();
+            }
+        }
+
+        match fs::rename(&self.temporary, &self.path) {
+            Ok(_) => println!("Cached transcode `{}`", self.path.display()),
+            Err(err) => {
+                println!("Could not cache transcode: {}", err);
+                self.abandon();
+            }
+        }
+    }
+
+    fn abandon(self) {
+        let _ = fs::remove_file(&self.temporary);
+    }
+}
+
+fn fix_wav_sizes(file: &mut fs::File) -> std::io::Result<()> {
+    let length = file.seek(SeekFrom::End(0))?;
+    let riff_size = u32::try_from(length - 8).unwrap_or(u32::MAX);
+    let data_size = u32::try_from(length - 44).unwrap_or(u32::MAX);
+
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&riff_size.to_le_bytes())?;
+    file.seek(SeekFrom::Start(40))?;
+    file.write_all(&data_size.to_le_bytes())?;
+    file.flush()
+}
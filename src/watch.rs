@@ -0,0 +1,207 @@
+use crate::config::Config;
+use crate::error::{FileError, Stage};
+use crate::music::File;
+use crate::{index_path, is_excluded, reconcile, walk_and_sweep, IndexedPath};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+// How long a path has to stop changing before it's indexed, so a file that is
+// still being copied isn't warmed half written
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+// Feeds filesystem events into the warm queue, forever. Network mounts (CIFS,
+// NFS) don't report changes made on other machines, so a full walk also runs
+// every `rescan_interval` seconds to catch anything the events missed.
+pub fn watch(
+    config: &Config,
+    directory_exclusions: &[String],
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) {
+    let (tx, rx) = channel();
+
+    // Keep the watcher alive for as long as this function runs
+    let _watcher = match start_watcher(&config.directory, tx) {
+        Ok(watcher) => {
+            println!("Watching `{}` for changes...", config.directory);
+            Some(watcher)
+        }
+        Err(err) => {
+            println!(
+                "Cannot watch `{}`, relying on rescans: {}",
+                config.directory, err
+            );
+            None
+        }
+    };
+
+    let rescan_interval = Duration::from_secs(config.rescan_interval);
+    let mut next_rescan = Instant::now() + rescan_interval;
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+
+    loop {
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(Ok(event)) => {
+                for path in event.paths {
+                    pending.insert(path, Instant::now());
+                }
+            }
+            Ok(Err(err)) => println!("Watch error: {}", err),
+            Err(RecvTimeoutError::Timeout) => (),
+            // No watcher, only rescans are left
+            Err(RecvTimeoutError::Disconnected) => {
+                if config.rescan_interval == 0 {
+                    return;
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, changed_at)| changed_at.elapsed() >= SETTLE_TIME)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        if !settled.is_empty() {
+            for path in &settled {
+                pending.remove(path);
+            }
+
+            apply_changes(
+                config,
+                directory_exclusions,
+                settled,
+                files_mutex.clone(),
+                to_be_warmed_mutex.clone(),
+                have_been_warmed_mutex.clone(),
+                mixes_mutex.clone(),
+                tunes_mutex.clone(),
+            );
+        }
+
+        if config.rescan_interval > 0 && Instant::now() >= next_rescan {
+            println!("Rescanning `{}`...", config.directory);
+            walk_and_sweep(
+                config,
+                directory_exclusions,
+                files_mutex.clone(),
+                to_be_warmed_mutex.clone(),
+                have_been_warmed_mutex.clone(),
+                mixes_mutex.clone(),
+                tunes_mutex.clone(),
+            );
+            next_rescan = Instant::now() + rescan_interval;
+        }
+    }
+}
+
+fn start_watcher(
+    directory: &str,
+    tx: std::sync::mpsc::Sender<notify::Result<notify::Event>>,
+) -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(Path::new(directory), RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+// Indexes paths that exist and forgets ones that don't
+#[allow(clippy::too_many_arguments)]
+fn apply_changes(
+    config: &Config,
+    directory_exclusions: &[String],
+    paths: Vec<PathBuf>,
+    files_mutex: Arc<Mutex<HashMap<u32, File>>>,
+    to_be_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    have_been_warmed_mutex: Arc<Mutex<Vec<u32>>>,
+    mixes_mutex: Arc<Mutex<Vec<u32>>>,
+    tunes_mutex: Arc<Mutex<Vec<u32>>>,
+) {
+    let mut new: Vec<u32> = Vec::new();
+    let mut gone: Vec<PathBuf> = Vec::new();
+
+    for path in paths {
+        let path = relative_to_directory(&path, &config.directory);
+        println!("Changed: `{:?}`", path);
+
+        if is_excluded(&path, directory_exclusions) {
+            continue;
+        }
+
+        if !path.exists() {
+            gone.push(path);
+            continue;
+        }
+
+        // A directory that was moved or copied in only reports itself
+        for entry in WalkDir::new(&path)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+        {
+            if is_excluded(entry.path(), directory_exclusions) {
+                continue;
+            }
+
+            match index_path(
+                entry.path(),
+                &config.extensions,
+                files_mutex.clone(),
+                to_be_warmed_mutex.clone(),
+            ) {
+                Ok(IndexedPath::New(file_hash)) => new.push(file_hash),
+                Ok(_) => (),
+                Err(error) => println!(
+                    "Watch error: {}",
+                    FileError::new(entry.path().to_string_lossy(), Stage::Index, error)
+                ),
+            }
+        }
+    }
+
+    // A path that's gone may have been a single file or a whole directory
+    println!("Locking files (apply_changes)...");
+    let files = files_mutex.lock().unwrap();
+    let stale: Vec<File> = files
+        .values()
+        .filter(|file| {
+            gone.iter()
+                .any(|path| Path::new(&file.path).starts_with(path))
+        })
+        .cloned()
+        .collect();
+    println!("Unlocking files (apply_changes)...");
+    drop(files);
+
+    reconcile(
+        config,
+        stale,
+        new,
+        files_mutex,
+        to_be_warmed_mutex,
+        have_been_warmed_mutex,
+        mixes_mutex,
+        tunes_mutex,
+    );
+}
+
+// Events can carry absolute paths, files are keyed on the configured
+// directory (e.g ./files/a.mp3) so rewrite them to match
+fn relative_to_directory(path: &Path, directory: &str) -> PathBuf {
+    let canonical_directory = match Path::new(directory).canonicalize() {
+        Ok(canonical_directory) => canonical_directory,
+        Err(_) => return path.to_path_buf(),
+    };
+
+    match path.strip_prefix(&canonical_directory) {
+        Ok(relative) => Path::new(directory).join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
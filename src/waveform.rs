@@ -0,0 +1,147 @@
+use crate::database::SQLite;
+use rusqlite::params;
+
+// Samples are first boiled down to one min/max pair per block of this many
+// frames, every resolution is then built from the blocks. A three hour mix at
+// 44.1kHz comes to about 1.9 million blocks.
+const BLOCK_FRAMES: usize = 256;
+
+// Collects min/max peaks while a file is being decoded
+pub struct PeakBuilder {
+    blocks: Vec<(f32, f32)>,
+    current: (f32, f32),
+    frames_in_block: usize,
+}
+
+impl PeakBuilder {
+    pub fn new() -> PeakBuilder {
+        PeakBuilder {
+            blocks: Vec::new(),
+            current: (0.0, 0.0),
+            frames_in_block: 0,
+        }
+    }
+
+    // Interleaved samples, every channel counts towards the same peak
+    pub fn push(&mut self, samples: &[f32], channels: usize) {
+        for frame in samples.chunks(channels.max(1)) {
+            for &sample in frame {
+                self.current.0 = self.current.0.min(sample);
+                self.current.1 = self.current.1.max(sample);
+            }
+
+            self.frames_in_block += 1;
+
+            if self.frames_in_block == BLOCK_FRAMES {
+                self.blocks.push(self.current);
+                self.current = (0.0, 0.0);
+                self.frames_in_block = 0;
+            }
+        }
+    }
+
+    // One set of peaks per resolution, as (buckets, peaks). A file too short to
+    // fill a resolution gets one bucket per block instead.
+    pub fn finish(mut self, resolutions: &[u32]) -> Vec<(u32, Vec<u8>)> {
+        if self.frames_in_block > 0 {
+            self.blocks.push(self.current);
+        }
+
+        let mut waveforms: Vec<(u32, Vec<u8>)> = Vec::new();
+
+        if self.blocks.is_empty() {
+            return waveforms;
+        }
+
+        for &resolution in resolutions {
+            let buckets = (resolution as usize).min(self.blocks.len());
+
+            if buckets == 0 || waveforms.iter().any(|(b, _)| *b as usize == buckets) {
+                continue;
+            }
+
+            waveforms.push((buckets as u32, self.downsample(buckets)));
+        }
+
+        waveforms
+    }
+
+    // Interleaved min/max pairs, each scaled to a signed byte
+    fn downsample(&self, buckets: usize) -> Vec<u8> {
+        let mut peaks: Vec<u8> = Vec::with_capacity(buckets * 2);
+        let total = self.blocks.len();
+
+        for bucket in 0..buckets {
+            let start = bucket * total / buckets;
+            let end = ((bucket + 1) * total / buckets).max(start + 1);
+
+            let (min, max) = self.blocks[start..end]
+                .iter()
+                .fold((0.0_f32, 0.0_f32), |(min, max), (block_min, block_max)| {
+                    (min.min(*block_min), max.max(*block_max))
+                });
+
+            peaks.push(to_byte(min));
+            peaks.push(to_byte(max));
+        }
+
+        peaks
+    }
+}
+
+fn to_byte(sample: f32) -> u8 {
+    ((sample.clamp(-1.0, 1.0) * 127.0).round() as i8) as u8
+}
+
+// Replaces every waveform stored for a file
+pub fn save(file_id: u32, waveforms: &[(u32, Vec<u8>)]) -> rusqlite::Result<()> {
+    let mut conn = SQLite::connect();
+    let transaction = conn.transaction()?;
+
+    transaction.execute("DELETE FROM waveforms WHERE file_id = ?1", params![file_id])?;
+
+    for (buckets, peaks) in waveforms {
+        transaction.execute(
+            "INSERT INTO waveforms (file_id, buckets, peaks) VALUES (?1, ?2, ?3)",
+            params![file_id, buckets, peaks],
+        )?;
+    }
+
+    transaction.commit()
+}
+
+// The stored resolution closest to the one asked for, rounding up, or the
+// most detailed one when nothing is asked for. Returns (buckets, peaks).
+pub fn load(file_id: u32, buckets: Option<u32>) -> rusqlite::Result<Option<(u32, Vec<u8>)>> {
+    let conn = SQLite::connect();
+
+    let mut stmt = conn
+        .prepare("SELECT buckets, peaks FROM waveforms WHERE file_id = ?1 ORDER BY buckets ASC")?;
+
+    let waveforms = stmt
+        .query_map(params![file_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(u32, Vec<u8>)>>>()?;
+
+    let wanted = match buckets {
+        Some(buckets) => waveforms
+            .iter()
+            .position(|(stored, _)| *stored >= buckets)
+            .or_else(|| waveforms.len().checked_sub(1)),
+        None => waveforms.len().checked_sub(1),
+    };
+
+    Ok(wanted.map(|position| waveforms[position].clone()))
+}
+
+// A moved file keeps its waveforms
+pub fn move_to(old_id: u32, new_id: u32) {
+    let conn = SQLite::connect();
+
+    match conn.execute(
+        "UPDATE waveforms SET file_id = ?2 WHERE file_id = ?1",
+        params![old_id, new_id],
+    ) {
+        Ok(_) => println!("Moving waveforms..."),
+        Err(err) => println!("Update failed (waveforms): {}", err),
+    }
+}
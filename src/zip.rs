@@ -0,0 +1,428 @@
+// Zips written while they're sent. Audio doesn't compress so entries are
+// stored as they are, which also means the whole length is known before the
+// first byte is read. ZIP64 is only used where a size or offset needs it.
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+use crate::date;
+use async_stream::stream;
+use std::cmp::min;
+use tokio::io::AsyncReadExt;
+use warp::hyper::Body;
+
+const LIMIT_32: u64 = 0xFFFF_FFFF;
+const LIMIT_16: usize = 0xFFFF;
+
+// bit 3: sizes and CRC follow the data, bit 11: names are UTF-8
+const FLAGS: u16 = 0x0808;
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+// made on unix, so the permissions below are used
+const MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+// -rw-r--r--
+const PERMISSIONS: u32 = 0o100644 << 16;
+
+const BUFFER_SIZE: u64 = 65536;
+
+pub struct Entry {
+    // on disk
+    pub path: String,
+    // in the zip, folders separated by `/`
+    pub name: String,
+    pub size: u64,
+    // unix seconds
+    pub modified: u64,
+}
+
+impl Entry {
+    fn zip64(&self) -> bool {
+        self.size >= LIMIT_32
+    }
+
+    fn local_header_length(&self) -> u64 {
+        30 + self.name.len() as u64 + if self.zip64() { 20 } else { 0 }
+    }
+
+    fn descriptor_length(&self) -> u64 {
+        if self.zip64() {
+            24
+        } else {
+            16
+        }
+    }
+}
+
+// Where every entry starts and where the central directory starts
+fn offsets(entries: &[Entry]) -> (Vec<u64>, u64) {
+    let mut offsets = Vec::with_capacity(entries.len());
+    let mut offset = 0;
+
+    for entry in entries {
+        offsets.push(offset);
+        offset += entry.local_header_length() + entry.size + entry.descriptor_length();
+    }
+
+    (offsets, offset)
+}
+
+// The extra field a central directory record needs, if any
+fn central_zip64_fields(entry: &Entry, offset: u64) -> Vec<u64> {
+    let mut fields = Vec::new();
+    if entry.zip64() {
+        // uncompressed then compressed, which are the same when stored
+        fields.push(entry.size);
+        fields.push(entry.size);
+    }
+    if offset >= LIMIT_32 {
+        fields.push(offset);
+    }
+    fields
+}
+
+fn central_directory_length(entries: &[Entry], offsets: &[u64]) -> u64 {
+    entries
+        .iter()
+        .zip(offsets)
+        .map(|(entry, offset)| {
+            let fields = central_zip64_fields(entry, *offset);
+            let extra = if fields.is_empty() {
+                0
+            } else {
+                4 + 8 * fields.len() as u64
+            };
+            46 + entry.name.len() as u64 + extra
+        })
+        .sum()
+}
+
+fn needs_zip64_end(entries: &[Entry], directory_offset: u64, directory_length: u64) -> bool {
+    entries.len() >= LIMIT_16 || directory_offset >= LIMIT_32 || directory_length >= LIMIT_32
+}
+
+// Exactly how many bytes `body` will send
+pub fn content_length(entries: &[Entry]) -> u64 {
+    let (offsets, directory_offset) = offsets(entries);
+    let directory_length = central_directory_length(entries, &offsets);
+    let end = if needs_zip64_end(entries, directory_offset, directory_length) {
+        56 + 20 + 22
+    } else {
+        22
+    };
+
+    directory_offset + directory_length + end
+}
+
+// Reads each file as it's reached, a file that has shrunk since `size` was
+// taken ends the stream with an error
+pub fn body(entries: Vec<Entry>) -> Body {
+    Body::wrap_stream(stream! {
+        let (offsets, directory_offset) = offsets(&entries);
+        let mut crcs = Vec::with_capacity(entries.len());
+
+        for entry in &entries {
+            yield Ok(local_header(entry));
+
+            let mut file = match tokio::fs::File::open(&entry.path).await {
+                Ok(file) => file,
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            };
+
+            let mut hasher = crc32fast::Hasher::new();
+            let mut sent: u64 = 0;
+            while sent < entry.size {
+                let mut buffer = vec![0; min(entry.size - sent, BUFFER_SIZE) as usize];
+                if let Err(err) = file.read_exact(&mut buffer).await {
+                    yield Err(err);
+                    return;
+                }
+                hasher.update(&buffer);
+                sent += buffer.len() as u64;
+                yield Ok(buffer);
+            }
+
+            let crc = hasher.finalize();
+            crcs.push(crc);
+            yield Ok(descriptor(entry, crc));
+        }
+
+        let mut directory = Vec::new();
+        for ((entry, offset), crc) in entries.iter().zip(&offsets).zip(&crcs) {
+            central_header(&mut directory, entry, *offset, *crc);
+        }
+        let directory_length = directory.len() as u64;
+
+        if needs_zip64_end(&entries, directory_offset, directory_length) {
+            zip64_end(&mut directory, entries.len() as u64, directory_offset, directory_length);
+        }
+        end(&mut directory, entries.len(), directory_offset, directory_length);
+
+        yield Ok(directory) as Result<Vec<u8>, std::io::Error>;
+    })
+}
+
+fn local_header(entry: &Entry) -> Vec<u8> {
+    let (time, date) = dos_date_time(entry.modified);
+    let mut header = Vec::with_capacity(entry.local_header_length() as usize);
+
+    header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+    header.extend_from_slice(&version(entry.zip64()).to_le_bytes());
+    header.extend_from_slice(&FLAGS.to_le_bytes());
+    // stored
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&time.to_le_bytes());
+    header.extend_from_slice(&date.to_le_bytes());
+    // the CRC and sizes are in the descriptor after the data
+    header.extend_from_slice(&0u32.to_le_bytes());
+    let size = if entry.zip64() { u32::MAX } else { 0 };
+    header.extend_from_slice(&size.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes());
+    header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+    let extra: u16 = if entry.zip64() { 20 } else { 0 };
+    header.extend_from_slice(&extra.to_le_bytes());
+    header.extend_from_slice(entry.name.as_bytes());
+
+    if entry.zip64() {
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+    }
+
+    header
+}
+
+fn descriptor(entry: &Entry, crc: u32) -> Vec<u8> {
+    let mut descriptor = Vec::with_capacity(entry.descriptor_length() as usize);
+
+    descriptor.extend_from_slice(&0x0807_4b50u32.to_le_bytes());
+    descriptor.extend_from_slice(&crc.to_le_bytes());
+    if entry.zip64() {
+        descriptor.extend_from_slice(&entry.size.to_le_bytes());
+        descriptor.extend_from_slice(&entry.size.to_le_bytes());
+    } else {
+        descriptor.extend_from_slice(&(entry.size as u32).to_le_bytes());
+        descriptor.extend_from_slice(&(entry.size as u32).to_le_bytes());
+    }
+
+    descriptor
+}
+
+fn central_header(directory: &mut Vec<u8>, entry: &Entry, offset: u64, crc: u32) {
+    let (time, date) = dos_date_time(entry.modified);
+    let fields = central_zip64_fields(entry, offset);
+    let zip64 = !fields.is_empty();
+    let size = if entry.zip64() {
+        u32::MAX
+    } else {
+        entry.size as u32
+    };
+    let extra: u16 = if zip64 {
+        4 + 8 * fields.len() as u16
+    } else {
+        0
+    };
+
+    directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+    directory.extend_from_slice(&MADE_BY.to_le_bytes());
+    directory.extend_from_slice(&version(zip64).to_le_bytes());
+    directory.extend_from_slice(&FLAGS.to_le_bytes());
+    directory.extend_from_slice(&0u16.to_le_bytes());
+    directory.extend_from_slice(&time.to_le_bytes());
+    directory.extend_from_slice(&date.to_le_bytes());
+    directory.extend_from_slice(&crc.to_le_bytes());
+    directory.extend_from_slice(&size.to_le_bytes());
+    directory.extend_from_slice(&size.to_le_bytes());
+    directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+    directory.extend_from_slice(&extra.to_le_bytes());
+    // comment length, disk number and internal attributes
+    directory.extend_from_slice(&[0; 6]);
+    directory.extend_from_slice(&PERMISSIONS.to_le_bytes());
+    directory.extend_from_slice(&(offset.min(LIMIT_32) as u32).to_le_bytes());
+    directory.extend_from_slice(entry.name.as_bytes());
+
+    if zip64 {
+        directory.extend_from_slice(&1u16.to_le_bytes());
+        directory.extend_from_slice(&(8 * fields.len() as u16).to_le_bytes());
+        for field in fields {
+            directory.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+}
+
+fn zip64_end(directory: &mut Vec<u8>, count: u64, directory_offset: u64, directory_length: u64) {
+    let record_offset = directory_offset + directory_length;
+
+    // the ZIP64 end of central directory record
+    directory.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+    // the size of the rest of the record
+    directory.extend_from_slice(&44u64.to_le_bytes());
+    directory.extend_from_slice(&MADE_BY.to_le_bytes());
+    directory.extend_from_slice(&VERSION_ZIP64.to_le_bytes());
+    // this disk and the disk the directory starts on
+    directory.extend_from_slice(&[0; 8]);
+    directory.extend_from_slice(&count.to_le_bytes());
+    directory.extend_from_slice(&count.to_le_bytes());
+    directory.extend_from_slice(&directory_length.to_le_bytes());
+    directory.extend_from_slice(&directory_offset.to_le_bytes());
+
+    // and where to find it
+    directory.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+    directory.extend_from_slice(&0u32.to_le_bytes());
+    directory.extend_from_slice(&record_offset.to_le_bytes());
+    directory.extend_from_slice(&1u32.to_le_bytes());
+}
+
+fn end(directory: &mut Vec<u8>, count: usize, directory_offset: u64, directory_length: u64) {
+    let count = count.min(LIMIT_16) as u16;
+
+    directory.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    // this disk and the disk the directory starts on
+    directory.extend_from_slice(&[0; 4]);
+    directory.extend_from_slice(&count.to_le_bytes());
+    directory.extend_from_slice(&count.to_le_bytes());
+    directory.extend_from_slice(&(directory_length.min(LIMIT_32) as u32).to_le_bytes());
+    directory.extend_from_slice(&(directory_offset.min(LIMIT_32) as u32).to_le_bytes());
+    // comment length
+    directory.extend_from_slice(&0u16.to_le_bytes());
+}
+
+fn version(zip64: bool) -> u16 {
+    if zip64 {
+        VERSION_ZIP64
+    } else {
+        VERSION
+    }
+}
+
+// MS-DOS time and date, which start in 1980 and have two second precision
+fn dos_date_time(seconds: u64) -> (u16, u16) {
+    // 1980-01-01
+    let civil = date::from_unix(seconds.max(315_532_800));
+
+    // the last date DOS can hold is in 2107
+    let year = civil.year.min(2107);
+
+    let time = (civil.hour << 11) | (civil.minute << 5) | (civil.second / 2);
+    let date = ((year - 1980) << 9) | (civil.month << 5) | civil.day;
+
+    (time as u16, date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::path::Path;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    // Name and contents of each entry, found through the central directory
+    // the way unzip would
+    fn read_back(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(zip, end), 0x0605_4b50);
+        let count = u16_at(zip, end + 10) as usize;
+        let directory_length = u32_at(zip, end + 12) as usize;
+        let mut at = u32_at(zip, end + 16) as usize;
+        assert_eq!(at + directory_length, end);
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(u32_at(zip, at), 0x0201_4b50);
+            let crc = u32_at(zip, at + 16);
+            let size = u32_at(zip, at + 20) as usize;
+            let name_length = u16_at(zip, at + 28) as usize;
+            let extra_length = u16_at(zip, at + 30) as usize;
+            let offset = u32_at(zip, at + 42) as usize;
+            let name = String::from_utf8(zip[at + 46..at + 46 + name_length].to_vec()).unwrap();
+
+            assert_eq!(u32_at(zip, offset), 0x0403_4b50);
+            assert_eq!(
+                &zip[offset + 30..offset + 30 + name_length],
+                name.as_bytes()
+            );
+            let data_at = offset + 30 + name_length + u16_at(zip, offset + 28) as usize;
+            let data = zip[data_at..data_at + size].to_vec();
+            assert_eq!(crc32fast::hash(&data), crc);
+
+            // the descriptor after the data agrees
+            let descriptor = data_at + size;
+            assert_eq!(u32_at(zip, descriptor), 0x0807_4b50);
+            assert_eq!(u32_at(zip, descriptor + 4), crc);
+            assert_eq!(u32_at(zip, descriptor + 8) as usize, size);
+
+            entries.push((name, data));
+            at += 46 + name_length + extra_length;
+        }
+
+        entries
+    }
+
+    fn write_file(directory: &Path, name: &str, contents: &[u8]) -> Entry {
+        let path = directory.join(name);
+        std::fs::write(&path, contents).unwrap();
+
+        Entry {
+            path: path.to_string_lossy().to_string(),
+            name: format!("Artist - Album/{}", name),
+            size: contents.len() as u64,
+            modified: 1_709_294_400,
+        }
+    }
+
+    #[tokio::test]
+    async fn zips_read_back() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let big: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let entries = vec![
+            write_file(&directory, "01 Song.mp3", b"not really an mp3"),
+            write_file(&directory, "02 Empty.flac", b""),
+            write_file(&directory, "03 Ünïcödé.ogg", &big),
+        ];
+        let expected: Vec<(String, Vec<u8>)> = entries
+            .iter()
+            .map(|entry| (entry.name.clone(), std::fs::read(&entry.path).unwrap()))
+            .collect();
+
+        let length = content_length(&entries);
+        let zip = warp::hyper::body::to_bytes(body(entries)).await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(zip.len() as u64, length);
+        assert_eq!(read_back(&zip), expected);
+    }
+
+    #[tokio::test]
+    async fn a_missing_file_ends_the_zip_with_an_error() {
+        let entries = vec![Entry {
+            path: "/nonexistent/auralist/song.mp3".to_string(),
+            name: "song.mp3".to_string(),
+            size: 10,
+            modified: 0,
+        }];
+
+        assert!(warp::hyper::body::to_bytes(body(entries)).await.is_err());
+    }
+
+    #[test]
+    fn dos_dates() {
+        // 2024-03-01 12:00:00
+        assert_eq!(
+            dos_date_time(1_709_294_400),
+            ((12 << 11) as u16, ((44 << 9) | (3 << 5) | 1) as u16)
+        );
+        // before 1980 is 1980-01-01
+        assert_eq!(dos_date_time(0), (0, (1 << 5) | 1));
+        // odd seconds round down
+        assert_eq!(dos_date_time(315_532_800 + 119).0, (1 << 5) | 29);
+    }
+}